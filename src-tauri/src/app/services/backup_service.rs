@@ -0,0 +1,30 @@
+use std::{path::PathBuf, sync::Arc};
+
+use tauri::State;
+
+use crate::server::{
+    backup::{self, BackupSummary},
+    db::db_instance::SharedDb,
+};
+
+#[tauri::command]
+pub async fn export_backup(
+    shared_db: State<'_, Arc<SharedDb>>,
+    destination: String,
+) -> Result<BackupSummary, String> {
+    let database = shared_db.get().await.ok_or("Server is not running")?;
+    backup::export_backup(database.as_ref(), &PathBuf::from(destination))
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn import_backup(
+    shared_db: State<'_, Arc<SharedDb>>,
+    source: String,
+) -> Result<BackupSummary, String> {
+    let database = shared_db.get().await.ok_or("Server is not running")?;
+    backup::import_backup(database.as_ref(), &PathBuf::from(source))
+        .await
+        .map_err(|e| e.to_string())
+}
@@ -0,0 +1,91 @@
+use std::sync::Arc;
+
+use tauri::State;
+
+use crate::server::{
+    db::{db_instance::SharedDb, device_table::Device, repository::Repository},
+    pin::reset_device_pin,
+    revocation::{self, Revocation, RevokedFiles},
+};
+
+#[derive(serde::Serialize)]
+pub struct DeviceInfo {
+    device_id: String,
+    device: Device,
+}
+
+// Saving would create a missing record, so check the device first
+async fn patch_device(
+    database: &dyn Repository,
+    device_id: &str,
+    patch: impl FnOnce(&mut Device),
+) -> Result<(), String> {
+    let mut device = match database.get_device(device_id).await {
+        Ok(Some(d)) => d,
+        Ok(None) => return Err("Device not found".to_string()),
+        Err(e) => return Err(e.to_string()),
+    };
+    patch(&mut device);
+    database
+        .save_device(device_id, &device)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn list_devices(shared_db: State<'_, Arc<SharedDb>>) -> Result<Vec<DeviceInfo>, String> {
+    let database = shared_db.get().await.ok_or("Server is not running")?;
+    let devices = match database.list_devices().await {
+        Ok(d) => d,
+        Err(e) => return Err(e.to_string()),
+    };
+    Ok(devices
+        .into_iter()
+        .map(|(device_id, device)| DeviceInfo { device_id, device })
+        .collect())
+}
+
+#[tauri::command]
+pub async fn revoke_device(
+    shared_db: State<'_, Arc<SharedDb>>,
+    device_id: String,
+    files: RevokedFiles,
+) -> Result<Revocation, String> {
+    let database = shared_db.get().await.ok_or("Server is not running")?;
+    revocation::revoke_device(database.as_ref(), &device_id, files)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn set_device_admin(
+    shared_db: State<'_, Arc<SharedDb>>,
+    device_id: String,
+    admin: bool,
+) -> Result<(), String> {
+    let database = shared_db.get().await.ok_or("Server is not running")?;
+    patch_device(database.as_ref(), &device_id, |d| d.admin = admin).await
+}
+
+// Read-only devices can still pull and sync but not push, modify or delete
+#[tauri::command]
+pub async fn set_device_read_only(
+    shared_db: State<'_, Arc<SharedDb>>,
+    device_id: String,
+    read_only: bool,
+) -> Result<(), String> {
+    let database = shared_db.get().await.ok_or("Server is not running")?;
+    patch_device(database.as_ref(), &device_id, |d| d.read_only = read_only).await
+}
+
+// Returns the temporary PIN to show on the desktop
+#[tauri::command]
+pub async fn reset_pin(
+    shared_db: State<'_, Arc<SharedDb>>,
+    device_id: String,
+) -> Result<String, String> {
+    let database = shared_db.get().await.ok_or("Server is not running")?;
+    reset_device_pin(database.as_ref(), &device_id)
+        .await
+        .map_err(|e| e.to_string())
+}
@@ -0,0 +1,19 @@
+use std::sync::Arc;
+
+use tauri::State;
+
+use crate::server::{
+    db::db_instance::SharedDb,
+    fsck::{self, FsckMode, FsckReport},
+};
+
+#[tauri::command]
+pub async fn run_fsck(
+    shared_db: State<'_, Arc<SharedDb>>,
+    mode: FsckMode,
+) -> Result<FsckReport, String> {
+    let database = shared_db.get().await.ok_or("Server is not running")?;
+    fsck::run_fsck(database.as_ref(), mode)
+        .await
+        .ok_or("A consistency check is already running".to_string())
+}
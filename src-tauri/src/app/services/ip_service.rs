@@ -3,7 +3,9 @@ use std::net::UdpSocket;
 
 use tracing::error;
 
-fn get_local_ip() -> Result<IpAddr, std::io::Error> {
+use crate::server::tls::get_fingerprint;
+
+pub fn get_local_ip() -> Result<IpAddr, std::io::Error> {
     // Create a UDP socket and bind it to a local address (typically 0.0.0.0:0).
     let socket = UdpSocket::bind("0.0.0.0:0")?;
 
@@ -29,4 +31,15 @@ pub fn get_ipv4() -> Result<String, String> {
             Err(e.to_string())
         }
     }
-}
\ No newline at end of file
+}
+
+#[tauri::command]
+pub async fn get_tls_fingerprint() -> Result<String, String> {
+    match get_fingerprint().await {
+        Some(fingerprint) => Ok(fingerprint),
+        None => {
+            error!("Error reading TLS certificate");
+            Err("No TLS certificate available".to_string())
+        }
+    }
+}
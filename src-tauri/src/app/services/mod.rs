@@ -1,2 +1,5 @@
+pub mod backup_service;
 pub mod device_service;
-pub mod ip_service;
\ No newline at end of file
+pub mod fsck_service;
+pub mod ip_service;
+pub mod pairing_service;
\ No newline at end of file
@@ -0,0 +1,34 @@
+use std::sync::Arc;
+
+use tauri::State;
+
+use crate::server::pairing::{PairingPayload, PairingState};
+
+use super::ip_service::get_local_ip;
+
+#[tauri::command]
+pub async fn start_pairing(
+    pairing: State<'_, Arc<PairingState>>,
+    require_approval: bool,
+) -> Result<PairingPayload, String> {
+    let host = match get_local_ip() {
+        Ok(addr) => addr.to_string(),
+        Err(e) => return Err(e.to_string()),
+    };
+    Ok(pairing.start(host, require_approval).await)
+}
+
+#[tauri::command]
+pub async fn stop_pairing(pairing: State<'_, Arc<PairingState>>) -> Result<(), String> {
+    pairing.stop().await;
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn respond_pairing(
+    pairing: State<'_, Arc<PairingState>>,
+    request_id: String,
+    approved: bool,
+) -> Result<bool, String> {
+    Ok(pairing.respond(&request_id, approved).await)
+}
@@ -10,8 +10,12 @@ extern crate rocket_include_static_resources;
 mod app;
 mod server;
 
-use app::services::ip_service;
+use std::sync::Arc;
+
+use app::services::{backup_service, device_service, fsck_service, ip_service, pairing_service};
+use server::db::db_instance::SharedDb;
 use server::db::device_table::Device;
+use server::pairing::PairingState;
 use tauri::Manager;
 use tokio::sync::Mutex;
 
@@ -39,14 +43,33 @@ fn main() {
     #[cfg(not(release))]
     tracing_subscriber::fmt().init();
 
+    let pairing = Arc::new(PairingState::default());
+    let shared_db = Arc::new(SharedDb::default());
+
     tauri::Builder::default()
-        .invoke_handler(tauri::generate_handler![ip_service::get_ipv4,])
-        .setup(|app| {
+        .manage(pairing.clone())
+        .manage(shared_db.clone())
+        .invoke_handler(tauri::generate_handler![
+            ip_service::get_ipv4,
+            ip_service::get_tls_fingerprint,
+            pairing_service::start_pairing,
+            pairing_service::stop_pairing,
+            pairing_service::respond_pairing,
+            device_service::list_devices,
+            device_service::revoke_device,
+            device_service::set_device_admin,
+            device_service::set_device_read_only,
+            device_service::reset_pin,
+            fsck_service::run_fsck,
+            backup_service::export_backup,
+            backup_service::import_backup,
+        ])
+        .setup(move |app| {
             let window = app.get_window("main").unwrap();
             let _main_window = app.get_window("main").unwrap();
             let app_handle = app.handle();
             // mount the rocket instance
-            server::run(window);
+            server::run(window, pairing.clone(), shared_db.clone());
             Ok(())
         })
         .run(tauri::generate_context!())
@@ -0,0 +1,196 @@
+use std::net::SocketAddr;
+
+use rocket::{
+    http::{ContentType, Status},
+    Data, State,
+};
+use rocket_multipart_form_data::{
+    MultipartFormData, MultipartFormDataField, MultipartFormDataOptions,
+};
+use serde_json::{json, Value};
+use tauri::Window;
+
+use crate::{
+    parse_multipart_form_texts,
+    server::{
+        auth::{create_session, refresh_session, AuthenticatedDevice, TokenKeys},
+        db::db_instance::DbInstance,
+        pin::{is_valid_pin, set_device_pin},
+        utility::TextFieldExt,
+    },
+};
+
+use super::utility::{verify_device_id, verify_pin};
+
+#[post("/login", data = "<data>")]
+pub async fn login(
+    content_type: &ContentType,
+    data: Data<'_>,
+    remote_address: SocketAddr,
+    db: &State<DbInstance>,
+    keys: &State<TokenKeys>,
+    window: &State<Window>,
+) -> Result<Value, Status> {
+    let options = MultipartFormDataOptions::with_multipart_form_data_fields(vec![
+        MultipartFormDataField::text("DeviceID"),
+        MultipartFormDataField::text("PIN"),
+    ]);
+
+    let form_result = MultipartFormData::parse(content_type, data, options).await;
+    parse_multipart_form_texts!(
+        multipart_form: form_result,
+        parse_error: Status::BadRequest;
+        device_id: "DeviceID";
+        pin: "PIN";
+    );
+
+    let database = &db.database;
+
+    // Verify Pin first, unknown devices answer with 401 like a wrong PIN and
+    // repeated failures answer with 429 until the lockout ends
+    let result = verify_pin(
+        database,
+        window,
+        &remote_address.ip(),
+        &device_id,
+        &pin,
+        Status::InternalServerError,
+        Status::Unauthorized,
+        Status::TooManyRequests,
+    )
+    .await;
+
+    let device_hash = match result {
+        Ok(h) => h,
+        Err(e) => return Err(e),
+    };
+
+    // A temporary PIN from a reset has to be changed through `/auth/pin` first
+    if device_hash.must_change {
+        return Err(Status::PreconditionRequired);
+    }
+
+    // Check Device Entry
+    let result = verify_device_id(
+        database,
+        &device_id,
+        Status::InternalServerError,
+        Status::Unauthorized,
+    )
+    .await;
+
+    if let Err(e) = result {
+        return Err(e);
+    }
+
+    match create_session(database, keys, &device_id).await {
+        Ok(tokens) => Ok(json!(tokens)),
+        Err(e) => {
+            error!("{e}");
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+/*
+   Changes the PIN of a device, requires the current (or temporary) PIN.
+   Every other session of the device ends, a new token pair is returned
+*/
+#[post("/pin", data = "<data>")]
+pub async fn change_pin(
+    content_type: &ContentType,
+    data: Data<'_>,
+    remote_address: SocketAddr,
+    db: &State<DbInstance>,
+    keys: &State<TokenKeys>,
+    window: &State<Window>,
+) -> Result<Value, Status> {
+    let options = MultipartFormDataOptions::with_multipart_form_data_fields(vec![
+        MultipartFormDataField::text("DeviceID"),
+        MultipartFormDataField::text("PIN"),
+        MultipartFormDataField::text("NewPIN"),
+    ]);
+
+    let form_result = MultipartFormData::parse(content_type, data, options).await;
+    parse_multipart_form_texts!(
+        multipart_form: form_result,
+        parse_error: Status::BadRequest;
+        device_id: "DeviceID";
+        pin: "PIN";
+        new_pin: "NewPIN";
+    );
+
+    if !is_valid_pin(&new_pin) {
+        return Err(Status::BadRequest);
+    }
+
+    let database = &db.database;
+
+    let result = verify_pin(
+        database,
+        window,
+        &remote_address.ip(),
+        &device_id,
+        &pin,
+        Status::InternalServerError,
+        Status::Unauthorized,
+        Status::TooManyRequests,
+    )
+    .await;
+
+    if let Err(e) = result {
+        return Err(e);
+    }
+
+    if let Err(e) = set_device_pin(database, &device_id, new_pin, false).await {
+        error!("{e}");
+        return Err(Status::InternalServerError);
+    }
+
+    match create_session(database, keys, &device_id).await {
+        Ok(tokens) => Ok(json!(tokens)),
+        Err(e) => {
+            error!("{e}");
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+#[post("/refresh", data = "<data>")]
+pub async fn refresh(
+    content_type: &ContentType,
+    data: Data<'_>,
+    db: &State<DbInstance>,
+    keys: &State<TokenKeys>,
+) -> Result<Value, Status> {
+    let options = MultipartFormDataOptions::with_multipart_form_data_fields(vec![
+        MultipartFormDataField::text("RefreshToken"),
+    ]);
+
+    let form_result = MultipartFormData::parse(content_type, data, options).await;
+    parse_multipart_form_texts!(
+        multipart_form: form_result,
+        parse_error: Status::BadRequest;
+        refresh_token: "RefreshToken";
+    );
+
+    match refresh_session(&db.database, keys, &refresh_token).await {
+        Ok(Some(tokens)) => Ok(json!(tokens)),
+        Ok(None) => Err(Status::Unauthorized),
+        Err(e) => {
+            error!("{e}");
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+#[post("/logout")]
+pub async fn logout(auth: AuthenticatedDevice, db: &State<DbInstance>) -> Status {
+    match db.database.delete_session(&auth.session_id).await {
+        Ok(_) => Status::Ok,
+        Err(e) => {
+            error!("{e}");
+            Status::InternalServerError
+        }
+    }
+}
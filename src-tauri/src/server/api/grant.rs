@@ -0,0 +1,144 @@
+use rocket::{
+    http::{ContentType, Status},
+    Data, State,
+};
+use rocket_multipart_form_data::{
+    MultipartFormData, MultipartFormDataField, MultipartFormDataOptions,
+};
+use serde_json::{json, Value};
+
+use crate::{
+    parse_multipart_form_texts,
+    server::{
+        auth::AuthenticatedDevice,
+        db::{
+            db_instance::DbInstance,
+            global_table::is_global_table,
+            grant_table::{Access, Grant},
+        },
+        utility::TextFieldExt,
+    },
+};
+
+use super::utility::verify_device_id;
+
+/*
+   Access grants, an owner lets another paired device read (or also write)
+   its library or the subtree below `RelativePath`. The other device then
+   passes `Owner` to the pull, push and sync handlers
+*/
+
+// Grant paths are compared component wise, trailing slashes don't matter
+fn normalize_path(path: Option<String>) -> String {
+    path.unwrap_or_default().trim_matches('/').to_string()
+}
+
+#[post("/", data = "<data>")]
+pub async fn create_grant(
+    auth: AuthenticatedDevice,
+    content_type: &ContentType,
+    data: Data<'_>,
+    db: &State<DbInstance>,
+) -> Result<Value, Status> {
+    let options = MultipartFormDataOptions::with_multipart_form_data_fields(vec![
+        MultipartFormDataField::text("Grantee"),
+        MultipartFormDataField::text("RelativePath"),
+        MultipartFormDataField::text("Access"),
+    ]);
+
+    let form_result = MultipartFormData::parse(content_type, data, options).await;
+    parse_multipart_form_texts!(
+        multipart_form: form_result,
+        parse_error: Status::BadRequest;
+        grantee: "Grantee";
+        access: "Access";
+    );
+
+    let access: Access = match access.parse() {
+        Ok(a) => a,
+        Err(_e) => return Err(Status::BadRequest),
+    };
+    let relative_path = normalize_path(multipart_form.texts.get("RelativePath").first_text());
+
+    let database = &db.database;
+    let device_id = auth.device_id;
+    if grantee == device_id || is_global_table(&grantee) {
+        return Err(Status::BadRequest);
+    }
+
+    let result = verify_device_id(
+        database,
+        &grantee,
+        Status::InternalServerError,
+        Status::NotFound,
+    )
+    .await;
+    if let Err(e) = result {
+        return Err(e);
+    }
+
+    // Granting the same path again replaces the access
+    let grant = Grant::new(device_id.clone(), grantee.clone(), relative_path, access);
+    match database.save_grant(&grant).await {
+        Ok(_) => Ok(json!(grant)),
+        Err(e) => {
+            error!("{e}");
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+#[delete("/", data = "<data>")]
+pub async fn remove_grant(
+    auth: AuthenticatedDevice,
+    content_type: &ContentType,
+    data: Data<'_>,
+    db: &State<DbInstance>,
+) -> Result<Status, Status> {
+    let options = MultipartFormDataOptions::with_multipart_form_data_fields(vec![
+        MultipartFormDataField::text("Grantee"),
+        MultipartFormDataField::text("RelativePath"),
+    ]);
+
+    let form_result = MultipartFormData::parse(content_type, data, options).await;
+    parse_multipart_form_texts!(
+        multipart_form: form_result,
+        parse_error: Status::BadRequest;
+        grantee: "Grantee";
+    );
+    let relative_path = normalize_path(multipart_form.texts.get("RelativePath").first_text());
+
+    let r = db
+        .database
+        .delete_grant(&auth.device_id, &grantee, &relative_path)
+        .await;
+    match r {
+        Ok(Some(_g)) => Ok(Status::Ok),
+        Ok(None) => Err(Status::NotFound),
+        Err(e) => {
+            error!("{e}");
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+// Grants the device gave to others and the ones it received
+#[get("/")]
+pub async fn list_grants(
+    auth: AuthenticatedDevice,
+    db: &State<DbInstance>,
+) -> Result<Value, Status> {
+    let database = &db.database;
+    let given = database.grants_given(&auth.device_id).await;
+    let received = database.grants_received(&auth.device_id).await;
+    match (given, received) {
+        (Ok(given), Ok(received)) => Ok(json!({
+            "Given": given,
+            "Received": received,
+        })),
+        (Err(e), _) | (_, Err(e)) => {
+            error!("{e}");
+            Err(Status::InternalServerError)
+        }
+    }
+}
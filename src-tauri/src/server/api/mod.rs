@@ -1,5 +1,9 @@
+pub mod auth;
+pub mod grant;
 pub mod modify;
+mod partial;
 pub mod pull;
 pub mod push;
 pub mod sync;
-mod utility;
+pub mod user;
+pub(crate) mod utility;
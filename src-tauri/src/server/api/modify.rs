@@ -8,19 +8,187 @@ use rocket_multipart_form_data::{
     MultipartFormData, MultipartFormDataField, MultipartFormDataOptions,
 };
 
-use surrealdb::opt::PatchOp;
+use serde_json::{json, Value};
 
-use crate::server::{
-    api::utility::{verify_device_id, verify_pin},
-    db::{db_instance::DbInstance, device_table::Device},
-    utility::{self, TextFieldExt},
+use crate::{
+    parse_multipart_form_texts,
+    server::{
+        api::utility::{
+            clear_tombstone, record_change, remove_local_entry, resolve_library, store_tombstone,
+            verify_device_id,
+        },
+        auth::{AuthenticatedDevice, WritableDevice},
+        blob_store::{acquire_blob, ingest_blob},
+        db::{
+            change_table::ChangeKind, db_instance::DbInstance, grant_table::Access,
+        },
+        fsck::hold_off_repairs,
+        revocation::{self, RevocationError, RevokedFiles},
+        utility::{gen_file_id, valid_file_name, TextFieldExt},
+    },
 };
 
-// #[patch("/file")]
-// fn modify_file() {}
+/*
+   Renames a file or moves it to another relative path. The record id is
+   `gen_file_id(relative_path, file_name)` so the entry is re-keyed, the old path
+   gets a tombstone for other syncing clients
+*/
+#[patch("/file", data = "<data>")]
+pub async fn modify_file(
+    auth: WritableDevice,
+    db: &State<DbInstance>,
+    data: Data<'_>,
+    content_type: &ContentType,
+) -> Result<Status, &'static str> {
+    let options = MultipartFormDataOptions::with_multipart_form_data_fields(vec![
+        MultipartFormDataField::text("DeviceName"),
+        MultipartFormDataField::text("FileName"),
+        MultipartFormDataField::text("RelativePath"),
+        MultipartFormDataField::text("NewFileName"),
+        MultipartFormDataField::text("NewRelativePath"),
+        MultipartFormDataField::text("NewDirPath"),
+        MultipartFormDataField::text("NewClientPath"),
+    ]);
+
+    let form_result = MultipartFormData::parse(content_type, data, options).await;
+    parse_multipart_form_texts!(
+        multipart_form: form_result,
+        parse_error: "Error: Could not parse the request";
+        file_name: "FileName";
+        relative_path: "RelativePath";
+    );
+
+    let optional_text = |key: &str| {
+        multipart_form
+            .texts
+            .get(key)
+            .and_then(|t| t.first())
+            .map(|t| t.text.clone())
+    };
+    let new_file_name = optional_text("NewFileName").unwrap_or_else(|| file_name.clone());
+    let new_relative_path =
+        optional_text("NewRelativePath").unwrap_or_else(|| relative_path.clone());
+    let new_dir_path = optional_text("NewDirPath");
+    let new_client_path = optional_text("NewClientPath");
+    if !valid_file_name(&file_name) || !valid_file_name(&new_file_name) {
+        return Err("Error: invalid file name");
+    }
+
+    let database = &db.database;
+    let device_id = auth.device_id;
+
+    let file_id = gen_file_id(&relative_path, &file_name);
+    let new_file_id = gen_file_id(&new_relative_path, &new_file_name);
+    let mut local = match database.get_entry(&device_id, &file_id).await {
+        Ok(Some(l)) => l,
+        Ok(None) => return Err("Could not find the file"),
+        Err(e) => {
+            error!("{e}");
+            return Err("Error: finding the file in database");
+        }
+    };
+
+    // Nothing to change, answer like a successful update
+    if file_id == new_file_id && new_dir_path.is_none() && new_client_path.is_none() {
+        return Ok(Status::Ok);
+    }
+
+    if file_id != new_file_id {
+        match database.get_entry(&device_id, &new_file_id).await {
+            Ok(Some(_e)) => return Err("A file already exists at the new path"),
+            Ok(None) => {}
+            Err(e) => {
+                error!("{e}");
+                return Err("Error: finding the file in database");
+            }
+        }
+    }
+
+    // Entries stored before the blob store still live at a path derived
+    // from their name, move them into the store so the path stops mattering
+    let _repairs = hold_off_repairs().await;
+    if local.content_hash.is_none() && !local.lost {
+        let blob = match ingest_blob(std::path::Path::new(&local.file_location), true).await {
+            Some(b) => b,
+            None => return Err("Unable to move file on server"),
+        };
+        if let Err(e) = acquire_blob(database, &blob).await {
+            error!("{e}");
+            return Err("Unable to move file on server");
+        }
+        local.file_location = String::from(blob.location.to_str().unwrap());
+        local.content_hash = Some(blob.hash);
+    }
+
+    // blurhash and metadata are carried over untouched
+    local.file_name = new_file_name.clone();
+    local.relative_path = new_relative_path.clone();
+    if let Some(dir_path) = new_dir_path {
+        local.dir_path = dir_path;
+    }
+    if let Some(client_path) = new_client_path {
+        local.client_path = client_path;
+    }
+
+    if file_id == new_file_id {
+        if let Err(e) = database.save_entry(&device_id, &file_id, &local).await {
+            error!("{e}");
+            return Err("Error: updating the file in database");
+        }
+        if let Err(e) = record_change(
+            database,
+            &device_id,
+            &file_id,
+            ChangeKind::Update,
+            &new_relative_path,
+            &new_file_name,
+        )
+        .await
+        {
+            error!("Failed to record change : {e}");
+            return Err("Error: recording the change");
+        }
+        return Ok(Status::Ok);
+    }
+
+    if let Err(e) = database.save_entry(&device_id, &new_file_id, &local).await {
+        error!("{e}");
+        return Err("Error: storing the moved file in database");
+    }
+    let old = match database.delete_entry(&device_id, &file_id).await {
+        Ok(Some(old)) => old,
+        Ok(None) => return Err("Could not find the file"),
+        Err(e) => {
+            error!("{e}");
+            return Err("Error: removing the old entry from database");
+        }
+    };
+
+    store_tombstone(database, &device_id, &file_id, &old).await;
+    clear_tombstone(database, &device_id, &new_file_id).await;
+
+    let changes = [
+        (&file_id, ChangeKind::Delete, &relative_path, &file_name),
+        (
+            &new_file_id,
+            ChangeKind::Create,
+            &new_relative_path,
+            &new_file_name,
+        ),
+    ];
+    for (id, kind, rel_path, name) in changes {
+        if let Err(e) = record_change(database, &device_id, id, kind, rel_path, name).await {
+            error!("Failed to record change : {e}");
+            return Err("Error: recording the change");
+        }
+    }
+
+    Ok(Status::Ok)
+}
 
 #[post("/device", data = "<data>")]
 pub async fn modfiy_device(
+    auth: AuthenticatedDevice,
     db: &State<DbInstance>,
     data: Data<'_>,
     remote_address: SocketAddr,
@@ -29,11 +197,11 @@ pub async fn modfiy_device(
     info!("Remote Address: {}", remote_address);
     // Process multipart form data
     let options = MultipartFormDataOptions::with_multipart_form_data_fields(vec![
-        MultipartFormDataField::text("DeviceID"),
         MultipartFormDataField::text("OS"),
         MultipartFormDataField::text("DeviceName"),
-        MultipartFormDataField::text("PIN"),
         MultipartFormDataField::text("RemoteAddr"),
+        MultipartFormDataField::text("ReadOnly"),
+        MultipartFormDataField::text("DeviceID"),
     ]);
 
     let form_result = MultipartFormData::parse(content_type, data, options).await;
@@ -45,51 +213,38 @@ pub async fn modfiy_device(
     };
 
     // Extract the data from the form
-    let device_id = multipart_form.texts.get("DeviceID");
     // let os = multipart_form.texts.get("OS");
     let device_name = multipart_form.texts.get("DeviceName");
-    let pin = multipart_form.texts.get("PIN");
-    let _read_only = match multipart_form.texts.get("ReadOnly") {
-        Some(_t) => true,
-        None => false,
+    let read_only = match multipart_form.texts.get("ReadOnly").first_text() {
+        Some(t) => match t.parse::<bool>() {
+            Ok(r) => Some(r),
+            Err(_e) => return Err(Status::BadRequest),
+        },
+        None => None,
     };
 
-    let required_available = utility::verify_required_data(&[device_id, pin]);
-    info!("All required Parameters available : {}", required_available);
-    if !required_available {
-        return Err(Status::BadRequest);
+    // Other devices and the read-only flag can only be changed by an admin
+    let device_id = match multipart_form.texts.get("DeviceID").first_text() {
+        Some(id) => id,
+        None => auth.device_id.clone(),
+    };
+    if (device_id != auth.device_id || read_only.is_some()) && !auth.device.admin {
+        return Err(Status::Forbidden);
     }
-
-    let device_id = device_id.first_text().unwrap();
-    let pin = pin.first_text().unwrap();
     let database = &db.database;
 
-    // Verify Device ID
-    let result = verify_device_id::<Status>(
-        database,
-        &device_id,
-        Status::InternalServerError,
-        Status::Conflict,
-    )
-    .await;
-    if let Err(e) = result {
-        return Err(e);
-    }
-
-    // Verify Pin
-    let result = verify_pin(
-        database,
-        &device_id,
-        &pin,
-        Status::InternalServerError,
-        Status::BadRequest,
-        Status::Unauthorized,
-    )
-    .await;
-
-    if let Err(e) = result {
-        return Err(e);
-    }
+    let mut device = match device_id == auth.device_id {
+        true => auth.device,
+        false => {
+            verify_device_id(
+                database,
+                &device_id,
+                Status::InternalServerError,
+                Status::NotFound,
+            )
+            .await?
+        }
+    };
 
     // After verfied
     // let os = os.first_text();
@@ -102,20 +257,17 @@ pub async fn modfiy_device(
     //             return Err(Status::BadRequest);
     //         }
     //     };
-    //     let _d: Device = database
-    //         .update(("device", &device_id))
-    //         .patch(PatchOp::replace("/os", to_string(&os).unwrap()))
-    //         .await
-    //         .unwrap();
+    //     device.os = os;
     // }
 
     let device_name = device_name.first_text();
     if let Some(dev_name) = device_name {
-        let _d: Option<Device> = database
-            .update(("device", &device_id))
-            .patch(PatchOp::replace("/name", dev_name))
-            .await
-            .unwrap();
+        device.name = dev_name;
+    }
+
+    if let Some(read_only) = read_only {
+        device.read_only = read_only;
+        info!("Device {} read-only : {}", device_id, read_only);
     }
 
     let remote_addr = match multipart_form.texts.get("RemoteAddr") {
@@ -124,15 +276,118 @@ pub async fn modfiy_device(
     };
 
     if remote_addr {
-        let _d: Option<Device> = database
-            .update(("device", &device_id))
-            .patch(PatchOp::replace(
-                "/last_remote_addr",
-                remote_address.to_string(),
-            ))
-            .await
-            .unwrap();
+        device.last_ip = remote_address.to_string();
+    }
+
+    match database.save_device(&device_id, &device).await {
+        Ok(()) => Ok(Status::Ok),
+        Err(e) => {
+            error!("{e}");
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+#[delete("/file", data = "<data>")]
+pub async fn delete_file(
+    auth: WritableDevice,
+    db: &State<DbInstance>,
+    data: Data<'_>,
+    content_type: &ContentType,
+) -> Result<Status, &'static str> {
+    let options = MultipartFormDataOptions::with_multipart_form_data_fields(vec![
+        MultipartFormDataField::text("DeviceName"),
+        MultipartFormDataField::text("FileName"),
+        MultipartFormDataField::text("RelativePath"),
+        MultipartFormDataField::text("Global"),
+        MultipartFormDataField::text("Owner"),
+    ]);
+
+    let form_result = MultipartFormData::parse(content_type, data, options).await;
+    parse_multipart_form_texts!(
+        multipart_form: form_result,
+        parse_error: "Error: Could not parse the request";
+        file_name: "FileName";
+        relative_path: "RelativePath";
+    );
+    let is_global = match multipart_form.texts.get("Global") {
+        Some(_t) => true,
+        None => false,
+    };
+    let owner = multipart_form.texts.get("Owner").first_text();
+
+    let database = &db.database;
+    let device_id = auth.device_id;
+    let library = match resolve_library(
+        database,
+        &device_id,
+        &auth.device,
+        is_global,
+        owner,
+        Access::ReadWrite,
+        "Error: finding access grants",
+        "Error: device has no access to this library",
+    )
+    .await
+    {
+        Ok(l) => l,
+        Err(e) => return Err(e),
+    };
+
+    if !library.allows(&relative_path) {
+        return Err("Error: device has no access to this path");
+    }
+    if !valid_file_name(&file_name) {
+        return Err("Error: invalid file name");
+    }
+
+    let file_id = gen_file_id(&relative_path, &file_name);
+    let result = remove_local_entry(database, &library, &file_id).await;
+    if let Err(e) = result {
+        return Err(e);
     }
 
     Ok(Status::Ok)
 }
+
+/*
+   Revokes a device, by default the calling one. Revoking other devices
+   needs an admin device. `Files` is one of Keep, Delete or Archive
+*/
+#[delete("/device", data = "<data>")]
+pub async fn revoke_device(
+    auth: AuthenticatedDevice,
+    db: &State<DbInstance>,
+    data: Data<'_>,
+    content_type: &ContentType,
+) -> Result<Value, Status> {
+    let options = MultipartFormDataOptions::with_multipart_form_data_fields(vec![
+        MultipartFormDataField::text("DeviceID"),
+        MultipartFormDataField::text("Files"),
+    ]);
+
+    let form_result = MultipartFormData::parse(content_type, data, options).await;
+    parse_multipart_form_texts!(
+        multipart_form: form_result,
+        parse_error: Status::BadRequest;
+        files: "Files";
+    );
+
+    let files: RevokedFiles = match files.parse() {
+        Ok(f) => f,
+        Err(_e) => return Err(Status::BadRequest),
+    };
+    let target = match multipart_form.texts.get("DeviceID").first_text() {
+        Some(id) => id,
+        None => auth.device_id.clone(),
+    };
+    if target != auth.device_id && !auth.device.admin {
+        return Err(Status::Forbidden);
+    }
+
+    match revocation::revoke_device(&db.database, &target, files).await {
+        Ok(r) => Ok(json!(r)),
+        Err(RevocationError::NotFound) => Err(Status::NotFound),
+        Err(_e) => Err(Status::InternalServerError),
+    }
+}
@@ -0,0 +1,285 @@
+use std::{
+    io::SeekFrom,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use chrono::{DateTime, TimeZone, Utc};
+use rocket::{
+    http::{ContentType, Header, Status},
+    request::{self, FromRequest, Request},
+    response::{self, Responder, Response},
+};
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, ReadBuf, Take},
+};
+
+use crate::server::db::local_table::SerializedMetadata;
+
+/*
+   Partial and conditional responses for pulled files
+
+   `ConditionalHeaders` collects Range / If-Range / If-None-Match /
+   If-Modified-Since from the request and `PartialFile::open` decides between
+   200, 206, 304 and 416. The ETag is the entry's content hash when it has one
+   (a strong tag), otherwise a weak tag from the stored `SerializedMetadata`.
+*/
+
+pub struct ConditionalHeaders {
+    range: Option<String>,
+    if_range: Option<String>,
+    if_none_match: Option<String>,
+    if_modified_since: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ConditionalHeaders {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let header = |name: &str| req.headers().get_one(name).map(String::from);
+        request::Outcome::Success(ConditionalHeaders {
+            range: header("Range"),
+            if_range: header("If-Range"),
+            if_none_match: header("If-None-Match"),
+            if_modified_since: header("If-Modified-Since"),
+        })
+    }
+}
+
+enum Body {
+    Full(File),
+    Partial(FileRange, u64, u64),
+    NotModified,
+    Unsatisfiable,
+}
+
+pub struct PartialFile {
+    body: Body,
+    len: u64,
+    etag: String,
+    last_modified: Option<String>,
+    content_type: Option<ContentType>,
+}
+
+impl PartialFile {
+    pub async fn open(
+        path: &std::path::Path,
+        metadata: &SerializedMetadata,
+        content_hash: Option<&str>,
+        headers: &ConditionalHeaders,
+    ) -> std::io::Result<PartialFile> {
+        let mut file = File::open(path).await?;
+        let len = file.metadata().await?.len();
+
+        let modified = metadata.modified();
+        // The content hash names the exact bytes, length and mtime only
+        // suggest them so that tag is weak
+        let etag = match content_hash {
+            Some(hash) => format!("\"{}\"", hash),
+            None => format!(
+                "W/\"{:x}-{:x}\"",
+                metadata.len().unwrap_or(len),
+                modified.unwrap_or(0)
+            ),
+        };
+        let last_modified = modified.and_then(http_date);
+        let content_type = metadata
+            .file_type()
+            .and_then(|ft| ContentType::parse_flexible(ft))
+            .or_else(|| {
+                path.extension()
+                    .and_then(|e| e.to_str())
+                    .and_then(ContentType::from_extension)
+            });
+
+        let mut partial = PartialFile {
+            body: Body::NotModified,
+            len,
+            etag,
+            last_modified,
+            content_type,
+        };
+
+        if partial.not_modified(headers, modified) {
+            return Ok(partial);
+        }
+
+        let range = match &headers.if_range {
+            Some(validator) if !partial.validator_matches(validator) => None,
+            _ => headers.range.as_deref(),
+        };
+        partial.body = match range.map(|r| parse_range(r, len)) {
+            None | Some(RangeRequest::Ignored) => Body::Full(file),
+            Some(RangeRequest::Unsatisfiable) => Body::Unsatisfiable,
+            Some(RangeRequest::Bytes(start, end)) => {
+                file.seek(SeekFrom::Start(start)).await?;
+                Body::Partial(FileRange::new(file, start, end + 1), start, end)
+            }
+        };
+        Ok(partial)
+    }
+
+    // If-None-Match wins over If-Modified-Since when both are present
+    fn not_modified(&self, headers: &ConditionalHeaders, modified: Option<u64>) -> bool {
+        if let Some(tags) = &headers.if_none_match {
+            return tags
+                .split(',')
+                .map(|t| t.trim().trim_start_matches("W/"))
+                .any(|t| t == "*" || t == self.etag.trim_start_matches("W/"));
+        }
+
+        match (&headers.if_modified_since, modified) {
+            (Some(since), Some(modified)) => match DateTime::parse_from_rfc2822(since) {
+                Ok(since) => modified as i64 <= since.timestamp(),
+                Err(_e) => false,
+            },
+            _ => false,
+        }
+    }
+
+    // If-Range only accepts a strong validator, a weak ETag never matches
+    fn validator_matches(&self, validator: &str) -> bool {
+        let validator = validator.trim();
+        let strong = !self.etag.starts_with("W/");
+        (strong && validator == self.etag) || Some(validator) == self.last_modified.as_deref()
+    }
+}
+
+impl<'r> Responder<'r, 'static> for PartialFile {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build();
+        response
+            .raw_header("Accept-Ranges", "bytes")
+            .raw_header("ETag", self.etag);
+        if let Some(last_modified) = self.last_modified {
+            response.raw_header("Last-Modified", last_modified);
+        }
+
+        match self.body {
+            Body::NotModified => {
+                response.status(Status::NotModified);
+            }
+            Body::Unsatisfiable => {
+                response
+                    .status(Status::RangeNotSatisfiable)
+                    .header(Header::new("Content-Range", format!("bytes */{}", self.len)));
+            }
+            Body::Full(file) => {
+                if let Some(content_type) = self.content_type {
+                    response.header(content_type);
+                }
+                response.sized_body(None, file);
+            }
+            Body::Partial(range, start, end) => {
+                if let Some(content_type) = self.content_type {
+                    response.header(content_type);
+                }
+                response
+                    .status(Status::PartialContent)
+                    .header(Header::new(
+                        "Content-Range",
+                        format!("bytes {}-{}/{}", start, end, self.len),
+                    ))
+                    .sized_body(None, range);
+            }
+        }
+        response.ok()
+    }
+}
+
+enum RangeRequest {
+    // Inclusive byte positions
+    Bytes(u64, u64),
+    Unsatisfiable,
+    // Malformed or multiple ranges, served as a normal 200
+    Ignored,
+}
+
+fn parse_range(header: &str, len: u64) -> RangeRequest {
+    let spec = match header.trim().strip_prefix("bytes=") {
+        Some(s) if !s.contains(',') => s.trim(),
+        _ => return RangeRequest::Ignored,
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(p) => p,
+        None => return RangeRequest::Ignored,
+    };
+
+    let (start, end) = match (start.parse::<u64>().ok(), end.parse::<u64>().ok()) {
+        // bytes=-500 is the last 500 bytes
+        (None, Some(suffix)) if start.is_empty() => {
+            if suffix == 0 || len == 0 {
+                return RangeRequest::Unsatisfiable;
+            }
+            (len.saturating_sub(suffix), len - 1)
+        }
+        (Some(start), None) if end.is_empty() => (start, len.saturating_sub(1)),
+        (Some(start), Some(end)) if start <= end => (start, end.min(len.saturating_sub(1))),
+        _ => return RangeRequest::Ignored,
+    };
+
+    if start >= len {
+        return RangeRequest::Unsatisfiable;
+    }
+    RangeRequest::Bytes(start, end)
+}
+
+fn http_date(secs: u64) -> Option<String> {
+    let date = Utc.timestamp_opt(secs as i64, 0).single()?;
+    Some(date.format("%a, %d %b %Y %H:%M:%S GMT").to_string())
+}
+
+/// Window `[start, end)` of a file which reads and seeks as if it was the
+/// whole body, so Rocket can size it for the Content-Length
+struct FileRange {
+    inner: Take<File>,
+    start: u64,
+    end: u64,
+}
+
+impl FileRange {
+    // The file has to be positioned at `start` already
+    fn new(file: File, start: u64, end: u64) -> FileRange {
+        FileRange {
+            inner: file.take(end - start),
+            start,
+            end,
+        }
+    }
+}
+
+impl AsyncRead for FileRange {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncSeek for FileRange {
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> std::io::Result<()> {
+        let pos = self.end - self.inner.limit();
+        let target = match position {
+            SeekFrom::Start(n) => self.start as i64 + n as i64,
+            SeekFrom::End(n) => self.end as i64 + n,
+            SeekFrom::Current(n) => pos as i64 + n,
+        };
+        let target = target.clamp(self.start as i64, self.end as i64) as u64;
+        Pin::new(self.inner.get_mut()).start_seek(SeekFrom::Start(target))
+    }
+
+    fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<u64>> {
+        match Pin::new(self.inner.get_mut()).poll_complete(cx) {
+            Poll::Ready(Ok(absolute)) => {
+                let remaining = self.end - absolute;
+                self.inner.set_limit(remaining);
+                Poll::Ready(Ok(absolute - self.start))
+            }
+            other => other,
+        }
+    }
+}
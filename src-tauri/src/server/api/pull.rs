@@ -1,33 +1,54 @@
-use rocket::{fs::NamedFile, http::ContentType, Data, State};
+use std::{
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use rocket::{
+    http::ContentType,
+    response::stream::{One, ReaderStream},
+    Data, State,
+};
 use rocket_multipart_form_data::{
     MultipartFormData, MultipartFormDataField, MultipartFormDataOptions,
 };
+use tokio::io::{AsyncRead, DuplexStream, ReadBuf};
+use tokio_util::io::SyncIoBridge;
 
 use crate::{
     parse_multipart_form_texts,
     server::{
-        db::{db_instance::DbInstance, local_table::LocalEntry},
-        utility::{gen_sha_256_hash, TextFieldExt},
+        auth::AuthenticatedDevice,
+        db::{db_instance::DbInstance, grant_table::Access},
+        utility::{gen_file_id, valid_file_name, TextFieldExt},
     },
 };
 
-use super::utility::{verify_device_id, verify_pin};
+use super::{
+    partial::{ConditionalHeaders, PartialFile},
+    utility::resolve_library,
+};
 
 #[get("/file", data = "<data>")]
 pub async fn pull_file(
+    auth: AuthenticatedDevice,
     content_type: &ContentType,
+    conditional: ConditionalHeaders,
     data: Data<'_>,
     db: &State<DbInstance>,
-) -> Result<NamedFile, &'static str> {
+) -> Result<PartialFile, &'static str> {
     let options = MultipartFormDataOptions {
         max_data_bytes: 100 * 1024 * 1024,
         allowed_fields: vec![
             MultipartFormDataField::text("FileName"),
             MultipartFormDataField::text("RelativePath"),
             MultipartFormDataField::text("Global"),
-            MultipartFormDataField::text("DeviceID"),
+            MultipartFormDataField::text("Owner"),
             MultipartFormDataField::text("DeviceName"),
-            MultipartFormDataField::text("PIN"),
         ],
         ..MultipartFormDataOptions::default()
     };
@@ -36,54 +57,44 @@ pub async fn pull_file(
     parse_multipart_form_texts!(
         multipart_form: form_result,
         parse_error: "Error: Could not parse the request";
-        device_id: "DeviceID";
-        _device_name: "DeviceName";
         file_name: "FileName";
         relative_path: "RelativePath";
-        pin: "PIN";
     );
 
-    let _is_global = match multipart_form.texts.get("Global") {
+    let is_global = match multipart_form.texts.get("Global") {
         Some(_t) => true,
         None => false,
     };
+    let owner = multipart_form.texts.get("Owner").first_text();
 
     let database = &db.database;
-
-    // Check Device Entry
-    let result = verify_device_id::<&'static str>(
+    let device_id = auth.device_id;
+    let library = match resolve_library(
         database,
         &device_id,
-        "Error: finding device in database",
-        "Device is not present in database",
+        &auth.device,
+        is_global,
+        owner,
+        Access::Read,
+        "Error: finding access grants",
+        "Error: device has no access to this library",
     )
-    .await;
+    .await
+    {
+        Ok(l) => l,
+        Err(e) => return Err(e),
+    };
 
-    if let Err(e) = result {
-        return Err(e);
+    if !library.allows(&relative_path) {
+        return Err("Error: device has no access to this path");
     }
-
-    // Verify Pin
-    let result = verify_pin(
-        database,
-        &device_id,
-        &pin,
-        "Error: finding device hash in database\nCould not verify",
-        "Couldn't find any auth entires for device ID",
-        "Unauthorized",
-    )
-    .await;
-
-    if let Err(e) = result {
-        return Err(e);
+    if !valid_file_name(&file_name) {
+        return Err("Error: invalid file name");
     }
 
     // Check for local Entry
-    let file_id = gen_sha_256_hash(&(relative_path + &file_name));
-    let local: Result<Option<LocalEntry>, surrealdb::Error> =
-        database.select((&device_id, &file_id)).await;
-
-    let local = match local {
+    let file_id = gen_file_id(&relative_path, &file_name);
+    let local = match database.get_entry(&library.table, &file_id).await {
         Ok(d) => d,
         Err(e) => {
             error!("{e}");
@@ -92,14 +103,21 @@ pub async fn pull_file(
     };
 
     let local = match local {
-        Some(l) => l,
+        Some(l) if library.allows(&l.relative_path) => l,
+        Some(_l) => return Err("Error: device has no access to this path"),
         None => return Err("Could not find the file"),
     };
 
     let file_path = std::path::PathBuf::from(local.file_location);
 
     if file_path.exists() {
-        let file_obj = NamedFile::open(file_path).await;
+        let file_obj = PartialFile::open(
+            &file_path,
+            &local.metadata,
+            local.content_hash.as_deref(),
+            &conditional,
+        )
+        .await;
         match file_obj {
             Err(e) => {
                 error!("{}", e);
@@ -112,5 +130,140 @@ pub async fn pull_file(
     }
 }
 
-// #[get("/folder")]
-// async fn pull_folder() {}
+// Read side of the archive pipe. Fails at the end when writing the archive
+// failed, so the response is aborted instead of ending like a complete one
+pub struct ArchiveReader {
+    inner: DuplexStream,
+    failed: Arc<AtomicBool>,
+}
+
+impl AsyncRead for ArchiveReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled = buf.filled().len();
+        match Pin::new(&mut self.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) if buf.filled().len() == filled => {
+                match self.failed.load(Ordering::SeqCst) {
+                    true => Poll::Ready(Err(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        "the archive could not be completed",
+                    ))),
+                    false => Poll::Ready(Ok(())),
+                }
+            }
+            other => other,
+        }
+    }
+}
+
+/*
+   Streams every file under `RelativePath` as a tar archive. The archive is
+   written into a pipe while the client reads it, nothing is staged on disk.
+   If a file cannot be read the response is cut off, never finished
+*/
+#[get("/folder", data = "<data>")]
+pub async fn pull_folder(
+    auth: AuthenticatedDevice,
+    content_type: &ContentType,
+    data: Data<'_>,
+    db: &State<DbInstance>,
+) -> Result<(ContentType, ReaderStream<One<ArchiveReader>>), &'static str> {
+    let options = MultipartFormDataOptions::with_multipart_form_data_fields(vec![
+        MultipartFormDataField::text("RelativePath"),
+        MultipartFormDataField::text("Global"),
+        MultipartFormDataField::text("Owner"),
+        MultipartFormDataField::text("DeviceName"),
+    ]);
+
+    let form_result = MultipartFormData::parse(content_type, data, options).await;
+    parse_multipart_form_texts!(
+        multipart_form: form_result,
+        parse_error: "Error: Could not parse the request";
+        relative_path: "RelativePath";
+    );
+    let is_global = match multipart_form.texts.get("Global") {
+        Some(_t) => true,
+        None => false,
+    };
+    let owner = multipart_form.texts.get("Owner").first_text();
+
+    let database = &db.database;
+    let device_id = auth.device_id;
+    let library = match resolve_library(
+        database,
+        &device_id,
+        &auth.device,
+        is_global,
+        owner,
+        Access::Read,
+        "Error: finding access grants",
+        "Error: device has no access to this library",
+    )
+    .await
+    {
+        Ok(l) => l,
+        Err(e) => return Err(e),
+    };
+
+    let local_entries = match database.list_entries(&library.table).await {
+        Ok(e) => e,
+        Err(e) => {
+            error!("Error retriving entires:  {}", e);
+            return Err("Error: retriving entires");
+        }
+    };
+
+    // Paths inside the archive are relative to the requested folder
+    let prefix = PathBuf::from(&relative_path);
+    let files: Vec<(PathBuf, PathBuf)> = local_entries
+        .into_iter()
+        .map(|(_id, entry)| entry)
+        .filter(|entry| library.allows(&entry.relative_path) && !entry.lost)
+        .filter_map(|entry| {
+            let entry_dir = PathBuf::from(&entry.relative_path);
+            let inner = entry_dir.strip_prefix(&prefix).ok()?;
+            Some((
+                inner.join(&entry.file_name),
+                PathBuf::from(entry.file_location),
+            ))
+        })
+        .collect();
+
+    if files.is_empty() {
+        return Err("Could not find any files in the folder");
+    }
+
+    let (writer, reader) = tokio::io::duplex(64 * 1024);
+    let writer = SyncIoBridge::new(writer);
+    let failed = Arc::new(AtomicBool::new(false));
+    let writer_failed = failed.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut builder = tar::Builder::new(writer);
+        for (name, location) in files {
+            if let Err(e) = builder.append_path_with_name(&location, &name) {
+                // The client went away, no point in reading the other files
+                if e.kind() != std::io::ErrorKind::BrokenPipe {
+                    error!("Failed to add {:?} to archive: {}", location, e);
+                }
+                // Set before the builder is dropped and the pipe closes
+                writer_failed.store(true, Ordering::SeqCst);
+                return;
+            }
+        }
+        if let Err(e) = builder.finish() {
+            error!("Failed to finish archive: {}", e);
+            writer_failed.store(true, Ordering::SeqCst);
+        }
+    });
+
+    Ok((
+        ContentType::new("application", "x-tar"),
+        ReaderStream::one(ArchiveReader {
+            inner: reader,
+            failed,
+        }),
+    ))
+}
@@ -1,26 +1,47 @@
+use std::{
+    io::Read,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use chrono::{DateTime, Duration, Utc};
 use rocket::{
+    data::ToByteUnit,
     http::{ContentType, Status},
     Data, State,
 };
 use rocket_multipart_form_data::{
     MultipartFormData, MultipartFormDataField, MultipartFormDataOptions,
 };
+use serde_json::{json, Value};
+use tokio::io::AsyncSeekExt;
+use tokio_util::io::SyncIoBridge;
+use uuid::Uuid;
 
 use crate::{
     parse_multipart_form_texts,
     server::{
-        db::{db_instance::DbInstance, local_table::LocalEntry},
-        utility::{gen_sha_256_hash, TextFieldExt},
+        auth::WritableDevice,
+        blob_store::{find_blob, ingest_blob},
+        db::{
+            db_instance::DbInstance,
+            grant_table::Access,
+            repository::Repository,
+            upload_table::{StoredChunk, UploadSession, UPLOAD_SESSION_TTL_HOURS},
+        },
+        fsck::hold_off_repairs,
+        utility::{valid_file_name, TextFieldExt},
     },
 };
 
-use super::utility::{
-    generate_blurhash, get_file_meta, is_image_file, save_file_to_documents, verify_device_id,
-    verify_pin,
-};
+use super::utility::{get_staging_dir, resolve_library, store_library_entry, PushOrigin};
+
+// Largest body accepted for a single chunk of a chunked upload
+const MAX_CHUNK_MIB: u64 = 64;
 
 #[post("/file", data = "<data>")]
 pub async fn push_file(
+    auth: WritableDevice,
     content_type: &ContentType,
     data: Data<'_>,
     db: &State<DbInstance>,
@@ -32,11 +53,12 @@ pub async fn push_file(
             MultipartFormDataField::text("FileName"),
             MultipartFormDataField::text("RelativePath"),
             MultipartFormDataField::text("Global"),
-            MultipartFormDataField::text("DeviceID"),
+            MultipartFormDataField::text("Owner"),
             MultipartFormDataField::text("DeviceName"),
-            MultipartFormDataField::text("PIN"),
             MultipartFormDataField::text("DirPath"),
             MultipartFormDataField::text("ClientPath"),
+            MultipartFormDataField::text("BaseHash"),
+            MultipartFormDataField::text("Modified"),
         ],
         ..MultipartFormDataOptions::default()
     };
@@ -45,112 +67,933 @@ pub async fn push_file(
     parse_multipart_form_texts!(
         multipart_form: form_result,
         parse_error: "Error: Could not parse the request";
-        device_id: "DeviceID";
-        device_name: "DeviceName";
         file_name: "FileName";
         relative_path: "RelativePath";
         dir_path: "DirPath";
         client_path: "ClientPath";
-        pin: "PIN";
     );
 
     // Get fields
     let file = multipart_form.files.get("File");
     let file = file.unwrap().first().unwrap();
-    let _is_global = match multipart_form.texts.get("Global") {
+    let is_global = match multipart_form.texts.get("Global") {
         Some(_t) => true,
         None => false,
     };
+    let owner = multipart_form.texts.get("Owner").first_text();
+    let base_hash = multipart_form.texts.get("BaseHash").first_text();
+    let modified = match multipart_form.texts.get("Modified").first_number() {
+        Ok(m) => m,
+        Err(e) => return Err(e),
+    };
 
     let database = &db.database;
-
-    // Check Device Entry
-    let result = verify_device_id(
+    let device_id = auth.device_id;
+    let library = match resolve_library(
         database,
         &device_id,
-        "Error: finding device in database",
-        "Device is not present in database",
+        &auth.device,
+        is_global,
+        owner,
+        Access::ReadWrite,
+        "Error: finding access grants",
+        "Error: device has no access to this library",
+    )
+    .await
+    {
+        Ok(l) => l,
+        Err(e) => return Err(e),
+    };
+    if !library.allows(&relative_path) {
+        return Err("Error: device has no access to this path");
+    }
+    if !valid_file_name(&file_name) {
+        return Err("Error: invalid file name");
+    }
+
+    // Save File, identical content is only stored once
+    let _repairs = hold_off_repairs().await;
+    let blob = match ingest_blob(&file.path, false).await {
+        Some(b) => b,
+        None => {
+            return Err("Unable to save file on server");
+        }
+    };
+
+    let origin = PushOrigin {
+        device_id: &device_id,
+        device_name: &auth.device.name,
+        base_hash: base_hash.as_deref(),
+        modified,
+    };
+    let result = store_library_entry(
+        database,
+        &library.table,
+        &origin,
+        &blob,
+        &file_name,
+        file.content_type.clone(),
+        &dir_path,
+        &client_path,
+        &relative_path,
     )
     .await;
 
-    if let Err(e) = result {
-        return Err(e);
+    match result {
+        // Kept next to a newer version in the global library
+        Ok(true) => Ok(Status::Conflict),
+        Ok(false) => Ok(Status::Accepted),
+        Err(e) => Err(e),
     }
+}
 
-    // Verify Pin
-    let result = verify_pin(
+// Largest folder archive accepted by `push_folder`
+const MAX_ARCHIVE_BYTES: u64 = 16 * 1024 * 1024 * 1024;
+
+// A regular file taken out of an uploaded archive, waiting to be stored
+struct UnpackedFile {
+    parent: PathBuf,
+    file_name: String,
+    staged: PathBuf,
+    // mtime from the tar header, the client's mtime of the file
+    modified: Option<u64>,
+}
+
+// Fields of a folder push, sent in the query as the body is the archive
+#[derive(FromForm)]
+pub struct FolderPush {
+    #[field(name = "RelativePath")]
+    relative_path: String,
+    #[field(name = "DirPath")]
+    dir_path: String,
+    #[field(name = "ClientPath")]
+    client_path: String,
+    #[field(name = "Global")]
+    global: Option<String>,
+    #[field(name = "Owner")]
+    owner: Option<String>,
+}
+
+/*
+   Pushes a whole folder as a tar archive in the request body, every regular
+   file in it becomes its own LocalEntry below `RelativePath`. The archive is
+   unpacked while it is received, only the files themselves touch the disk
+*/
+#[post("/folder?<fields..>", data = "<data>")]
+pub async fn push_folder(
+    auth: WritableDevice,
+    fields: FolderPush,
+    data: Data<'_>,
+    db: &State<DbInstance>,
+) -> Result<Value, (Status, &'static str)> {
+    let FolderPush {
+        relative_path,
+        dir_path,
+        client_path,
+        global,
+        owner,
+    } = fields;
+
+    let database = &db.database;
+    let device_id = auth.device_id;
+    let library = match resolve_library(
         database,
         &device_id,
-        &pin,
-        "Error: finding device hash in database\nCould not verify",
-        "Couldn't find any auth entires for device ID",
-        "Unauthorized",
+        &auth.device,
+        global.is_some(),
+        owner,
+        Access::ReadWrite,
+        (Status::InternalServerError, "Error: finding access grants"),
+        (
+            Status::Forbidden,
+            "Error: device has no access to this library",
+        ),
+    )
+    .await
+    {
+        Ok(l) => l,
+        Err(e) => return Err(e),
+    };
+
+    let staging_dir = match get_staging_dir(&Uuid::new_v4().to_string()).await {
+        Some(d) => d,
+        None => {
+            return Err((
+                Status::InternalServerError,
+                "Unable to create staging directory on server",
+            ))
+        }
+    };
+    if let Err(e) = tokio::fs::create_dir_all(&staging_dir).await {
+        error!("Failed to create staging directory: {}", e);
+        return Err((
+            Status::InternalServerError,
+            "Unable to create staging directory on server",
+        ));
+    }
+
+    // The body is piped into the blocking tar reader as it arrives
+    let (mut writer, reader) = tokio::io::duplex(64 * 1024);
+    let unpack_dir = staging_dir.clone();
+    let unpacking =
+        tokio::task::spawn_blocking(move || unpack_archive(SyncIoBridge::new(reader), &unpack_dir));
+    let received = data
+        .open(MAX_ARCHIVE_BYTES.bytes())
+        .stream_to(&mut writer)
+        .await;
+    drop(writer);
+
+    let unpacked = match unpacking.await {
+        Ok(Ok(u)) => Ok(u),
+        Ok(Err(e)) => {
+            error!("Failed to unpack archive: {}", e);
+            Err((Status::BadRequest, "Error: Archive is not a valid tar file"))
+        }
+        Err(e) => {
+            error!("Failed to unpack archive: {}", e);
+            Err((
+                Status::InternalServerError,
+                "Unable to save files on server",
+            ))
+        }
+    };
+    let unpacked = match (received, unpacked) {
+        // Cutting the body off at the limit also truncates the archive, so
+        // this goes first or the size would be reported as a broken tar
+        (Ok(n), _unpacked) if !n.complete => {
+            let _ = tokio::fs::remove_dir_all(&staging_dir).await;
+            return Err((
+                Status::PayloadTooLarge,
+                "Error: Archive is larger than the 16 GiB limit",
+            ));
+        }
+        (Ok(_n), Ok(u)) => u,
+        // The reader gives up on a broken archive, which also breaks the pipe
+        (_received, Err(e)) => {
+            let _ = tokio::fs::remove_dir_all(&staging_dir).await;
+            return Err(e);
+        }
+        (Err(e), Ok(_u)) => {
+            error!("Failed to receive archive: {}", e);
+            let _ = tokio::fs::remove_dir_all(&staging_dir).await;
+            return Err((
+                Status::InternalServerError,
+                "Unable to save files on server",
+            ));
+        }
+    };
+
+    let mut stored = 0;
+    let mut conflicts = Vec::new();
+    let mut failed = Vec::new();
+    let _repairs = hold_off_repairs().await;
+    for file in unpacked {
+        let path_text = |base: &String| {
+            PathBuf::from(base)
+                .join(&file.parent)
+                .to_string_lossy()
+                .to_string()
+        };
+        let display_name = file.parent.join(&file.file_name);
+        if !library.allows(&path_text(&relative_path)) || !valid_file_name(&file.file_name) {
+            failed.push(display_name);
+            continue;
+        }
+
+        let blob = match ingest_blob(&file.staged, true).await {
+            Some(b) => b,
+            None => {
+                failed.push(display_name);
+                continue;
+            }
+        };
+        let origin = PushOrigin {
+            device_id: &device_id,
+            device_name: &auth.device.name,
+            base_hash: None,
+            modified: file.modified,
+        };
+        let result = store_library_entry(
+            database,
+            &library.table,
+            &origin,
+            &blob,
+            &file.file_name,
+            None,
+            &path_text(&dir_path),
+            &path_text(&client_path),
+            &path_text(&relative_path),
+        )
+        .await;
+        match result {
+            Ok(false) => stored += 1,
+            Ok(true) => conflicts.push(display_name),
+            Err(e) => {
+                error!("Failed to store {:?}: {}", display_name, e);
+                failed.push(display_name);
+            }
+        }
+    }
+
+    if let Err(e) = tokio::fs::remove_dir_all(&staging_dir).await {
+        error!("Failed to remove staging directory: {}", e);
+    }
+
+    Ok(json!({
+        "Stored": stored,
+        "Conflicts": conflicts,
+        "Failed": failed,
+    }))
+}
+
+// Extracts the regular files of a tar stream into the staging directory,
+// anything trying to escape the folder (absolute paths, `..`) is rejected
+fn unpack_archive<R: Read>(archive: R, staging_dir: &Path) -> std::io::Result<Vec<UnpackedFile>> {
+    let mut archive = tar::Archive::new(archive);
+    let mut unpacked = Vec::new();
+
+    for (index, entry) in archive.entries()?.enumerate() {
+        let mut entry = entry?;
+        if entry.header().entry_type() != tar::EntryType::Regular {
+            continue;
+        }
+
+        let path = entry.path()?.into_owned();
+        if !path.components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("unsafe path in archive: {:?}", path),
+            ));
+        }
+        let file_name = match path.file_name().and_then(|n| n.to_str()) {
+            Some(n) => n.to_string(),
+            None => continue,
+        };
+        let parent = path.parent().map(Path::to_path_buf).unwrap_or_default();
+
+        let modified = entry.header().mtime().ok();
+        let staged = staging_dir.join(index.to_string());
+        entry.unpack(&staged)?;
+        unpacked.push(UnpackedFile {
+            parent,
+            file_name,
+            staged,
+            modified,
+        });
+    }
+
+    Ok(unpacked)
+}
+
+/*
+   Asks the server if it already holds content with the given sha256 and size.
+   If it does the LocalEntry is created or updated without any transfer and
+   `Present` is true, otherwise the client has to push the bytes.
+*/
+#[post("/hash", data = "<data>")]
+pub async fn push_hash(
+    auth: WritableDevice,
+    content_type: &ContentType,
+    data: Data<'_>,
+    db: &State<DbInstance>,
+) -> Result<Value, &'static str> {
+    let options = MultipartFormDataOptions::with_multipart_form_data_fields(vec![
+        MultipartFormDataField::text("Hash"),
+        MultipartFormDataField::text("FileSize"),
+        MultipartFormDataField::text("ContentType"),
+        MultipartFormDataField::text("FileName"),
+        MultipartFormDataField::text("RelativePath"),
+        MultipartFormDataField::text("Global"),
+        MultipartFormDataField::text("Owner"),
+        MultipartFormDataField::text("DeviceName"),
+        MultipartFormDataField::text("DirPath"),
+        MultipartFormDataField::text("ClientPath"),
+        MultipartFormDataField::text("BaseHash"),
+        MultipartFormDataField::text("Modified"),
+    ]);
+
+    let form_result = MultipartFormData::parse(content_type, data, options).await;
+    parse_multipart_form_texts!(
+        multipart_form: form_result,
+        parse_error: "Error: Could not parse the request";
+        hash: "Hash";
+        file_size: "FileSize";
+        file_name: "FileName";
+        relative_path: "RelativePath";
+        dir_path: "DirPath";
+        client_path: "ClientPath";
+    );
+
+    let file_size: u64 = match file_size.parse() {
+        Ok(s) => s,
+        Err(_e) => return Err("Error: FileSize is not a valid number"),
+    };
+    let hash = hash.to_lowercase();
+    if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err("Error: Hash is not a valid sha256");
+    }
+    let mime = multipart_form
+        .texts
+        .get("ContentType")
+        .and_then(|t| t.first())
+        .and_then(|t| t.text.parse().ok());
+    let is_global = match multipart_form.texts.get("Global") {
+        Some(_t) => true,
+        None => false,
+    };
+    let owner = multipart_form.texts.get("Owner").first_text();
+    let base_hash = multipart_form.texts.get("BaseHash").first_text();
+    let modified = match multipart_form.texts.get("Modified").first_number() {
+        Ok(m) => m,
+        Err(e) => return Err(e),
+    };
+
+    let database = &db.database;
+    let device_id = auth.device_id;
+    let library = match resolve_library(
+        database,
+        &device_id,
+        &auth.device,
+        is_global,
+        owner,
+        Access::ReadWrite,
+        "Error: finding access grants",
+        "Error: device has no access to this library",
+    )
+    .await
+    {
+        Ok(l) => l,
+        Err(e) => return Err(e),
+    };
+    if !library.allows(&relative_path) {
+        return Err("Error: device has no access to this path");
+    }
+    if !valid_file_name(&file_name) {
+        return Err("Error: invalid file name");
+    }
+
+    let _repairs = hold_off_repairs().await;
+    let blob = match find_blob(database, &hash, file_size).await {
+        Ok(b) => b,
+        Err(e) => {
+            error!("{e}");
+            return Err("Error: finding blob in database");
+        }
+    };
+    let blob = match blob {
+        Some(b) => b,
+        None => return Ok(json!({ "Present": false })),
+    };
+
+    let origin = PushOrigin {
+        device_id: &device_id,
+        device_name: &auth.device.name,
+        base_hash: base_hash.as_deref(),
+        modified,
+    };
+    let result = store_library_entry(
+        database,
+        &library.table,
+        &origin,
+        &blob,
+        &file_name,
+        mime,
+        &dir_path,
+        &client_path,
+        &relative_path,
     )
     .await;
 
-    if let Err(e) = result {
+    match result {
+        Ok(conflict) => Ok(json!({ "Present": true, "Conflict": conflict })),
+        Err(e) => Err(e),
+    }
+}
+
+/*
+   Chunked uploads
+
+   1. POST /push/session                   -> opens a session and returns its id
+   2. PUT  /push/session/<id>/<offset>     -> stores the raw body as the chunk at offset
+   3. GET  /push/session/<id>              -> lists the chunks already stored
+   4. POST /push/session/<id>/commit       -> assembles the file and creates the LocalEntry
+*/
+
+#[post("/session", data = "<data>")]
+pub async fn open_upload_session(
+    auth: WritableDevice,
+    content_type: &ContentType,
+    data: Data<'_>,
+    db: &State<DbInstance>,
+) -> Result<String, (Status, &'static str)> {
+    let options = MultipartFormDataOptions::with_multipart_form_data_fields(vec![
+        MultipartFormDataField::text("FileName"),
+        MultipartFormDataField::text("FileSize"),
+        MultipartFormDataField::text("ContentType"),
+        MultipartFormDataField::text("RelativePath"),
+        MultipartFormDataField::text("Global"),
+        MultipartFormDataField::text("Owner"),
+        MultipartFormDataField::text("DeviceName"),
+        MultipartFormDataField::text("DirPath"),
+        MultipartFormDataField::text("ClientPath"),
+        MultipartFormDataField::text("BaseHash"),
+        MultipartFormDataField::text("Modified"),
+    ]);
+
+    let form_result = MultipartFormData::parse(content_type, data, options).await;
+    parse_multipart_form_texts!(
+        multipart_form: form_result,
+        parse_error: (Status::BadRequest, "Error: Could not parse the request");
+        file_name: "FileName";
+        file_size: "FileSize";
+        relative_path: "RelativePath";
+        dir_path: "DirPath";
+        client_path: "ClientPath";
+    );
+
+    let file_size: u64 = match file_size.parse() {
+        Ok(s) => s,
+        Err(_e) => return Err((Status::BadRequest, "Error: FileSize is not a valid number")),
+    };
+    let mime = multipart_form
+        .texts
+        .get("ContentType")
+        .and_then(|t| t.first())
+        .map(|t| t.text.clone());
+    let is_global = match multipart_form.texts.get("Global") {
+        Some(_t) => true,
+        None => false,
+    };
+    let owner = multipart_form.texts.get("Owner").first_text();
+    // Optional, the name the device paired with is used when it is missing
+    let device_name = multipart_form
+        .texts
+        .get("DeviceName")
+        .first_text()
+        .unwrap_or_else(|| auth.device.name.clone());
+    let base_hash = multipart_form.texts.get("BaseHash").first_text();
+    let modified = match multipart_form.texts.get("Modified").first_number() {
+        Ok(m) => m,
+        Err(e) => return Err((Status::BadRequest, e)),
+    };
+
+    let database = &db.database;
+    let device_id = auth.device_id;
+    let library = match resolve_library(
+        database,
+        &device_id,
+        &auth.device,
+        is_global,
+        owner.clone(),
+        Access::ReadWrite,
+        (Status::InternalServerError, "Error: finding access grants"),
+        (
+            Status::Forbidden,
+            "Error: device has no access to this library",
+        ),
+    )
+    .await
+    {
+        Ok(l) => l,
+        Err(e) => return Err(e),
+    };
+    if !library.allows(&relative_path) {
+        return Err((
+            Status::Forbidden,
+            "Error: device has no access to this path",
+        ));
+    }
+    if !valid_file_name(&file_name) {
+        return Err((Status::BadRequest, "Error: invalid file name"));
+    }
+
+    let session = UploadSession::new(
+        device_id,
+        device_name,
+        file_name,
+        file_size,
+        mime,
+        dir_path,
+        client_path,
+        relative_path,
+        is_global,
+        owner,
+        base_hash,
+        modified,
+    );
+
+    let staging_dir = match get_staging_dir(&session.session_id).await {
+        Some(d) => d,
+        None => {
+            return Err((
+                Status::InternalServerError,
+                "Unable to create staging directory on server",
+            ))
+        }
+    };
+    if let Err(e) = tokio::fs::create_dir_all(&staging_dir).await {
+        error!("Failed to create staging directory: {}", e);
+        return Err((
+            Status::InternalServerError,
+            "Unable to create staging directory on server",
+        ));
+    }
+
+    if let Err(e) = database.save_upload(&session).await {
+        error!("{e}");
+        return Err((
+            Status::InternalServerError,
+            "Error: could not create upload session",
+        ));
+    }
+
+    info!("Upload session opened : {}", session.session_id);
+    Ok(session.session_id)
+}
+
+#[put("/session/<session_id>/<offset>", data = "<data>")]
+pub async fn push_chunk(
+    auth: WritableDevice,
+    session_id: &str,
+    offset: u64,
+    data: Data<'_>,
+    db: &State<DbInstance>,
+) -> Result<Status, Status> {
+    let database = &db.database;
+
+    let session = match find_upload_session(database, &auth.device_id, session_id).await {
+        Ok(s) => s,
+        Err(e) => return Err(e),
+    };
+    if offset >= session.file_size {
+        return Err(Status::BadRequest);
+    }
+
+    let staging_dir = match get_staging_dir(session_id).await {
+        Some(d) => d,
+        None => return Err(Status::InternalServerError),
+    };
+
+    // Write into a temporary file first so a dropped connection never
+    // leaves a truncated chunk behind that looks complete, every request
+    // gets its own so retries of the same chunk cannot interleave
+    let temp_path = staging_dir.join(format!("{offset}.{}.part.tmp", Uuid::new_v4()));
+    let chunk_path = staging_dir.join(format!("{offset}.part"));
+
+    let written = data
+        .open(MAX_CHUNK_MIB.mebibytes())
+        .into_file(&temp_path)
+        .await;
+    let written = match written {
+        Ok(w) => w,
+        Err(e) => {
+            error!("Failed to write chunk {} for {}: {}", offset, session_id, e);
+            let _ = tokio::fs::remove_file(&temp_path).await;
+            return Err(Status::InternalServerError);
+        }
+    };
+
+    if !written.is_complete() {
+        let _ = tokio::fs::remove_file(&temp_path).await;
+        return Err(Status::PayloadTooLarge);
+    }
+    if offset + written.n.written > session.file_size {
+        let _ = tokio::fs::remove_file(&temp_path).await;
+        return Err(Status::BadRequest);
+    }
+
+    if let Err(e) = tokio::fs::rename(&temp_path, &chunk_path).await {
+        error!("Failed to store chunk {} for {}: {}", offset, session_id, e);
+        return Err(Status::InternalServerError);
+    }
+
+    Ok(Status::Created)
+}
+
+#[get("/session/<session_id>")]
+pub async fn upload_session_status(
+    auth: WritableDevice,
+    session_id: &str,
+    db: &State<DbInstance>,
+) -> Result<Value, Status> {
+    let database = &db.database;
+
+    let session = match find_upload_session(database, &auth.device_id, session_id).await {
+        Ok(s) => s,
+        Err(e) => return Err(e),
+    };
+
+    let staging_dir = match get_staging_dir(session_id).await {
+        Some(d) => d,
+        None => return Err(Status::InternalServerError),
+    };
+    let chunks = match read_stored_chunks(&staging_dir).await {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Failed to read chunks for {}: {}", session_id, e);
+            return Err(Status::InternalServerError);
+        }
+    };
+    let received: u64 = chunks.iter().map(|c| c.len).sum();
+
+    Ok(json!({
+        "SessionID": session.session_id,
+        "FileSize": session.file_size,
+        "Received": received,
+        "Chunks": chunks,
+    }))
+}
+
+#[post("/session/<session_id>/commit")]
+pub async fn commit_upload_session(
+    auth: WritableDevice,
+    session_id: &str,
+    db: &State<DbInstance>,
+) -> Result<Status, &'static str> {
+    let database = &db.database;
+
+    let session = match find_upload_session(database, &auth.device_id, session_id).await {
+        Ok(s) => s,
+        Err(_e) => return Err("Could not find the upload session"),
+    };
+
+    // Access is checked again before anything is stored, it may have been
+    // revoked since the session was opened
+    let library = match resolve_library(
+        database,
+        &session.device_id,
+        &auth.device,
+        session.global,
+        session.owner.clone(),
+        Access::ReadWrite,
+        "Error: finding access grants",
+        "Error: device has no access to this library",
+    )
+    .await
+    {
+        Ok(l) => l,
+        Err(e) => return Err(e),
+    };
+    if !library.allows(&session.relative_path) {
+        return Err("Error: device has no access to this path");
+    }
+
+    let staging_dir = match get_staging_dir(session_id).await {
+        Some(d) => d,
+        None => return Err("Unable to find staging directory on server"),
+    };
+    let chunks = match read_stored_chunks(&staging_dir).await {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Failed to read chunks for {}: {}", session_id, e);
+            return Err("Error: reading stored chunks");
+        }
+    };
+
+    let file_path = staging_dir.join("assembled");
+    if let Err(e) = assemble_chunks(&staging_dir, &chunks, session.file_size, &file_path).await {
+        let _ = tokio::fs::remove_file(&file_path).await;
         return Err(e);
     }
 
-    // Save File
-    let file_status = save_file_to_documents(file, &file_name, &relative_path, &device_name).await;
-    let file_path = match file_status {
-        Some(f) => f,
+    let _repairs = hold_off_repairs().await;
+    let blob = match ingest_blob(&file_path, true).await {
+        Some(b) => b,
         None => {
+            let _ = tokio::fs::remove_file(&file_path).await;
             return Err("Unable to save file on server");
         }
     };
 
-    let file_meta = match get_file_meta(&file_path) {
-        Some(f) => f,
-        None => return Err("Error: Unable to get file metadata "),
+    let origin = PushOrigin {
+        device_id: &session.device_id,
+        device_name: &auth.device.name,
+        base_hash: session.base_hash.as_deref(),
+        modified: session.modified,
+    };
+    let result = store_library_entry(
+        database,
+        &library.table,
+        &origin,
+        &blob,
+        &session.file_name,
+        session.content_type.as_ref().and_then(|ct| ct.parse().ok()),
+        &session.dir_path,
+        &session.client_path,
+        &session.relative_path,
+    )
+    .await;
+
+    let conflict = match result {
+        Ok(c) => c,
+        Err(e) => return Err(e),
     };
-    let check_image = is_image_file(&file_path.clone());
 
-    // Check for local Entry
-    let file_id = gen_sha_256_hash(&(relative_path.clone() + &file_name));
-    let local: Result<Option<LocalEntry>, surrealdb::Error> =
-        database.select((&device_id, &file_id)).await;
+    // Cleanup the session, failures here only leave garbage behind
+    if let Err(e) = tokio::fs::remove_dir_all(&staging_dir).await {
+        error!("Failed to remove staging directory: {}", e);
+    }
+    if let Err(e) = database.delete_upload(session_id).await {
+        error!("Failed to remove upload session : {e}");
+    }
 
-    let local = match local {
-        Ok(d) => d,
+    match conflict {
+        true => Ok(Status::Conflict),
+        false => Ok(Status::Accepted),
+    }
+}
+
+// Sessions of other devices and expired ones are reported as missing
+async fn find_upload_session(
+    database: &dyn Repository,
+    device_id: &str,
+    session_id: &str,
+) -> Result<UploadSession, Status> {
+    match database.get_upload(session_id).await {
+        Ok(Some(s)) if s.device_id == device_id && !s.is_expired() => Ok(s),
+        Ok(_) => Err(Status::NotFound),
         Err(e) => {
             error!("{e}");
-            return Err("Error: finding device hash in database\nCould not verify");
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+// Lists `<offset>.part` files in the staging directory sorted by offset
+async fn read_stored_chunks(staging_dir: &std::path::Path) -> std::io::Result<Vec<StoredChunk>> {
+    let mut chunks = Vec::new();
+    let mut entries = tokio::fs::read_dir(staging_dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name();
+        let offset = match name
+            .to_str()
+            .and_then(|n| n.strip_suffix(".part"))
+            .and_then(|n| n.parse::<u64>().ok())
+        {
+            Some(o) => o,
+            None => continue,
+        };
+        let len = entry.metadata().await?.len();
+        chunks.push(StoredChunk { offset, len });
+    }
+    chunks.sort_by_key(|c| c.offset);
+    Ok(chunks)
+}
+
+// Concatenates the stored chunks into the target file, chunks may overlap
+// when a client re-sent a range but there must not be any gaps
+async fn assemble_chunks(
+    staging_dir: &std::path::Path,
+    chunks: &[StoredChunk],
+    file_size: u64,
+    target: &std::path::Path,
+) -> Result<(), &'static str> {
+    let mut covered: u64 = 0;
+    for chunk in chunks {
+        if chunk.offset > covered {
+            return Err("Upload is incomplete, missing chunks");
+        }
+        covered = covered.max(chunk.offset + chunk.len);
+    }
+    if covered != file_size {
+        return Err("Upload is incomplete, missing chunks");
+    }
+
+    let mut target_file = match tokio::fs::File::create(target).await {
+        Ok(f) => f,
+        Err(e) => {
+            error!("Failed to create target file: {}", e);
+            return Err("Unable to save file on server");
         }
     };
-    let new_local_entry = LocalEntry::new(
-        device_id.clone(),
-        file_name.clone(),
-        file_meta.len(),
-        String::from(file_path.to_str().unwrap()),
-        file.content_type.clone(),
-        match check_image {
-            true => generate_blurhash(&file_path).await,
-            false => None,
-        },
-        dir_path.clone(),
-        client_path.clone(),
-        relative_path.clone(),
-    );
-    let _res = match local {
-        Some(_instance) => {
-            let local_l: Result<Option<LocalEntry>, _> = database
-                .update((&device_id, &file_id))
-                .content(new_local_entry)
-                .await;
-            local_l
+
+    let mut written: u64 = 0;
+    for chunk in chunks {
+        let end = chunk.offset + chunk.len;
+        if end <= written {
+            continue;
         }
-        None => {
-            let local_l: Result<Option<LocalEntry>, _> = database
-                .create((&device_id, &file_id))
-                .content(new_local_entry)
-                .await;
-            local_l
+        let chunk_path = staging_dir.join(format!("{}.part", chunk.offset));
+        let mut reader = match tokio::fs::File::open(&chunk_path).await {
+            Ok(f) => f,
+            Err(e) => {
+                error!("Failed to open chunk: {}", e);
+                return Err("Error: reading stored chunks");
+            }
+        };
+        if let Err(e) = reader
+            .seek(std::io::SeekFrom::Start(written - chunk.offset))
+            .await
+        {
+            error!("Failed to seek chunk: {}", e);
+            return Err("Error: reading stored chunks");
+        }
+        if let Err(e) = tokio::io::copy(&mut reader, &mut target_file).await {
+            error!("Failed to write to target file: {}", e);
+            return Err("Unable to save file on server");
+        }
+        written = end;
+    }
+
+    Ok(())
+}
+
+/// Drops upload sessions older than `UPLOAD_SESSION_TTL_HOURS` with their
+/// staged chunks, as well as staging directories no session points to
+pub async fn expire_upload_sessions(database: &dyn Repository) {
+    let cutoff = Utc::now() - Duration::hours(UPLOAD_SESSION_TTL_HOURS);
+    let expired = match database.delete_uploads_before(cutoff).await {
+        Ok(e) => e,
+        Err(e) => {
+            error!("{e}");
+            return;
         }
     };
+    for session in &expired {
+        if let Some(staging_dir) = get_staging_dir(&session.session_id).await {
+            let _ = tokio::fs::remove_dir_all(&staging_dir).await;
+        }
+        info!("Upload session expired : {}", session.session_id);
+    }
 
-    Ok(Status::Accepted)
+    // Leftovers of failed cleanups, only touched once they are as old as
+    // an expired session so nothing in use is removed
+    let staging_root = match get_staging_dir("").await {
+        Some(d) => d,
+        None => return,
+    };
+    let mut entries = match tokio::fs::read_dir(&staging_root).await {
+        Ok(e) => e,
+        Err(_e) => return,
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let modified = match entry.metadata().await.and_then(|m| m.modified()) {
+            Ok(m) => DateTime::<Utc>::from(m),
+            Err(_e) => continue,
+        };
+        if modified >= cutoff {
+            continue;
+        }
+        let session_id = entry.file_name().to_string_lossy().to_string();
+        match database.get_upload(&session_id).await {
+            Ok(None) => {
+                if let Err(e) = tokio::fs::remove_dir_all(entry.path()).await {
+                    error!("Failed to remove staging directory: {}", e);
+                }
+            }
+            Ok(Some(_session)) => {}
+            Err(e) => error!("{e}"),
+        }
+    }
 }
 
-// async fn push_folder() {}
+// Runs `expire_upload_sessions` on start and then every hour
+pub fn spawn_upload_cleanup(database: Arc<dyn Repository>) {
+    tauri::async_runtime::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60 * 60));
+        loop {
+            interval.tick().await;
+            expire_upload_sessions(database.as_ref()).await;
+        }
+    });
+}
@@ -1,5 +1,8 @@
+use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::sync::Arc;
 
+use chrono::Utc;
 use rocket::http::{ContentType, Status};
 use rocket::{Data, State};
 use rocket_multipart_form_data::{
@@ -8,16 +11,23 @@ use rocket_multipart_form_data::{
 use serde_json::{from_str, json, to_string, Value};
 
 use surrealdb::sql::{Id, Thing};
+use tauri::Window;
 use tracing::info;
 
 use crate::parse_multipart_form_texts;
-use crate::server::api::utility::{verify_device_id, verify_pin};
+use crate::server::api::utility::{resolve_library, verify_device_id};
+use crate::server::auth::AuthenticatedDevice;
+use crate::server::backup::hold_off_imports;
 use crate::server::db::db_instance::DbInstance;
 use crate::server::db::device_table::Device;
+use crate::server::db::global_table::is_global_table;
+use crate::server::db::grant_table::Access;
 use crate::server::db::hash_table::DeviceHash;
+use crate::server::db::is_reserved_table;
 use crate::server::db::local_table::LocalEntry;
-use crate::server::db::Record;
-use crate::server::utility::TextFieldExt;
+use crate::server::db::tombstone_table::Tombstone;
+use crate::server::pairing::{PairingError, PairingState};
+use crate::server::utility::{gen_file_id, TextFieldExt};
 
 #[get("/connect", data = "<data>")]
 pub async fn connect(
@@ -25,6 +35,8 @@ pub async fn connect(
     data: Data<'_>,
     remote_address: SocketAddr,
     db: &State<DbInstance>,
+    pairing: &State<Arc<PairingState>>,
+    window: &State<Window>,
 ) -> Result<String, Status> {
     info!("Remote Address: {}", remote_address);
     // Process multipart form data    let device_id:String;
@@ -36,6 +48,7 @@ pub async fn connect(
         MultipartFormDataField::text("Location"),
         MultipartFormDataField::text("PIN"),
         MultipartFormDataField::text("ReadOnly"),
+        MultipartFormDataField::text("PairingCode"),
     ]);
 
     let form_result = MultipartFormData::parse(content_type, data, options).await;
@@ -49,6 +62,7 @@ pub async fn connect(
         os: "OS";
         device_name: "DeviceName";
         pin: "PIN";
+        pairing_code: "PairingCode";
     );
 
     // Extract the data from the form for bools
@@ -61,22 +75,58 @@ pub async fn connect(
         None => false,
     };
 
+    // The id becomes the table name of the device library, it can't name a
+    // server table or another library and is used in schema definitions
+    if is_reserved_table(&device_id) || device_id.is_empty() || device_id.contains('`') {
+        return Err(Status::BadRequest);
+    }
+
     let database = &db.database;
+    // An import only restores into a server without devices
+    let _import = hold_off_imports().await;
 
     // Check for existing setup
-    let device: Option<Device> = database.select(("device", &device_id)).await.unwrap();
+    let device = match database.get_device(&device_id).await {
+        Ok(d) => d,
+        Err(e) => {
+            error!("{e}");
+            return Err(Status::InternalServerError);
+        }
+    };
 
-    let device = match device {
+    if device.is_some() {
         // Return Conflict if there is already a device with the same id
-        Some(_d) => return Err(Status::Conflict),
-        None => Device::new(
-            device_name,
-            is_global,
-            read_only,
-            from_str(&os).unwrap(),
-            remote_address.to_string(),
-        ),
+        return Err(Status::Conflict);
+    }
+
+    // Devices can only register while pairing mode is active on the desktop
+    let require_approval = match pairing.redeem(&pairing_code).await {
+        Ok(r) => r,
+        Err(PairingError::InvalidCode) => return Err(Status::Unauthorized),
+        Err(_) => return Err(Status::Forbidden),
     };
+    if require_approval {
+        let result = pairing
+            .request_approval(
+                window,
+                device_id.clone(),
+                device_name.clone(),
+                os.clone(),
+                remote_address.to_string(),
+            )
+            .await;
+        if result.is_err() {
+            return Err(Status::Forbidden);
+        }
+    }
+
+    let device = Device::new(
+        device_name,
+        is_global,
+        read_only,
+        from_str(&os).unwrap(),
+        remote_address.to_string(),
+    );
 
     let seralized = to_string(&device).unwrap();
     info!("Creating Device : {:#?}", seralized);
@@ -84,36 +134,44 @@ pub async fn connect(
     // let r: surrealdb::Response = database
     // .query(format!("CREATE device:{device_id} CONTENT {seralized}"))
     // .await.unwrap();
-    let r: Option<Record> = database
-        .create(("device", &device_id))
-        .content(&device)
-        .await
-        .unwrap();
-    info!("Device Created : {:#?}", r);
+    if let Err(e) = database.save_device(&device_id, &device).await {
+        error!("{e}");
+        return Err(Status::InternalServerError);
+    }
+    info!("Device Created : {}", device_id);
 
     // Create hash and store it using the pin
-    let device_hash = DeviceHash::new(
-        device.uuid.clone(),
-        device.name,
-        pin,
-        Thing {
-            tb: "device".to_string(),
-            id: Id::from(&device_id),
-        },
-    );
+    let uuid = device.uuid.clone();
+    let device_thing = Thing {
+        tb: "device".to_string(),
+        id: Id::from(&device_id),
+    };
+    let device_hash =
+        tokio::task::spawn_blocking(move || DeviceHash::new(uuid, device.name, pin, device_thing))
+            .await;
+    let device_hash = match device_hash {
+        Ok(h) => h,
+        Err(e) => {
+            error!("{e}");
+            return Err(Status::InternalServerError);
+        }
+    };
     // let r = database
     // .query(format!("CREATE hash:{device_id} Content {}", to_string(&device_hash).unwrap()))
     // .await.unwrap();
-    let _r: Option<Record> = database
-        .create(("hash", &device_id))
-        .content(&device_hash)
-        .await
-        .unwrap();
+    if let Err(e) = database.save_hash(&device_id, &device_hash).await {
+        error!("{e}");
+        return Err(Status::InternalServerError);
+    }
+    if let Err(e) = database.prepare_library(&device_id).await {
+        error!("Failed to define library table : {e}");
+    }
     Ok(device.uuid)
 }
 
 #[get("/database", data = "<data>")]
 pub async fn sync_database(
+    auth: AuthenticatedDevice,
     content_type: &ContentType,
     data: Data<'_>,
     remote_address: SocketAddr,
@@ -122,10 +180,9 @@ pub async fn sync_database(
     info!("Remote Address: {}", remote_address);
     // Process multipart form data
     let options = MultipartFormDataOptions::with_multipart_form_data_fields(vec![
-        MultipartFormDataField::text("DeviceID"),
         MultipartFormDataField::text("DeviceName"),
         MultipartFormDataField::text("Global"),
-        MultipartFormDataField::text("PIN"),
+        MultipartFormDataField::text("Owner"),
     ]);
 
     let form_result = MultipartFormData::parse(content_type, data, options).await;
@@ -133,62 +190,40 @@ pub async fn sync_database(
     parse_multipart_form_texts!(
         multipart_form: form_result,
         parse_error: "Error Parsing the request";
-        device_id: "DeviceID";
-        _device_name: "DeviceName";
-        pin: "PIN";
     );
 
-    let _is_global = match multipart_form.texts.get("Global") {
+    let is_global = match multipart_form.texts.get("Global") {
         Some(_t) => true,
         None => false,
     };
+    let owner = multipart_form.texts.get("Owner").first_text();
 
     let database = &db.database;
-    // Check Device Entry
-    let result = verify_device_id(
-        database,
-        &device_id,
-        "Error: finding device in database",
-        "Device is not present in database",
-    )
-    .await;
-
-    if let Err(e) = result {
-        return Err(e);
-    }
-
-    // Verify Pin
-    let result = verify_pin(
+    let device_id = auth.device_id;
+    let library = match resolve_library(
         database,
         &device_id,
-        &pin,
-        "Error: finding device hash in database\nCould not verify",
-        "Couldn't find any auth entires for device ID",
-        "Unauthorized",
+        &auth.device,
+        is_global,
+        owner,
+        Access::Read,
+        "Error: finding access grants",
+        "Error: device has no access to this library",
     )
-    .await;
-
-    if let Err(e) = result {
-        return Err(e);
-    }
+    .await
+    {
+        Ok(l) => l,
+        Err(e) => return Err(e),
+    };
 
     // Start sync logic
 
-    let local_entries: Result<Vec<LocalEntry>, surrealdb::Error> =
-        database.select(&device_id).await;
-    let n = match local_entries {
+    let n = match database.list_entries(&library.table).await {
         Ok(entires) => {
-            let local_ids: Vec<Record> = database
-                .select(&device_id)
-                .await
-                .expect("Error retriving ids");
             let e: Vec<LocalEntryWithId> = entires
                 .into_iter()
-                .enumerate()
-                .map(|(index, entry)| LocalEntryWithId {
-                    id: local_ids[index].id.id.to_string(),
-                    entry,
-                })
+                .map(|(id, entry)| LocalEntryWithId { id, entry })
+                .filter(|e| library.allows(&e.entry.relative_path))
                 .collect();
             e
         }
@@ -198,8 +233,21 @@ pub async fn sync_database(
         }
     };
 
+    // Deletions the client has to apply on its side
+    let tombstones: Vec<Tombstone> = match database.list_tombstones(&library.table).await {
+        Ok(t) => t
+            .into_iter()
+            .filter(|t: &Tombstone| library.allows(&t.relative_path))
+            .collect(),
+        Err(e) => {
+            error!("Error retriving tombstones:  {}", e);
+            return Err("Error: retriving tombstones");
+        }
+    };
+
     Ok(json!({
         "local_entries": n,
+        "tombstones": tombstones,
     }))
 }
 
@@ -209,6 +257,359 @@ struct LocalEntryWithId {
     entry: LocalEntry,
 }
 
+/*
+   Manifest based sync, the client posts what it has and the server answers
+   with the decision for every file in a single round trip
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct ManifestItem {
+    relative_path: String,
+    file_name: String,
+    size: u64,
+    mtime: Option<u64>,
+    hash: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct ManifestConflict {
+    client: ManifestItem,
+    server: LocalEntryWithId,
+}
+
+#[derive(Default, serde::Serialize)]
+struct ManifestDiff {
+    to_upload: Vec<ManifestItem>,
+    to_download: Vec<LocalEntryWithId>,
+    unchanged: Vec<ManifestItem>,
+    conflict: Vec<ManifestConflict>,
+}
+
+#[post("/manifest", data = "<data>")]
+pub async fn sync_manifest(
+    auth: AuthenticatedDevice,
+    content_type: &ContentType,
+    data: Data<'_>,
+    db: &State<DbInstance>,
+) -> Result<Value, &'static str> {
+    let options = MultipartFormDataOptions {
+        max_data_bytes: 32 * 1024 * 1024,
+        allowed_fields: vec![
+            MultipartFormDataField::text("DeviceName"),
+            MultipartFormDataField::text("Global"),
+            MultipartFormDataField::text("Owner"),
+            MultipartFormDataField::text("Manifest").size_limit(32 * 1024 * 1024),
+        ],
+        ..MultipartFormDataOptions::default()
+    };
+
+    let form_result = MultipartFormData::parse(content_type, data, options).await;
+
+    parse_multipart_form_texts!(
+        multipart_form: form_result,
+        parse_error: "Error Parsing the request";
+        manifest: "Manifest";
+    );
+
+    let manifest: Vec<ManifestItem> = match from_str(&manifest) {
+        Ok(m) => m,
+        Err(e) => {
+            error!("Error Parsing Manifest : {}", e);
+            return Err("Error: Manifest is not valid");
+        }
+    };
+    let is_global = match multipart_form.texts.get("Global") {
+        Some(_t) => true,
+        None => false,
+    };
+    let owner = multipart_form.texts.get("Owner").first_text();
+
+    let database = &db.database;
+    let device_id = auth.device_id;
+    let library = match resolve_library(
+        database,
+        &device_id,
+        &auth.device,
+        is_global,
+        owner,
+        Access::Read,
+        "Error: finding access grants",
+        "Error: device has no access to this library",
+    )
+    .await
+    {
+        Ok(l) => l,
+        Err(e) => return Err(e),
+    };
+    let local_entries = match database.list_entries(&library.table).await {
+        Ok(e) => e,
+        Err(e) => {
+            error!("Error retriving entires:  {}", e);
+            return Err("Error: retriving entires");
+        }
+    };
+
+    // Shared libraries are only compared inside the granted subtrees
+    let manifest = manifest
+        .into_iter()
+        .filter(|item| library.allows(&item.relative_path))
+        .collect();
+    let local_entries = local_entries
+        .into_iter()
+        .map(|(_id, entry)| entry)
+        .filter(|entry| library.allows(&entry.relative_path))
+        .collect();
+
+    let diff = diff_manifest(manifest, local_entries);
+    Ok(json!(diff))
+}
+
+// Works out what each side has to do, server entries which are not in the
+// manifest have to be downloaded by the client
+fn diff_manifest(manifest: Vec<ManifestItem>, local_entries: Vec<LocalEntry>) -> ManifestDiff {
+    let mut server: HashMap<String, LocalEntry> = local_entries
+        .into_iter()
+        .map(|entry| (gen_file_id(&entry.relative_path, &entry.file_name), entry))
+        .collect();
+
+    let mut diff = ManifestDiff::default();
+    for item in manifest {
+        let file_id = gen_file_id(&item.relative_path, &item.file_name);
+        // A lost entry waits for a client holding the content to push it
+        let entry = match server.remove(&file_id) {
+            Some(e) if !e.lost => e,
+            _ => {
+                diff.to_upload.push(item);
+                continue;
+            }
+        };
+
+        // The server only knows the mtime the pushing client reported, the
+        // blob file's own mtime is when the server stored the content
+        let server_mtime = entry.metadata.client_modified();
+        let same_content = match (&item.hash, &entry.content_hash) {
+            (Some(client), Some(server)) => client.eq_ignore_ascii_case(server),
+            _ => {
+                item.size == entry.file_size
+                    && item.mtime.is_some()
+                    && item.mtime == server_mtime
+            }
+        };
+        if same_content {
+            diff.unchanged.push(item);
+            continue;
+        }
+
+        match (item.mtime, server_mtime) {
+            (Some(client), Some(server)) if client > server => diff.to_upload.push(item),
+            (Some(client), Some(server)) if client < server => {
+                diff.to_download.push(LocalEntryWithId { id: file_id, entry })
+            }
+            _ => diff.conflict.push(ManifestConflict {
+                client: item,
+                server: LocalEntryWithId { id: file_id, entry },
+            }),
+        }
+    }
+
+    diff.to_download.extend(
+        server
+            .into_iter()
+            .filter(|(_id, entry)| !entry.lost)
+            .map(|(id, entry)| LocalEntryWithId { id, entry }),
+    );
+    diff
+}
+
+// Page size for the change feed when the client does not ask for one
+const DEFAULT_CHANGE_LIMIT: u64 = 500;
+const MAX_CHANGE_LIMIT: u64 = 5000;
+
+/*
+   Incremental change feed, returns the changes for the device after `Cursor`
+   ordered by sequence, or after its last acknowledged cursor when `Cursor`
+   is left out. `next_cursor` is what the client acknowledges through
+   `/sync/ack` once it applied the page
+*/
+#[get("/changes", data = "<data>")]
+pub async fn sync_changes(
+    auth: AuthenticatedDevice,
+    content_type: &ContentType,
+    data: Data<'_>,
+    db: &State<DbInstance>,
+) -> Result<Value, &'static str> {
+    let options = MultipartFormDataOptions::with_multipart_form_data_fields(vec![
+        MultipartFormDataField::text("DeviceName"),
+        MultipartFormDataField::text("Cursor"),
+        MultipartFormDataField::text("Limit"),
+        MultipartFormDataField::text("Global"),
+        MultipartFormDataField::text("Owner"),
+    ]);
+
+    let form_result = MultipartFormData::parse(content_type, data, options).await;
+
+    parse_multipart_form_texts!(
+        multipart_form: form_result,
+        parse_error: "Error Parsing the request";
+    );
+
+    let cursor = match optional_number(&multipart_form, "Cursor") {
+        Ok(c) => c,
+        Err(e) => return Err(e),
+    };
+    let limit: u64 = match optional_number(&multipart_form, "Limit") {
+        Ok(l) => l.unwrap_or(DEFAULT_CHANGE_LIMIT).clamp(1, MAX_CHANGE_LIMIT),
+        Err(e) => return Err(e),
+    };
+
+    let is_global = match multipart_form.texts.get("Global") {
+        Some(_t) => true,
+        None => false,
+    };
+    let owner = multipart_form.texts.get("Owner").first_text();
+
+    let database = &db.database;
+    let device_id = auth.device_id;
+    let mut device = auth.device;
+    let library = match resolve_library(
+        database,
+        &device_id,
+        &device,
+        is_global,
+        owner,
+        Access::Read,
+        "Error: finding access grants",
+        "Error: device has no access to this library",
+    )
+    .await
+    {
+        Ok(l) => l,
+        Err(e) => return Err(e),
+    };
+    // Without a cursor the feed continues after the last acknowledged change
+    let cursor = match cursor {
+        Some(c) => c,
+        None => *library_cursor(&mut device, &device_id, &library.table),
+    };
+    // Ask for one extra row to know if there is another page
+    let changes = database
+        .changes_after(&library.table, cursor, limit + 1)
+        .await;
+    let mut changes = match changes {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Error retriving changes:  {}", e);
+            return Err("Error: retriving changes");
+        }
+    };
+
+    let has_more = changes.len() as u64 > limit;
+    changes.truncate(limit as usize);
+    let next_cursor = changes.last().map(|c| c.seq).unwrap_or(cursor);
+    // Filtered after paging so the cursor still moves past hidden changes
+    changes.retain(|c| library.allows(&c.relative_path));
+
+    Ok(json!({
+        "changes": changes,
+        "next_cursor": next_cursor,
+        "has_more": has_more,
+    }))
+}
+
+// The acknowledged cursor of the device for a library table
+fn library_cursor<'a>(device: &'a mut Device, device_id: &str, table: &str) -> &'a mut u64 {
+    if table == device_id {
+        &mut device.sync_cursor
+    } else if is_global_table(table) {
+        &mut device.global_cursor
+    } else {
+        device.shared_cursors.entry(table.to_string()).or_default()
+    }
+}
+
+#[post("/ack", data = "<data>")]
+pub async fn sync_ack(
+    auth: AuthenticatedDevice,
+    content_type: &ContentType,
+    data: Data<'_>,
+    db: &State<DbInstance>,
+) -> Result<Status, Status> {
+    let options = MultipartFormDataOptions::with_multipart_form_data_fields(vec![
+        MultipartFormDataField::text("Cursor"),
+        MultipartFormDataField::text("Global"),
+        MultipartFormDataField::text("Owner"),
+    ]);
+
+    let form_result = MultipartFormData::parse(content_type, data, options).await;
+
+    parse_multipart_form_texts!(
+        multipart_form: form_result,
+        parse_error: Status::BadRequest;
+        cursor: "Cursor";
+    );
+
+    let cursor: u64 = match cursor.parse() {
+        Ok(c) => c,
+        Err(_e) => return Err(Status::BadRequest),
+    };
+
+    let is_global = match multipart_form.texts.get("Global") {
+        Some(_t) => true,
+        None => false,
+    };
+    let owner = multipart_form.texts.get("Owner").first_text();
+
+    let database = &db.database;
+    let device_id = auth.device_id;
+    let mut device = auth.device;
+    let library = match resolve_library(
+        database,
+        &device_id,
+        &device,
+        is_global,
+        owner,
+        Access::Read,
+        Status::InternalServerError,
+        Status::Forbidden,
+    )
+    .await
+    {
+        Ok(l) => l,
+        Err(e) => return Err(e),
+    };
+
+    // A cursor past the last issued sequence would skip future changes
+    let latest = match database.latest_change_seq().await {
+        Ok(l) => l,
+        Err(e) => {
+            error!("{e}");
+            return Err(Status::InternalServerError);
+        }
+    };
+    if cursor > latest {
+        return Err(Status::BadRequest);
+    }
+
+    // Cursors only move forward, every library has its own one
+    let current = library_cursor(&mut device, &device_id, &library.table);
+    if cursor < *current {
+        return Err(Status::Conflict);
+    }
+    *current = cursor;
+    device.last_sync = Utc::now();
+
+    if let Err(e) = database.save_device(&device_id, &device).await {
+        error!("{e}");
+        return Err(Status::InternalServerError);
+    }
+
+    Ok(Status::Ok)
+}
+
+// Parses an optional numeric text field, missing is fine but garbage is not
+fn optional_number(form: &MultipartFormData, key: &str) -> Result<Option<u64>, &'static str> {
+    form.texts.get(key).first_number()
+}
+
 #[get("/server", data = "<data>")]
 pub async fn server_sync(
     db: &State<DbInstance>,
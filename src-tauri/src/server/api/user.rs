@@ -0,0 +1,251 @@
+use std::net::SocketAddr;
+
+use rocket::{
+    http::{ContentType, Status},
+    Data, State,
+};
+use rocket_multipart_form_data::{
+    MultipartFormData, MultipartFormDataField, MultipartFormDataOptions,
+};
+use serde_json::{json, Value};
+use tauri::Window;
+
+use crate::{
+    parse_multipart_form_texts,
+    server::{
+        auth::AuthenticatedDevice,
+        db::{db_instance::DbInstance, global_table::global_table, user_table::User},
+        lockout::{attempt_failed, begin_attempt, record_success, Attempt, Subject},
+        utility::TextFieldExt,
+    },
+};
+
+/*
+   User accounts
+
+   A device creates an account with `/user/register` and becomes its first
+   device, other devices join with the user name and password. Devices of
+   the same user read each other's libraries without grants and share a
+   global library of the user (`global_<user uuid>`) instead of the server
+   wide one. It is stored in the user's own SurrealDB namespace
+   `user_<user uuid>`, see `SurrealRepository`. Registering, joining and
+   leaving switch the global library of the device and the responses say
+   so with `GlobalLibraryChanged`. Failed joins are locked out per user name.
+*/
+
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+// Points the device at `user`, the global library changes with it so its
+// cursor starts over. Returns whether the global library changed
+async fn set_device_user(
+    db: &DbInstance,
+    device_id: &str,
+    user: Option<String>,
+) -> Result<bool, Status> {
+    let mut device = match db.database.get_device(device_id).await {
+        Ok(Some(d)) => d,
+        Ok(None) => return Err(Status::NotFound),
+        Err(e) => {
+            error!("{e}");
+            return Err(Status::InternalServerError);
+        }
+    };
+    let changed = device.user != user;
+    device.user = user;
+    if changed {
+        device.global_cursor = 0;
+    }
+    match db.database.save_device(device_id, &device).await {
+        Ok(()) => Ok(changed),
+        Err(e) => {
+            error!("{e}");
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+#[post("/register", data = "<data>")]
+pub async fn register_user(
+    auth: AuthenticatedDevice,
+    content_type: &ContentType,
+    data: Data<'_>,
+    db: &State<DbInstance>,
+) -> Result<Value, Status> {
+    let options = MultipartFormDataOptions::with_multipart_form_data_fields(vec![
+        MultipartFormDataField::text("UserName"),
+        MultipartFormDataField::text("Password"),
+    ]);
+
+    let form_result = MultipartFormData::parse(content_type, data, options).await;
+    parse_multipart_form_texts!(
+        multipart_form: form_result,
+        parse_error: Status::BadRequest;
+        user_name: "UserName";
+        password: "Password";
+    );
+
+    if user_name.trim().is_empty() || password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(Status::BadRequest);
+    }
+    if auth.device.user.is_some() {
+        return Err(Status::Conflict);
+    }
+
+    let database = &db.database;
+    let name = user_name.clone();
+    let user = match tokio::task::spawn_blocking(move || User::new(name, &password)).await {
+        Ok(u) => u,
+        Err(e) => {
+            error!("{e}");
+            return Err(Status::InternalServerError);
+        }
+    };
+    // Checked and created in one step, two registrations of the same name
+    // can't both succeed
+    match database.create_user(&user).await {
+        Ok(true) => {}
+        Ok(false) => return Err(Status::Conflict),
+        Err(e) => {
+            error!("{e}");
+            return Err(Status::InternalServerError);
+        }
+    }
+    let library = global_table(Some(&user.uuid));
+    if let Err(e) = database.prepare_library(&library).await {
+        error!("Failed to define library table : {e}");
+    }
+
+    let changed = set_device_user(db, &auth.device_id, Some(user.uuid.clone())).await?;
+    info!("User {} registered by {}", user_name, auth.device_id);
+    Ok(json!({
+        "UserID": user.uuid,
+        "UserName": user.name,
+        "GlobalLibraryChanged": changed,
+    }))
+}
+
+#[post("/join", data = "<data>")]
+pub async fn join_user(
+    auth: AuthenticatedDevice,
+    content_type: &ContentType,
+    data: Data<'_>,
+    remote_address: SocketAddr,
+    db: &State<DbInstance>,
+    window: &State<Window>,
+) -> Result<Value, Status> {
+    let options = MultipartFormDataOptions::with_multipart_form_data_fields(vec![
+        MultipartFormDataField::text("UserName"),
+        MultipartFormDataField::text("Password"),
+    ]);
+
+    let form_result = MultipartFormData::parse(content_type, data, options).await;
+    parse_multipart_form_texts!(
+        multipart_form: form_result,
+        parse_error: Status::BadRequest;
+        user_name: "UserName";
+        password: "Password";
+    );
+
+    let database = &db.database;
+    let source = remote_address.ip();
+    let subject = Subject::User(&user_name);
+    let lockout = match begin_attempt(database, subject, &source).await {
+        Ok(Attempt::Allowed(lockout)) => lockout,
+        Ok(Attempt::Locked(_until)) => return Err(Status::TooManyRequests),
+        Err(e) => {
+            error!("{e}");
+            return Err(Status::InternalServerError);
+        }
+    };
+
+    let user = match database.get_user(&user_name).await {
+        Ok(u) => u,
+        Err(e) => {
+            error!("{e}");
+            return Err(Status::InternalServerError);
+        }
+    };
+
+    // Unknown users count as failures too
+    let verified = tokio::task::spawn_blocking(move || match user {
+        Some(user) if user.verify(&password) => Some(user),
+        _ => None,
+    })
+    .await;
+    let user = match verified {
+        Ok(Some(user)) => user,
+        Ok(None) => {
+            return if attempt_failed(window, subject, &source, lockout) {
+                Err(Status::TooManyRequests)
+            } else {
+                Err(Status::Unauthorized)
+            };
+        }
+        Err(e) => {
+            error!("{e}");
+            return Err(Status::InternalServerError);
+        }
+    };
+
+    if let Err(e) = record_success(database, subject, &source).await {
+        error!("Failed to clear failed attempts : {e}");
+    }
+
+    let changed = set_device_user(db, &auth.device_id, Some(user.uuid.clone())).await?;
+    info!("Device {} joined user {}", auth.device_id, user.name);
+    Ok(json!({
+        "UserID": user.uuid,
+        "UserName": user.name,
+        "GlobalLibraryChanged": changed,
+    }))
+}
+
+// The device goes back to the server wide global library
+#[post("/leave")]
+pub async fn leave_user(
+    auth: AuthenticatedDevice,
+    db: &State<DbInstance>,
+) -> Result<Value, Status> {
+    if auth.device.user.is_none() {
+        return Err(Status::NotFound);
+    }
+    let changed = set_device_user(db, &auth.device_id, None).await?;
+    info!("Device {} left its user", auth.device_id);
+    Ok(json!({
+        "GlobalLibraryChanged": changed,
+    }))
+}
+
+// The account of the device with all of its devices
+#[get("/")]
+pub async fn user_info(
+    auth: AuthenticatedDevice,
+    db: &State<DbInstance>,
+) -> Result<Value, Status> {
+    let uuid = match auth.device.user {
+        Some(u) => u,
+        None => return Err(Status::NotFound),
+    };
+
+    let database = &db.database;
+    let user = database.user_by_uuid(&uuid).await;
+    let devices = database.user_devices(&uuid).await;
+    match (user, devices) {
+        (Ok(Some(user)), Ok(devices)) => {
+            let devices: Vec<Value> = devices
+                .into_iter()
+                .map(|(id, d)| json!({ "DeviceID": id, "DeviceName": d.name }))
+                .collect();
+            Ok(json!({
+                "UserID": user.uuid,
+                "UserName": user.name,
+                "Devices": devices,
+            }))
+        }
+        (Ok(None), _) => Err(Status::NotFound),
+        (Err(e), _) | (_, Err(e)) => {
+            error!("{e}");
+            Err(Status::InternalServerError)
+        }
+    }
+}
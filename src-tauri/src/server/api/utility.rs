@@ -1,11 +1,25 @@
+use std::net::IpAddr;
+use std::path::{Component, Path};
+
+use mime::Mime;
 use rocket::log::private::info;
-use rocket_multipart_form_data::FileField;
-use surrealdb::{engine::remote::ws::Client, Surreal};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tauri::Window;
+use tokio::io::AsyncReadExt;
 
 use crate::server::{
-    db::{device_table::Device, hash_table::DeviceHash},
-    utility::gen_sha_256_hash,
+    blob_store::{acquire_blob, release_blob, StoredBlob},
+    db::{
+        change_table::{Change, ChangeKind},
+        device_table::Device,
+        global_table::{conflict_file_name, global_table, is_global_table, resolve_push, GlobalPush},
+        grant_table::Access,
+        hash_table::DeviceHash,
+        local_table::LocalEntry,
+        repository::{Repository, RepositoryError},
+        tombstone_table::Tombstone,
+    },
+    lockout::{attempt_failed, begin_attempt, record_success, Attempt, Subject},
+    utility::gen_file_id,
 };
 
 // Mulitpart form extraction
@@ -47,11 +61,12 @@ macro_rules! parse_multipart_form_texts {
             Err(_e) => return Err($error_return),
         };
 
+        // A missing field is a bad request like an unparsable form
         $(
-            let $field = $multipart.texts
-            .get($elem)
-            .first_text()
-            .expect(format!("{} not found", $elem).as_str());
+            let $field = match $multipart.texts.get($elem).first_text() {
+                Some(text) => text,
+                None => return Err($error_return),
+            };
         )*
     };
     // let multipart_form = match MultipartFormData::parse(content_type, data, options).await {
@@ -89,75 +104,12 @@ pub fn get_file_meta(path: &std::path::PathBuf) -> Option<std::fs::Metadata> {
     None
 }
 
-pub async fn save_file_to_documents(
-    file: &FileField,
-    file_name: &str,
-    relative_path: &str,
-    device_id: &str,
-) -> Option<std::path::PathBuf> {
-    let documents_dir = match get_documents_dir().await {
-        Ok(dir) => dir,
-        Err(e) => {
-            error!("Failed to get documents directory: {}", e);
-            return None;
-        }
-    };
-
-    let device_dir = documents_dir.join("Aperture").join(device_id);
-    let target_dir = device_dir.join(relative_path);
-
-    if let Err(e) = tokio::fs::create_dir_all(&target_dir).await {
-        error!("Failed to create target directory: {}", e);
-        return None;
-    }
-
-    let target_file_path = target_dir.join(file_name);
-    let mut target_file = match tokio::fs::File::create(&target_file_path).await {
-        Ok(file) => file,
-        Err(e) => {
-            error!("Failed to create target file: {}", e);
-            return None;
-        }
-    };
-
-    let file_path = &file.path;
-    let mut reader = match tokio::fs::File::open(file_path).await {
-        Ok(file) => file,
-        Err(e) => {
-            error!("Failed to open source file: {}", e);
-            return None;
-        }
-    };
-    let mut buffer = vec![0; 4096];
-    loop {
-        match reader.read(&mut buffer).await {
-            Ok(bytes_read) => {
-                if bytes_read == 0 {
-                    break;
-                }
-                if let Err(e) = target_file.write_all(&buffer[..bytes_read]).await {
-                    error!("Failed to write to target file: {}", e);
-                    return None;
-                }
-            }
-            Err(e) => {
-                error!("Failed to read from source file: {}", e);
-                return None;
-            }
-        }
-    }
-
-    Some(target_file_path.clone())
-}
-
 pub fn is_image_file(file_path: &std::path::Path) -> bool {
     info!("is_image_file : {:?}", file_path);
-    let file_extension = file_path
-        .extension()
-        .unwrap()
-        .to_str()
-        .unwrap()
-        .to_lowercase();
+    let file_extension = match file_path.extension().and_then(|e| e.to_str()) {
+        Some(ext) => ext.to_lowercase(),
+        None => return false,
+    };
     let image_extensions = vec!["jpg", "jpeg", "png", " "]; // Add more image extensions if needed
     image_extensions.contains(&file_extension.as_str())
 }
@@ -188,18 +140,373 @@ pub async fn get_documents_dir() -> Result<std::path::PathBuf, Box<dyn std::erro
     }
 }
 
+// Staging area for chunked uploads, one directory per upload session
+pub async fn get_staging_dir(session_id: &str) -> Option<std::path::PathBuf> {
+    match get_documents_dir().await {
+        Ok(dir) => Some(dir.join("Aperture").join(".staging").join(session_id)),
+        Err(e) => {
+            error!("Failed to get documents directory: {}", e);
+            None
+        }
+    }
+}
+
 // Handle database checks
 
+// The library a request works on and the subtrees it may touch
+pub struct Library {
+    pub table: String,
+    // `None` for full access, otherwise the granted relative paths
+    pub paths: Option<Vec<String>>,
+}
+
+impl Library {
+    fn full(table: String) -> Library {
+        Library { table, paths: None }
+    }
+
+    // `..` could climb out of a granted subtree, so it is never allowed
+    // below a grant
+    pub fn allows(&self, relative_path: &str) -> bool {
+        let relative_path = Path::new(relative_path.trim_start_matches('/'));
+        let climbs = relative_path
+            .components()
+            .any(|c| c == Component::ParentDir);
+        match &self.paths {
+            None => true,
+            Some(paths) => !climbs && paths.iter().any(|p| relative_path.starts_with(p)),
+        }
+    }
+}
+
+/// Resolves the library of a request: the device's own one, the global
+/// library for devices which are allowed to use it, or the library of
+/// `owner` as far as its grants to the device allow `access`. Devices of
+/// the same user can read each other's libraries without a grant.
+/// For global requests the library of the device's user takes precedence,
+/// the shared `global` table only serves devices without a user account
+pub async fn resolve_library<T>(
+    database: &dyn Repository,
+    device_id: &String,
+    device: &Device,
+    global: bool,
+    owner: Option<String>,
+    access: Access,
+    surreal_error: T,
+    forbidden_error: T,
+) -> Result<Library, T> {
+    if global {
+        return match device.global && owner.is_none() {
+            true => Ok(Library::full(global_table(device.user.as_deref()))),
+            false => Err(forbidden_error),
+        };
+    }
+    let owner = match owner {
+        Some(o) if &o != device_id => o,
+        _ => return Ok(Library::full(device_id.clone())),
+    };
+
+    if access == Access::Read && device.user.is_some() {
+        match database.get_device(&owner).await {
+            Ok(Some(d)) if d.user == device.user => return Ok(Library::full(owner)),
+            Ok(_) => {}
+            Err(e) => {
+                error!("{e}");
+                return Err(surreal_error);
+            }
+        }
+    }
+
+    let grants = match database.grants_between(&owner, device_id).await {
+        Ok(g) => g,
+        Err(e) => {
+            error!("{e}");
+            return Err(surreal_error);
+        }
+    };
+
+    let paths: Vec<String> = grants
+        .into_iter()
+        .filter(|g| g.access.covers(access))
+        .map(|g| g.relative_path)
+        .collect();
+    if paths.is_empty() {
+        return Err(forbidden_error);
+    }
+    let paths = match paths.iter().any(|p| p.is_empty()) {
+        true => None,
+        false => Some(paths),
+    };
+    Ok(Library {
+        table: owner,
+        paths,
+    })
+}
+
+// The device pushing a file, the version of the path it last saw and the
+// mtime of the file on the device in seconds since the epoch
+pub struct PushOrigin<'a> {
+    pub device_id: &'a String,
+    pub device_name: &'a str,
+    pub base_hash: Option<&'a str>,
+    pub modified: Option<u64>,
+}
+
+/// Stores a pushed file in `library`. Pushes to the global library go
+/// through its conflict rules, returns true if the file was kept as a
+/// conflict copy instead of replacing the current version
+pub async fn store_library_entry(
+    database: &dyn Repository,
+    library: &String,
+    origin: &PushOrigin<'_>,
+    blob: &StoredBlob,
+    file_name: &String,
+    mime: Option<Mime>,
+    dir_path: &String,
+    client_path: &String,
+    relative_path: &String,
+) -> Result<bool, &'static str> {
+    let mut stored_name = file_name.clone();
+    if is_global_table(library) {
+        let file_id = gen_file_id(relative_path, file_name);
+        let current = match database.get_entry(library, &file_id).await {
+            Ok(c) => c,
+            Err(e) => {
+                error!("{e}");
+                return Err("Error: finding the global entry");
+            }
+        };
+
+        match resolve_push(
+            current.as_ref(),
+            origin.device_id,
+            &blob.hash,
+            origin.base_hash,
+        ) {
+            GlobalPush::Unchanged => return Ok(false),
+            GlobalPush::Create | GlobalPush::Replace => {}
+            GlobalPush::ConflictCopy => {
+                stored_name = conflict_file_name(file_name, origin.device_name);
+                warn!(
+                    "Global conflict at {}{}, stored as {}",
+                    relative_path, file_name, stored_name
+                );
+            }
+        }
+    }
+
+    let result = store_local_entry(
+        database,
+        library,
+        origin.device_id,
+        blob,
+        origin.modified,
+        &stored_name,
+        mime,
+        dir_path,
+        client_path,
+        relative_path,
+    )
+    .await;
+    match result {
+        Ok(_entry) => Ok(&stored_name != file_name),
+        Err(e) => Err(e),
+    }
+}
+
+/// Creates or updates the `LocalEntry` for a file which is already saved
+/// under the Aperture directory, keyed by `(library, gen_file_id(..))`.
+/// `library` is the device id or `global`, `device_id` the pushing device
+pub async fn store_local_entry(
+    database: &dyn Repository,
+    library: &String,
+    device_id: &String,
+    blob: &StoredBlob,
+    client_modified: Option<u64>,
+    file_name: &String,
+    mime: Option<Mime>,
+    dir_path: &String,
+    client_path: &String,
+    relative_path: &String,
+) -> Result<LocalEntry, &'static str> {
+    let file_path = &blob.location;
+    let check_image = is_image_file(std::path::Path::new(file_name));
+
+    // Check for local Entry
+    let file_id = gen_file_id(relative_path, file_name);
+    let local = match database.get_entry(library, &file_id).await {
+        Ok(d) => d,
+        Err(e) => {
+            error!("{e}");
+            return Err("Error: finding device hash in database\nCould not verify");
+        }
+    };
+    // Take the reference before the blob is read, so it can not be collected
+    // in between. The previous content is released once nothing points at it
+    let previous_hash = local.as_ref().and_then(|l| l.content_hash.clone());
+    let acquired = previous_hash.as_deref() != Some(blob.hash.as_str());
+    if acquired {
+        if let Err(e) = acquire_blob(database, blob).await {
+            error!("{e}");
+            return Err("Error: could not store the local entry");
+        }
+    }
+
+    let blurhash = match check_image {
+        true => generate_blurhash(file_path).await,
+        false => None,
+    };
+    let new_local_entry = LocalEntry::new(
+        device_id.clone(),
+        file_name.clone(),
+        blob.size,
+        String::from(file_path.to_str().unwrap()),
+        Some(blob.hash.clone()),
+        mime,
+        blurhash,
+        dir_path.clone(),
+        client_path.clone(),
+        relative_path.clone(),
+    );
+    let mut new_local_entry = match new_local_entry {
+        Ok(entry) => entry,
+        Err(e) => {
+            error!("Failed to read metadata of {:?}: {e}", file_path);
+            give_back_blob(database, blob, acquired).await;
+            return Err("Error: could not store the local entry");
+        }
+    };
+    new_local_entry.metadata.set_client_modified(client_modified);
+
+    let kind = match local {
+        Some(_) => ChangeKind::Update,
+        None => ChangeKind::Create,
+    };
+    if let Err(e) = database
+        .save_entry(library, &file_id, &new_local_entry)
+        .await
+    {
+        error!("{e}");
+        give_back_blob(database, blob, acquired).await;
+        return Err("Error: could not store the local entry");
+    }
+    if let (true, Some(previous_hash)) = (acquired, previous_hash) {
+        if let Err(e) = release_blob(database, &previous_hash).await {
+            error!("{e}");
+        }
+    }
+
+    // The path is alive again, so it is no longer a deletion
+    clear_tombstone(database, library, &file_id).await;
+
+    if let Err(e) = record_change(database, library, &file_id, kind, relative_path, file_name).await
+    {
+        error!("Failed to record change : {e}");
+        return Err("Error: recording the change");
+    }
+    Ok(new_local_entry)
+}
+
+// Releases the reference `store_local_entry` took for an entry it could
+// not store after all
+async fn give_back_blob(database: &dyn Repository, blob: &StoredBlob, acquired: bool) {
+    if !acquired {
+        return;
+    }
+    if let Err(e) = release_blob(database, &blob.hash).await {
+        error!("{e}");
+    }
+}
+
+/// Removes the `LocalEntry` with its file and leaves a tombstone behind,
+/// files stored before the blob store are removed directly
+pub async fn remove_local_entry(
+    database: &dyn Repository,
+    library: &Library,
+    file_id: &String,
+) -> Result<Tombstone, &'static str> {
+    let device_id = &library.table;
+    match database.get_entry(device_id, file_id).await {
+        Ok(Some(l)) if !library.allows(&l.relative_path) => {
+            return Err("Error: device has no access to this path")
+        }
+        Ok(Some(_l)) => {}
+        Ok(None) => return Err("Could not find the file"),
+        Err(e) => {
+            error!("{e}");
+            return Err("Error: removing the entry from database");
+        }
+    }
+    let local = match database.delete_entry(device_id, file_id).await {
+        Ok(Some(l)) => l,
+        Ok(None) => return Err("Could not find the file"),
+        Err(e) => {
+            error!("{e}");
+            return Err("Error: removing the entry from database");
+        }
+    };
+
+    match &local.content_hash {
+        Some(hash) => {
+            if let Err(e) = release_blob(database, hash).await {
+                error!("{e}");
+            }
+        }
+        None if local.lost => {}
+        None => {
+            if let Err(e) = tokio::fs::remove_file(&local.file_location).await {
+                error!("Failed to remove file {}: {}", local.file_location, e);
+            }
+        }
+    }
+
+    let tombstone = store_tombstone(database, device_id, file_id, &local).await;
+
+    if let Err(e) = record_change(
+        database,
+        device_id,
+        file_id,
+        ChangeKind::Delete,
+        &local.relative_path,
+        &local.file_name,
+    )
+    .await
+    {
+        error!("Failed to record change : {e}");
+        return Err("Error: recording the change");
+    }
+    Ok(tombstone)
+}
+
+/// Appends an entry to the change log with the next sequence number
+pub async fn record_change(
+    database: &dyn Repository,
+    device_id: &str,
+    file_id: &str,
+    kind: ChangeKind,
+    relative_path: &str,
+    file_name: &str,
+) -> Result<Change, RepositoryError> {
+    // Numbered by `append_change`
+    let mut change = Change::new(
+        0,
+        device_id.to_string(),
+        file_id.to_string(),
+        kind,
+        relative_path.to_string(),
+        file_name.to_string(),
+    );
+    database.append_change(&mut change).await?;
+    Ok(change)
+}
+
 pub async fn verify_device_id<T>(
-    database: &Surreal<Client>,
+    database: &dyn Repository,
     device_id: &String,
     surreal_error: T,
     not_found_error: T,
 ) -> Result<Device, T> {
-    let result: Result<Option<Device>, surrealdb::Error> =
-        database.select(("device", device_id)).await;
-
-    let result = match result {
+    let result = match database.get_device(device_id).await {
         Err(_e) => return Err(surreal_error),
         Ok(d) => d,
     };
@@ -211,17 +518,30 @@ pub async fn verify_device_id<T>(
     return Ok(device);
 }
 
+/// Verifies the PIN of a device with brute force protection, while the
+/// device or the source address is locked out `locked_error` is returned
+/// without looking at the PIN. Unknown devices count as a wrong PIN so
+/// device ids cannot be probed
 pub async fn verify_pin<T>(
-    database: &Surreal<Client>,
+    database: &dyn Repository,
+    window: &Window,
+    source: &IpAddr,
     device_id: &String,
     pin: &String,
     surreal_error: T,
-    not_found_error: T,
     incorrect_pin_error: T,
+    locked_error: T,
 ) -> Result<DeviceHash, T> {
-    let hash: Result<Option<DeviceHash>, surrealdb::Error> =
-        database.select(("hash", device_id)).await;
-    let hash = match hash {
+    let lockout = match begin_attempt(database, Subject::Device(device_id), source).await {
+        Ok(Attempt::Allowed(lockout)) => lockout,
+        Ok(Attempt::Locked(_until)) => return Err(locked_error),
+        Err(e) => {
+            error!("{e}");
+            return Err(surreal_error);
+        }
+    };
+
+    let hash = match database.get_hash(device_id).await {
         Ok(d) => d,
         Err(e) => {
             error!("{e}");
@@ -229,12 +549,143 @@ pub async fn verify_pin<T>(
         }
     };
 
-    let device_hash = match hash {
-        Some(d) => d,
-        None => return Err(not_found_error),
+    let pin = pin.clone();
+    let verified = tokio::task::spawn_blocking(move || {
+        let mut device_hash = hash?;
+        if !device_hash.verify(&pin) {
+            return None;
+        }
+        let migrated = device_hash.needs_rehash();
+        if migrated {
+            device_hash.rehash(&pin);
+        }
+        Some((device_hash, migrated))
+    })
+    .await;
+
+    let (device_hash, migrated) = match verified {
+        Ok(Some(v)) => v,
+        Ok(None) => {
+            return if attempt_failed(window, Subject::Device(device_id), source, lockout) {
+                Err(locked_error)
+            } else {
+                Err(incorrect_pin_error)
+            };
+        }
+        Err(e) => {
+            error!("{e}");
+            return Err(surreal_error);
+        }
     };
-    if device_hash.hash != gen_sha_256_hash(pin) {
-        return Err(incorrect_pin_error);
+
+    if let Err(e) = record_success(database, Subject::Device(device_id), source).await {
+        error!("Failed to clear failed attempts : {e}");
+    }
+
+    // Legacy sha256 hashes are replaced on the first successful login
+    if migrated {
+        match database.save_hash(device_id, &device_hash).await {
+            Ok(_) => info!("Migrated PIN hash to Argon2id for {}", device_id),
+            Err(e) => error!("Failed to migrate PIN hash : {e}"),
+        }
     }
     return Ok(device_hash);
 }
+
+// Marks the path of `local` as deleted for syncing clients
+pub async fn store_tombstone(
+    database: &dyn Repository,
+    device_id: &String,
+    file_id: &String,
+    local: &LocalEntry,
+) -> Tombstone {
+    let tombstone = Tombstone::new(
+        device_id.clone(),
+        file_id.clone(),
+        local.relative_path.clone(),
+        local.file_name.clone(),
+        local.content_hash.clone(),
+    );
+    if let Err(e) = database.save_tombstone(&tombstone).await {
+        error!("Failed to store tombstone : {e}");
+    }
+    tombstone
+}
+
+pub async fn clear_tombstone(database: &dyn Repository, device_id: &str, file_id: &str) {
+    if let Err(e) = database.delete_tombstone(device_id, file_id).await {
+        error!("Failed to remove tombstone : {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::db::{global_table::GLOBAL_TABLE, memory_repository::MemoryRepository, OS};
+
+    fn device(global: bool, user: Option<&str>) -> Device {
+        let mut device = Device::new(
+            "Phone".to_string(),
+            global,
+            false,
+            OS::Windows("11".to_string()),
+            "127.0.0.1".to_string(),
+        );
+        device.user = user.map(str::to_string);
+        device
+    }
+
+    async fn resolve_global(
+        device: &Device,
+        owner: Option<String>,
+    ) -> Result<Library, &'static str> {
+        let database = MemoryRepository::default();
+        resolve_library(
+            &database,
+            &device.uuid,
+            device,
+            true,
+            owner,
+            Access::ReadWrite,
+            "surreal",
+            "forbidden",
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn devices_without_a_user_share_the_global_library() {
+        let library = resolve_global(&device(true, None), None).await.unwrap();
+        assert_eq!(library.table, GLOBAL_TABLE);
+        assert!(library.paths.is_none());
+    }
+
+    #[tokio::test]
+    async fn the_global_library_of_the_user_takes_precedence() {
+        let user = "6f1c2a4e-0b8d-4c57-9a3e-2d7f5b1e8c90";
+        let library = resolve_global(&device(true, Some(user)), None)
+            .await
+            .unwrap();
+        assert_eq!(library.table, "global_6f1c2a4e0b8d4c579a3e2d7f5b1e8c90");
+        assert_ne!(library.table, GLOBAL_TABLE);
+
+        // Every device of the user ends up in the same library
+        let other = resolve_global(&device(true, Some(user)), None)
+            .await
+            .unwrap();
+        assert_eq!(other.table, library.table);
+    }
+
+    #[tokio::test]
+    async fn global_requests_need_a_global_device() {
+        let result = resolve_global(&device(false, None), None).await;
+        assert_eq!(result.err(), Some("forbidden"));
+
+        let user = "6f1c2a4e-0b8d-4c57-9a3e-2d7f5b1e8c90";
+        let result = resolve_global(&device(false, Some(user)), None).await;
+        assert_eq!(result.err(), Some("forbidden"));
+
+        let result = resolve_global(&device(true, None), Some("phone".to_string())).await;
+        assert_eq!(result.err(), Some("forbidden"));
+    }
+}
@@ -0,0 +1,309 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{Duration, Utc};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use rocket::{
+    fairing::{self, Fairing, Info, Kind},
+    http::Status,
+    request::{FromRequest, Outcome, Request},
+    Build, Rocket,
+};
+use sha2::Sha256;
+
+use super::{
+    db::{
+        db_instance::DbInstance,
+        device_table::Device,
+        repository::{Repository, RepositoryError},
+        session_table::{AuthConfig, Session},
+    },
+    utility::gen_sha_256_hash,
+};
+
+/*
+   Token based authentication
+
+   `/auth/login` trades the device id and PIN for a short lived access token
+   and a refresh token. Access tokens are `base64(claims).base64(hmac)` signed
+   with the secret in `config:auth`, refresh tokens are `<session_id>.<secret>`
+   and only their hash is stored in the `session` table.
+*/
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const ACCESS_TOKEN_MINUTES: i64 = 15;
+pub const REFRESH_TOKEN_DAYS: i64 = 30;
+
+pub struct TokenKeys {
+    secret: Vec<u8>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Claims {
+    sub: String,
+    sid: String,
+    exp: i64,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct TokenPair {
+    #[serde(rename = "AccessToken")]
+    pub access_token: String,
+    #[serde(rename = "RefreshToken")]
+    pub refresh_token: String,
+    #[serde(rename = "ExpiresIn")]
+    pub expires_in: i64,
+}
+
+fn random_secret() -> Vec<u8> {
+    let mut secret = vec![0u8; 32];
+    rand::thread_rng().fill_bytes(&mut secret);
+    secret
+}
+
+impl TokenKeys {
+    fn sign(&self, payload: &[u8]) -> Vec<u8> {
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("HMAC takes any key size");
+        mac.update(payload);
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    pub fn issue_access_token(&self, session: &Session) -> String {
+        let claims = Claims {
+            sub: session.device_id.clone(),
+            sid: session.session_id.clone(),
+            exp: (Utc::now() + Duration::minutes(ACCESS_TOKEN_MINUTES)).timestamp(),
+        };
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).unwrap());
+        let signature = URL_SAFE_NO_PAD.encode(self.sign(payload.as_bytes()));
+        format!("{payload}.{signature}")
+    }
+
+    // Returns `(device_id, session_id)` for a valid, unexpired token
+    fn verify_access_token(&self, token: &str) -> Option<(String, String)> {
+        let (payload, signature) = token.split_once('.')?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+
+        let mut mac = HmacSha256::new_from_slice(&self.secret).ok()?;
+        mac.update(payload.as_bytes());
+        mac.verify_slice(&signature).ok()?;
+
+        let claims: Claims = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()?;
+        if claims.exp < Utc::now().timestamp() {
+            return None;
+        }
+        Some((claims.sub, claims.sid))
+    }
+}
+
+/// Creates a session for the device and returns its first token pair
+pub async fn create_session(
+    database: &dyn Repository,
+    keys: &TokenKeys,
+    device_id: &str,
+) -> Result<TokenPair, RepositoryError> {
+    let refresh_secret = URL_SAFE_NO_PAD.encode(random_secret());
+    let session = Session::new(
+        device_id.to_string(),
+        gen_sha_256_hash(&refresh_secret),
+        Duration::days(REFRESH_TOKEN_DAYS),
+    );
+    database.save_session(&session).await?;
+
+    Ok(TokenPair {
+        access_token: keys.issue_access_token(&session),
+        refresh_token: format!("{}.{}", session.session_id, refresh_secret),
+        expires_in: ACCESS_TOKEN_MINUTES * 60,
+    })
+}
+
+/// Rotates the refresh token of a session, the old one stops working.
+/// `None` when the token is unknown, expired or does not match
+pub async fn refresh_session(
+    database: &dyn Repository,
+    keys: &TokenKeys,
+    refresh_token: &str,
+) -> Result<Option<TokenPair>, RepositoryError> {
+    let (session_id, secret) = match refresh_token.split_once('.') {
+        Some(parts) => parts,
+        None => return Ok(None),
+    };
+
+    let mut session = match database.get_session(session_id).await? {
+        Some(s) => s,
+        None => return Ok(None),
+    };
+    if session.refresh_hash != gen_sha_256_hash(&secret.to_string()) {
+        return Ok(None);
+    }
+    if session.refresh_expiry < Utc::now() {
+        database.delete_session(session_id).await?;
+        return Ok(None);
+    }
+
+    let refresh_secret = URL_SAFE_NO_PAD.encode(random_secret());
+    session.refresh_hash = gen_sha_256_hash(&refresh_secret);
+    session.refresh_expiry = Utc::now() + Duration::days(REFRESH_TOKEN_DAYS);
+    database.save_session(&session).await?;
+
+    Ok(Some(TokenPair {
+        access_token: keys.issue_access_token(&session),
+        refresh_token: format!("{}.{}", session.session_id, refresh_secret),
+        expires_in: ACCESS_TOKEN_MINUTES * 60,
+    }))
+}
+
+/*  Request guard for `Authorization: Bearer <access token>`, resolves the
+   device the token was issued for. Fails with 401 when the token is
+   missing, invalid, expired or its session was removed
+*/
+pub struct AuthenticatedDevice {
+    pub device_id: String,
+    pub session_id: String,
+    pub device: Device,
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    Missing,
+    Invalid,
+    Database,
+    ReadOnly,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AuthenticatedDevice {
+    type Error = AuthError;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let token = match req
+            .headers()
+            .get_one("Authorization")
+            .and_then(|h| h.strip_prefix("Bearer "))
+        {
+            Some(t) => t.trim(),
+            None => return Outcome::Failure((Status::Unauthorized, AuthError::Missing)),
+        };
+
+        let (keys, db) = match (
+            req.rocket().state::<TokenKeys>(),
+            req.rocket().state::<DbInstance>(),
+        ) {
+            (Some(k), Some(d)) => (k, d),
+            _ => return Outcome::Failure((Status::InternalServerError, AuthError::Database)),
+        };
+
+        let (device_id, session_id) = match keys.verify_access_token(token) {
+            Some(c) => c,
+            None => return Outcome::Failure((Status::Unauthorized, AuthError::Invalid)),
+        };
+
+        let database = &db.database;
+        match database.get_session(&session_id).await {
+            Ok(Some(s)) if s.device_id == device_id => {}
+            Ok(_) => return Outcome::Failure((Status::Unauthorized, AuthError::Invalid)),
+            Err(e) => {
+                error!("{e}");
+                return Outcome::Failure((Status::InternalServerError, AuthError::Database));
+            }
+        }
+
+        match database.get_device(&device_id).await {
+            Ok(Some(device)) => Outcome::Success(AuthenticatedDevice {
+                device_id,
+                session_id,
+                device,
+            }),
+            Ok(None) => Outcome::Failure((Status::Unauthorized, AuthError::Invalid)),
+            Err(e) => {
+                error!("{e}");
+                Outcome::Failure((Status::InternalServerError, AuthError::Database))
+            }
+        }
+    }
+}
+
+/*  Same as `AuthenticatedDevice` but fails with 403 for read-only devices,
+   used by every handler which changes stored files
+*/
+pub struct WritableDevice {
+    pub device_id: String,
+    pub session_id: String,
+    pub device: Device,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for WritableDevice {
+    type Error = AuthError;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let auth = match req.guard::<AuthenticatedDevice>().await {
+            Outcome::Success(a) => a,
+            Outcome::Failure(f) => return Outcome::Failure(f),
+            Outcome::Forward(f) => return Outcome::Forward(f),
+        };
+
+        if auth.device.read_only {
+            req.local_cache(|| Some(AuthError::ReadOnly));
+            return Outcome::Failure((Status::Forbidden, AuthError::ReadOnly));
+        }
+
+        Outcome::Success(WritableDevice {
+            device_id: auth.device_id,
+            session_id: auth.session_id,
+            device: auth.device,
+        })
+    }
+}
+
+// Loads the token signing secret, creating it on first start. Has to be
+// attached after `DbMiddleware`
+pub struct AuthMiddleware;
+
+#[rocket::async_trait]
+impl Fairing for AuthMiddleware {
+    fn info(&self) -> Info {
+        Info {
+            name: "Auth Middleware",
+            kind: Kind::Ignite,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> fairing::Result {
+        let db = match rocket.state::<DbInstance>() {
+            Some(db) => db,
+            None => {
+                error!("Auth Middleware needs the Database Middleware attached first");
+                return Err(rocket);
+            }
+        };
+        let database = &db.database;
+
+        let secret = match database.get_auth_config().await {
+            Ok(Some(c)) => URL_SAFE_NO_PAD.decode(c.token_secret).ok(),
+            Ok(None) => None,
+            Err(e) => {
+                error!("{e}");
+                return Err(rocket);
+            }
+        };
+
+        let secret = match secret {
+            Some(s) => s,
+            None => {
+                let secret = random_secret();
+                let config = AuthConfig {
+                    token_secret: URL_SAFE_NO_PAD.encode(&secret),
+                };
+                if let Err(e) = database.save_auth_config(&config).await {
+                    error!("{e}");
+                    return Err(rocket);
+                }
+                info!("Generated new token signing secret");
+                secret
+            }
+        };
+
+        Ok(rocket.manage(TokenKeys { secret }))
+    }
+}
@@ -0,0 +1,926 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    io::{Read, Write},
+    path::{Component, Path, PathBuf},
+    sync::OnceLock,
+};
+
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use tokio::sync::{RwLock, RwLockReadGuard};
+use uuid::Uuid;
+
+use super::{
+    api::utility::{get_documents_dir, get_staging_dir},
+    db::{
+        blob_table::Blob,
+        device_table::Device,
+        grant_table::Grant,
+        hash_table::DeviceHash,
+        local_table::LocalEntry,
+        migrations::{latest_version, MIGRATIONS},
+        repository::{all_libraries, Repository, RepositoryError},
+        user_table::User,
+    },
+    fsck::hold_off_repairs,
+};
+
+/*
+   Backup and restore of the whole server
+
+   The export is a single tar archive:
+
+   - `manifest.json`  format, schema version, the storage root the files
+                      were exported from and the sha256 / size of every
+                      other member of the archive
+   - `records.json`   devices, PIN hashes, users, grants, blob records and
+                      every library table
+   - `files/...`      every stored file, relative to `Documents/Aperture`
+
+   Sessions, change logs and tombstones are not exported. Devices sign in
+   again after a restore and their sync cursors start over, so they pull
+   a full manifest once. A stored file outside of the storage root fails
+   the export. An import only goes into a server without any devices, it
+   verifies every checksum before touching the database, re-points
+   `file_location` to the storage root of this machine and runs the
+   migrations newer than the backup on the restored records. A failed
+   import removes what it restored so far and can simply be retried.
+   Pairing waits for a running import, so the server stays empty until
+   the import is done.
+*/
+
+const FORMAT_VERSION: u32 = 1;
+const MANIFEST_NAME: &str = "manifest.json";
+const RECORDS_NAME: &str = "records.json";
+const FILES_DIR: &str = "files";
+
+static IMPORT_LOCK: OnceLock<RwLock<()>> = OnceLock::new();
+
+fn import_lock() -> &'static RwLock<()> {
+    IMPORT_LOCK.get_or_init(|| RwLock::new(()))
+}
+
+/// Held by pairing while it adds a device, an import holds off pairing
+pub async fn hold_off_imports() -> RwLockReadGuard<'static, ()> {
+    import_lock().read().await
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct ManifestFile {
+    #[serde(rename = "Path")]
+    pub path: String,
+    #[serde(rename = "Sha256")]
+    pub sha256: String,
+    #[serde(rename = "Size")]
+    pub size: u64,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct Manifest {
+    #[serde(rename = "FormatVersion")]
+    pub format_version: u32,
+    #[serde(rename = "SchemaVersion")]
+    pub schema_version: u32,
+    #[serde(rename = "Created")]
+    pub created: DateTime<Utc>,
+    #[serde(rename = "StorageRoot")]
+    pub storage_root: String,
+    #[serde(rename = "Files")]
+    pub files: Vec<ManifestFile>,
+}
+
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+struct Records {
+    devices: Vec<(String, Device)>,
+    hashes: Vec<(String, DeviceHash)>,
+    users: Vec<User>,
+    grants: Vec<Grant>,
+    blobs: Vec<Blob>,
+    libraries: BTreeMap<String, Vec<(String, LocalEntry)>>,
+}
+
+// What an import put in place so far, removed again when it fails. Records
+// which were there before the import are not listed
+#[derive(Default)]
+struct Restored {
+    files: Vec<PathBuf>,
+    users: Vec<String>,
+    devices: Vec<String>,
+    blobs: Vec<String>,
+    // Tables which were empty before, dropped as a whole
+    libraries: Vec<String>,
+    entries: Vec<(String, String)>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct BackupSummary {
+    #[serde(rename = "Path")]
+    pub path: String,
+    #[serde(rename = "Devices")]
+    pub devices: usize,
+    #[serde(rename = "Entries")]
+    pub entries: usize,
+    #[serde(rename = "Files")]
+    pub files: usize,
+    #[serde(rename = "Bytes")]
+    pub bytes: u64,
+}
+
+// Hashes everything read through it, used while appending to the archive
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let bytes_read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..bytes_read]);
+        Ok(bytes_read)
+    }
+}
+
+// Hashes everything written through it, used while unpacking the archive
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let bytes_written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..bytes_written]);
+        self.size += bytes_written as u64;
+        Ok(bytes_written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn invalid_data(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+async fn get_storage_root() -> Result<PathBuf, &'static str> {
+    match get_documents_dir().await {
+        Ok(dir) => Ok(dir.join("Aperture")),
+        Err(e) => {
+            error!("Failed to get documents directory: {}", e);
+            Err("Error: could not find the storage directory")
+        }
+    }
+}
+
+// Archive member names always use `/`, whatever the exporting platform was
+fn archive_name(relative: &Path) -> Option<String> {
+    let mut parts = vec![FILES_DIR.to_string()];
+    for component in relative.components() {
+        match component {
+            Component::Normal(p) => parts.push(p.to_str()?.to_string()),
+            _ => return None,
+        }
+    }
+    Some(parts.join("/"))
+}
+
+/// Moves a stored path from the exporting storage root to `new_root`. The
+/// old root may come from another platform, so both separators are accepted
+pub fn repoint_location(location: &str, old_root: &str, new_root: &Path) -> String {
+    let old_root = old_root.trim_end_matches(['/', '\\']);
+    let relative = match location.strip_prefix(old_root) {
+        Some(r) if r.starts_with(['/', '\\']) => r,
+        _ => return location.to_string(),
+    };
+    let mut path = new_root.to_path_buf();
+    for part in relative.split(['/', '\\']).filter(|p| !p.is_empty()) {
+        path.push(part);
+    }
+    path.to_string_lossy().to_string()
+}
+
+async fn collect_records(database: &dyn Repository) -> Result<Records, &'static str> {
+    let mut records = Records::default();
+    let devices = database.list_devices().await.map_err(|e| {
+        error!("{e}");
+        "Error: could not read the devices"
+    })?;
+
+    for (device_id, device) in &devices {
+        match database.get_hash(device_id).await {
+            Ok(Some(hash)) => records.hashes.push((device_id.clone(), hash)),
+            Ok(None) => {}
+            Err(e) => {
+                error!("{e}");
+                return Err("Error: could not read the device hashes");
+            }
+        }
+        match database.grants_given(device_id).await {
+            Ok(grants) => records.grants.extend(grants),
+            Err(e) => {
+                error!("{e}");
+                return Err("Error: could not read the grants");
+            }
+        }
+        if let Some(uuid) = &device.user {
+            if records.users.iter().any(|u| &u.uuid == uuid) {
+                continue;
+            }
+            match database.user_by_uuid(uuid).await {
+                Ok(Some(user)) => records.users.push(user),
+                Ok(None) => {}
+                Err(e) => {
+                    error!("{e}");
+                    return Err("Error: could not read the users");
+                }
+            }
+        }
+    }
+    records.devices = devices;
+
+    let libraries = all_libraries(database).await.map_err(|e| {
+        error!("{e}");
+        "Error: could not read the libraries"
+    })?;
+    for library in libraries {
+        let entries = database.list_entries(&library).await.map_err(|e| {
+            error!("{e}");
+            "Error: could not read the library entries"
+        })?;
+        for (_file_id, entry) in &entries {
+            let hash = match &entry.content_hash {
+                Some(h) => h,
+                None => continue,
+            };
+            if records.blobs.iter().any(|b| &b.hash == hash) {
+                continue;
+            }
+            match database.get_blob(hash).await {
+                Ok(Some(blob)) => records.blobs.push(blob),
+                Ok(None) => {}
+                Err(e) => {
+                    error!("{e}");
+                    return Err("Error: could not read the blob records");
+                }
+            }
+        }
+        records.libraries.insert(library, entries);
+    }
+    Ok(records)
+}
+
+// Writes the archive, returns the number of files and bytes stored
+fn write_archive(
+    destination: &Path,
+    storage_root: &Path,
+    records: &[u8],
+    locations: BTreeSet<PathBuf>,
+    schema_version: u32,
+) -> std::io::Result<(usize, u64)> {
+    let mut builder = tar::Builder::new(std::fs::File::create(destination)?);
+    let mut files = Vec::with_capacity(locations.len() + 1);
+
+    let mut header = tar::Header::new_gnu();
+    header.set_size(records.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(Utc::now().timestamp() as u64);
+    builder.append_data(&mut header, RECORDS_NAME, records)?;
+    files.push(ManifestFile {
+        path: RECORDS_NAME.to_string(),
+        sha256: format!("{:x}", Sha256::digest(records)),
+        size: records.len() as u64,
+    });
+
+    let mut bytes = 0;
+    for location in locations {
+        let name = match location
+            .strip_prefix(storage_root)
+            .ok()
+            .and_then(archive_name)
+        {
+            Some(n) => n,
+            // Its entry would be restored without the file
+            None => {
+                return Err(invalid_data(format!(
+                    "{:?} is outside of the storage root",
+                    location
+                )))
+            }
+        };
+        // A missing file fails the export, run the consistency check first
+        let file = std::fs::File::open(&location)?;
+        let metadata = file.metadata()?;
+        let mut header = tar::Header::new_gnu();
+        header.set_metadata(&metadata);
+        let mut reader = HashingReader {
+            inner: file,
+            hasher: Sha256::new(),
+        };
+        builder.append_data(&mut header, &name, &mut reader)?;
+        bytes += metadata.len();
+        files.push(ManifestFile {
+            path: name,
+            sha256: format!("{:x}", reader.hasher.finalize()),
+            size: metadata.len(),
+        });
+    }
+
+    let stored = files.len() - 1;
+    let manifest = Manifest {
+        format_version: FORMAT_VERSION,
+        schema_version,
+        created: Utc::now(),
+        storage_root: storage_root.to_string_lossy().to_string(),
+        files,
+    };
+    let manifest = serde_json::to_vec_pretty(&manifest).map_err(std::io::Error::from)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(Utc::now().timestamp() as u64);
+    builder.append_data(&mut header, MANIFEST_NAME, manifest.as_slice())?;
+
+    builder.into_inner()?.sync_all()?;
+    Ok((stored, bytes))
+}
+
+/// Exports every record and stored file into a single archive at `destination`
+pub async fn export_backup(
+    database: &dyn Repository,
+    destination: &Path,
+) -> Result<BackupSummary, &'static str> {
+    let storage_root = get_storage_root().await?;
+    let schema_version = database.schema_version().await.map_err(|e| {
+        error!("{e}");
+        "Error: could not read the schema version"
+    })?;
+    let records = collect_records(database).await?;
+
+    // Blobs are shared between entries, every file goes in once
+    let locations: BTreeSet<PathBuf> = records
+        .libraries
+        .values()
+        .flatten()
+        .filter(|(_file_id, entry)| !entry.lost)
+        .map(|(_file_id, entry)| PathBuf::from(&entry.file_location))
+        .collect();
+
+    let devices = records.devices.len();
+    let entries = records.libraries.values().map(Vec::len).sum();
+    let records = serde_json::to_vec(&records).map_err(|e| {
+        error!("{e}");
+        "Error: could not serialize the records"
+    })?;
+
+    // Written under a temporary name so a failed export never looks complete
+    let partial = destination.with_extension("partial");
+    let archive_path = partial.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        write_archive(
+            &archive_path,
+            &storage_root,
+            &records,
+            locations,
+            schema_version,
+        )
+    })
+    .await;
+    let (files, bytes) = match result {
+        Ok(Ok(r)) => r,
+        Ok(Err(e)) => {
+            error!("Failed to write backup archive: {}", e);
+            let _ = tokio::fs::remove_file(&partial).await;
+            return Err("Error: could not write the backup archive");
+        }
+        Err(e) => {
+            error!("{e}");
+            let _ = tokio::fs::remove_file(&partial).await;
+            return Err("Error: could not write the backup archive");
+        }
+    };
+    if let Err(e) = tokio::fs::rename(&partial, destination).await {
+        error!("Failed to move backup archive in place: {}", e);
+        return Err("Error: could not write the backup archive");
+    }
+
+    info!(
+        "Backup exported to {:?} : {} devices, {} entries, {} files",
+        destination, devices, entries, files
+    );
+    Ok(BackupSummary {
+        path: destination.to_string_lossy().to_string(),
+        devices,
+        entries,
+        files,
+        bytes,
+    })
+}
+
+// Unpacks every member into `staging_dir` and returns the manifest with the
+// sha256 / size of what was actually unpacked
+fn unpack_backup(
+    archive: &Path,
+    staging_dir: &Path,
+) -> std::io::Result<(Manifest, HashMap<String, (String, u64)>)> {
+    let mut archive = tar::Archive::new(std::fs::File::open(archive)?);
+    let mut manifest = None;
+    let mut unpacked = HashMap::new();
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.header().entry_type() != tar::EntryType::Regular {
+            continue;
+        }
+        let path = entry.path()?.into_owned();
+        if !path.components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(invalid_data(format!("unsafe path in archive: {:?}", path)));
+        }
+        let name = path
+            .to_str()
+            .ok_or_else(|| invalid_data(format!("invalid path in archive: {:?}", path)))?
+            .replace('\\', "/");
+
+        if name == MANIFEST_NAME {
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content)?;
+            manifest = Some(serde_json::from_slice::<Manifest>(&content)?);
+            continue;
+        }
+
+        let staged = staging_dir.join(&path);
+        if let Some(parent) = staged.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut writer = HashingWriter {
+            inner: std::fs::File::create(&staged)?,
+            hasher: Sha256::new(),
+            size: 0,
+        };
+        std::io::copy(&mut entry, &mut writer)?;
+        writer.flush()?;
+        unpacked.insert(
+            name,
+            (format!("{:x}", writer.hasher.finalize()), writer.size),
+        );
+    }
+
+    match manifest {
+        Some(m) => Ok((m, unpacked)),
+        None => Err(invalid_data("the archive has no manifest".to_string())),
+    }
+}
+
+// Every member has to be listed with a matching checksum and nothing else
+// may be in the archive
+fn verify_backup(
+    manifest: &Manifest,
+    unpacked: &HashMap<String, (String, u64)>,
+) -> Result<(), &'static str> {
+    if manifest.format_version != FORMAT_VERSION {
+        return Err("Error: unsupported backup format");
+    }
+    if manifest.schema_version > latest_version() {
+        return Err("Error: the backup was made by a newer version of Aperture");
+    }
+    if unpacked.len() != manifest.files.len() {
+        return Err("Error: the backup does not match its manifest");
+    }
+    for file in &manifest.files {
+        match unpacked.get(&file.path) {
+            Some((sha256, size)) if sha256 == &file.sha256 && *size == file.size => {}
+            _ => {
+                error!("Checksum mismatch for {} in backup", file.path);
+                return Err("Error: the backup is corrupted, a checksum does not match");
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn restore_records(
+    database: &dyn Repository,
+    records: Records,
+    old_root: &str,
+    new_root: &Path,
+    restored: &mut Restored,
+) -> Result<(), RepositoryError> {
+    for user in &records.users {
+        if database.get_user(&user.name).await?.is_none() {
+            restored.users.push(user.name.clone());
+        }
+        database.save_user(user).await?;
+    }
+    for (device_id, mut device) in records.devices {
+        // The change log is not part of the backup
+        device.sync_cursor = 0;
+        device.global_cursor = 0;
+        device.shared_cursors.clear();
+        restored.devices.push(device_id.clone());
+        database.save_device(&device_id, &device).await?;
+    }
+    for (device_id, hash) in &records.hashes {
+        database.save_hash(device_id, hash).await?;
+    }
+    for grant in &records.grants {
+        database.save_grant(grant).await?;
+    }
+    for mut blob in records.blobs {
+        blob.location = repoint_location(&blob.location, old_root, new_root);
+        if database.get_blob(&blob.hash).await?.is_none() {
+            restored.blobs.push(blob.hash.clone());
+        }
+        database.save_blob(&blob).await?;
+    }
+    for (library, entries) in records.libraries {
+        let existing = database.list_entries(&library).await?;
+        if existing.is_empty() {
+            restored.libraries.push(library.clone());
+        }
+        database.prepare_library(&library).await?;
+        for (file_id, mut entry) in entries {
+            if !entry.lost {
+                entry.file_location = repoint_location(&entry.file_location, old_root, new_root);
+            }
+            if !existing.is_empty() && !existing.iter().any(|(id, _e)| id == &file_id) {
+                restored.entries.push((library.clone(), file_id.clone()));
+            }
+            database.save_entry(&library, &file_id, &entry).await?;
+        }
+    }
+    Ok(())
+}
+
+// Records of an older schema get the migrations this server already ran
+async fn migrate_records(
+    database: &dyn Repository,
+    schema_version: u32,
+    libraries: &[String],
+) -> Result<(), RepositoryError> {
+    for migration in MIGRATIONS.iter().filter(|m| m.version > schema_version) {
+        info!(
+            "Applying schema migration {} to the backup : {}",
+            migration.version, migration.name
+        );
+        database.apply_migration(migration, libraries).await?;
+    }
+    Ok(())
+}
+
+// Best effort, removes what the failed import added to the server
+async fn roll_back(database: &dyn Repository, restored: Restored) {
+    for library in &restored.libraries {
+        if let Err(e) = database.delete_library(library).await {
+            error!("{e}");
+        }
+    }
+    for (library, file_id) in &restored.entries {
+        if let Err(e) = database.delete_entry(library, file_id).await {
+            error!("{e}");
+        }
+    }
+    for hash in &restored.blobs {
+        if let Err(e) = database.delete_blob(hash).await {
+            error!("{e}");
+        }
+    }
+    for device_id in &restored.devices {
+        let removed = (
+            database.delete_grants(device_id).await,
+            database.delete_hash(device_id).await,
+            database.delete_device(device_id).await,
+        );
+        if let (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) = removed {
+            error!("{e}");
+        }
+    }
+    for name in &restored.users {
+        if let Err(e) = database.delete_user(name).await {
+            error!("{e}");
+        }
+    }
+    for file in &restored.files {
+        if let Err(e) = tokio::fs::remove_file(file).await {
+            error!("Failed to remove restored file {:?}: {}", file, e);
+        }
+    }
+    warn!("Backup import failed, restored records and files were removed");
+}
+
+/// Restores a backup made by `export_backup` into this server, which must
+/// not have any devices yet
+pub async fn import_backup(
+    database: &dyn Repository,
+    source: &Path,
+) -> Result<BackupSummary, &'static str> {
+    // Checked under the lock, no device can pair until the import is done
+    let _pairing = import_lock().write().await;
+    match database.list_devices().await {
+        Ok(devices) if devices.is_empty() => {}
+        Ok(_devices) => return Err("Error: a backup can only be imported into an empty server"),
+        Err(e) => {
+            error!("{e}");
+            return Err("Error: could not read the devices");
+        }
+    }
+    let storage_root = get_storage_root().await?;
+    let staging_dir = get_staging_dir(&format!("restore-{}", Uuid::new_v4()))
+        .await
+        .ok_or("Error: could not find the storage directory")?;
+
+    let result = restore(database, source, &storage_root, &staging_dir).await;
+    if let Err(e) = tokio::fs::remove_dir_all(&staging_dir).await {
+        error!("Failed to remove staging directory: {}", e);
+    }
+    result
+}
+
+async fn restore(
+    database: &dyn Repository,
+    source: &Path,
+    storage_root: &Path,
+    staging_dir: &Path,
+) -> Result<BackupSummary, &'static str> {
+    let archive = source.to_path_buf();
+    let staging = staging_dir.to_path_buf();
+    let (manifest, unpacked) =
+        match tokio::task::spawn_blocking(move || unpack_backup(&archive, &staging)).await {
+            Ok(Ok(r)) => r,
+            Ok(Err(e)) => {
+                error!("Failed to read backup archive: {}", e);
+                return Err("Error: could not read the backup archive");
+            }
+            Err(e) => {
+                error!("{e}");
+                return Err("Error: could not read the backup archive");
+            }
+        };
+    verify_backup(&manifest, &unpacked)?;
+
+    let records = match tokio::fs::read(staging_dir.join(RECORDS_NAME)).await {
+        Ok(r) => r,
+        Err(e) => {
+            error!("Failed to read backup records: {}", e);
+            return Err("Error: could not read the backup records");
+        }
+    };
+    let records: Records = match serde_json::from_slice(&records) {
+        Ok(r) => r,
+        Err(e) => {
+            error!("{e}");
+            return Err("Error: could not read the backup records");
+        }
+    };
+    let devices = records.devices.len();
+    let entries = records.libraries.values().map(Vec::len).sum();
+
+    let _repairs = hold_off_repairs().await;
+    let mut restored = Restored::default();
+    let result = restore_content(
+        database,
+        &manifest,
+        records,
+        storage_root,
+        staging_dir,
+        &mut restored,
+    )
+    .await;
+    let (files, bytes) = match result {
+        Ok(r) => r,
+        Err(e) => {
+            roll_back(database, restored).await;
+            return Err(e);
+        }
+    };
+
+    info!(
+        "Backup imported from {:?} : {} devices, {} entries, {} files",
+        source, devices, entries, files
+    );
+    Ok(BackupSummary {
+        path: source.to_string_lossy().to_string(),
+        devices,
+        entries,
+        files,
+        bytes,
+    })
+}
+
+// Moves the files in place and restores the records, returns the number of
+// files and bytes. Everything put in place is noted in `restored`
+async fn restore_content(
+    database: &dyn Repository,
+    manifest: &Manifest,
+    records: Records,
+    storage_root: &Path,
+    staging_dir: &Path,
+    restored: &mut Restored,
+) -> Result<(usize, u64), &'static str> {
+    // Files first, the records must never point at something missing
+    let mut bytes = 0;
+    let mut files = 0;
+    for file in manifest.files.iter().filter(|f| f.path != RECORDS_NAME) {
+        let relative = match file.path.strip_prefix(&format!("{FILES_DIR}/")) {
+            Some(r) => r,
+            None => continue,
+        };
+        let target = relative
+            .split('/')
+            .fold(storage_root.to_path_buf(), |path, part| path.join(part));
+        if let Some(parent) = target.parent() {
+            if let Err(e) = tokio::fs::create_dir_all(parent).await {
+                error!("Failed to create {:?}: {}", parent, e);
+                return Err("Error: could not restore the stored files");
+            }
+        }
+        // Files already there are left alone by a rollback
+        let existed = target.exists();
+        if let Err(e) = tokio::fs::rename(staging_dir.join(&file.path), &target).await {
+            error!("Failed to restore {:?}: {}", target, e);
+            return Err("Error: could not restore the stored files");
+        }
+        if !existed {
+            restored.files.push(target);
+        }
+        files += 1;
+        bytes += file.size;
+    }
+
+    let libraries: Vec<String> = records.libraries.keys().cloned().collect();
+    let old_root = manifest.storage_root.as_str();
+    let result = restore_records(database, records, old_root, storage_root, restored).await;
+    if let Err(e) = result {
+        error!("{e}");
+        return Err("Error: could not restore the records");
+    }
+    let migrated = migrate_records(database, manifest.schema_version, &libraries).await;
+    if let Err(e) = migrated {
+        error!("{e}");
+        return Err("Error: could not migrate the restored records");
+    }
+    Ok((files, bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::db::{memory_repository::MemoryRepository, OS};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("aperture-{name}-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // A device with one blob backed entry below `root`
+    async fn populated(root: &Path) -> MemoryRepository {
+        let database = MemoryRepository::default();
+        let mut device = Device::new(
+            "Phone".to_string(),
+            true,
+            false,
+            OS::Windows("11".to_string()),
+            "127.0.0.1".to_string(),
+        );
+        device.sync_cursor = 7;
+        database.save_device("phone", &device).await.unwrap();
+        database
+            .save_user(&User::new("alice".to_string(), "correct horse"))
+            .await
+            .unwrap();
+
+        let content = b"holiday photo";
+        let hash = format!("{:x}", Sha256::digest(content));
+        let location = root.join(".blobs").join(&hash[..2]).join(&hash);
+        std::fs::create_dir_all(location.parent().unwrap()).unwrap();
+        std::fs::write(&location, content).unwrap();
+        let location = location.to_string_lossy().to_string();
+
+        let mut blob = Blob::new(hash.clone(), content.len() as u64, location.clone());
+        blob.ref_count = 1;
+        database.save_blob(&blob).await.unwrap();
+        let entry = LocalEntry::new(
+            device.uuid,
+            "photo.jpg".to_string(),
+            content.len() as u64,
+            location,
+            Some(hash),
+            None,
+            None,
+            "DCIM".to_string(),
+            "/sdcard/DCIM".to_string(),
+            "DCIM".to_string(),
+        )
+        .unwrap();
+        database.save_entry("phone", "photo", &entry).await.unwrap();
+        database
+    }
+
+    #[tokio::test]
+    async fn round_trip_restores_records_and_files() {
+        let old_root = temp_dir("export");
+        let new_root = temp_dir("import");
+        let staging = temp_dir("staging");
+        let archive = old_root.with_extension("tar");
+
+        let source = populated(&old_root).await;
+        let records = collect_records(&source).await.unwrap();
+        let locations = records
+            .libraries
+            .values()
+            .flatten()
+            .map(|(_id, entry)| PathBuf::from(&entry.file_location))
+            .collect();
+        let records = serde_json::to_vec(&records).unwrap();
+        let (files, _bytes) =
+            write_archive(&archive, &old_root, &records, locations, latest_version()).unwrap();
+        assert_eq!(files, 1);
+
+        let (manifest, unpacked) = unpack_backup(&archive, &staging).unwrap();
+        verify_backup(&manifest, &unpacked).unwrap();
+
+        let target = MemoryRepository::default();
+        let records: Records =
+            serde_json::from_slice(&std::fs::read(staging.join(RECORDS_NAME)).unwrap()).unwrap();
+        let mut restored = Restored::default();
+        let (files, _bytes) = restore_content(
+            &target,
+            &manifest,
+            records,
+            &new_root,
+            &staging,
+            &mut restored,
+        )
+        .await
+        .unwrap();
+        assert_eq!(files, 1);
+
+        let device = target.get_device("phone").await.unwrap().unwrap();
+        assert_eq!(device.sync_cursor, 0);
+        assert!(target.get_user("alice").await.unwrap().is_some());
+        let entry = target.get_entry("phone", "photo").await.unwrap().unwrap();
+        assert!(entry.file_location.starts_with(new_root.to_str().unwrap()));
+        assert_eq!(
+            std::fs::read(&entry.file_location).unwrap(),
+            b"holiday photo"
+        );
+        let blob = target
+            .get_blob(entry.content_hash.as_ref().unwrap())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(blob.location, entry.file_location);
+        assert_eq!(blob.ref_count, 1);
+
+        for dir in [old_root, new_root, staging] {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+        let _ = std::fs::remove_file(archive);
+    }
+
+    #[tokio::test]
+    async fn failed_import_is_rolled_back() {
+        let old_root = temp_dir("export");
+        let new_root = temp_dir("import");
+        let source = populated(&old_root).await;
+        let records = collect_records(&source).await.unwrap();
+
+        let target = MemoryRepository::default();
+        let mut restored = Restored::default();
+        restore_records(
+            &target,
+            records,
+            old_root.to_str().unwrap(),
+            &new_root,
+            &mut restored,
+        )
+        .await
+        .unwrap();
+        roll_back(&target, restored).await;
+
+        assert!(target.list_devices().await.unwrap().is_empty());
+        assert!(target.get_user("alice").await.unwrap().is_none());
+        assert!(target.list_entries("phone").await.unwrap().is_empty());
+        assert!(target.list_libraries().await.unwrap().is_empty());
+
+        let _ = std::fs::remove_dir_all(old_root);
+        let _ = std::fs::remove_dir_all(new_root);
+    }
+
+    #[test]
+    fn files_outside_the_storage_root_fail_the_export() {
+        let root = temp_dir("root");
+        let outside = temp_dir("outside").join("stray.jpg");
+        std::fs::write(&outside, b"stray").unwrap();
+        let archive = root.with_extension("tar");
+
+        let locations = BTreeSet::from([outside.clone()]);
+        let result = write_archive(&archive, &root, b"{}", locations, latest_version());
+        assert!(result.is_err());
+
+        let _ = std::fs::remove_dir_all(root);
+        let _ = std::fs::remove_dir_all(outside.parent().unwrap());
+        let _ = std::fs::remove_file(archive);
+    }
+}
@@ -0,0 +1,297 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+use uuid::Uuid;
+
+use super::{
+    api::utility::get_documents_dir,
+    db::{
+        blob_table::Blob,
+        repository::{Repository, RepositoryError},
+    },
+};
+
+/*  Content addressed blob store
+
+   Files live in `Documents/Aperture/.blobs/<first 2 hex chars>/<sha256>`.
+   Ingesting only puts bytes in place, the `blob` table keeps the reference
+   counts which `acquire_blob` / `release_blob` change with single atomic
+   updates in the repository.
+*/
+
+// Striped locks, a hash always maps to the same one. Held while a blob file
+// is put in place or removed and its reference count changes, so a release
+// can never delete a file a concurrent push is about to reference
+const BLOB_LOCK_STRIPES: usize = 64;
+static BLOB_LOCKS: OnceLock<Vec<tokio::sync::Mutex<()>>> = OnceLock::new();
+
+async fn lock_blob(hash: &str) -> tokio::sync::MutexGuard<'static, ()> {
+    let locks = BLOB_LOCKS.get_or_init(|| {
+        (0..BLOB_LOCK_STRIPES)
+            .map(|_i| tokio::sync::Mutex::new(()))
+            .collect()
+    });
+    let stripe = hash
+        .get(..2)
+        .and_then(|h| usize::from_str_radix(h, 16).ok())
+        .unwrap_or_default();
+    locks[stripe % BLOB_LOCK_STRIPES].lock().await
+}
+
+#[derive(Debug)]
+pub enum BlobError {
+    Database(RepositoryError),
+    // The blob file is gone, it was released while being referenced again
+    Missing(String),
+}
+
+impl fmt::Display for BlobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlobError::Database(e) => write!(f, "{e}"),
+            BlobError::Missing(hash) => write!(f, "blob {hash} is no longer on disk"),
+        }
+    }
+}
+
+impl std::error::Error for BlobError {}
+
+impl From<RepositoryError> for BlobError {
+    fn from(e: RepositoryError) -> Self {
+        BlobError::Database(e)
+    }
+}
+
+// A blob which is present on disk, not necessarily referenced yet
+#[derive(Debug, Clone)]
+pub struct StoredBlob {
+    pub hash: String,
+    pub size: u64,
+    pub location: PathBuf,
+}
+
+pub async fn get_blob_dir() -> Option<PathBuf> {
+    match get_documents_dir().await {
+        Ok(dir) => Some(dir.join("Aperture").join(".blobs")),
+        Err(e) => {
+            error!("Failed to get documents directory: {}", e);
+            None
+        }
+    }
+}
+
+pub async fn get_blob_path(hash: &str) -> Option<PathBuf> {
+    let blob_dir = get_blob_dir().await?;
+    Some(blob_dir.join(&hash[..2]).join(hash))
+}
+
+// Streams the file through sha256 and returns the hex digest with the size
+pub async fn hash_file(path: &Path) -> std::io::Result<(String, u64)> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut size: u64 = 0;
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let bytes_read = file.read(&mut buffer).await?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..bytes_read]);
+        size += bytes_read as u64;
+    }
+    Ok((format!("{:x}", hasher.finalize()), size))
+}
+
+/// Puts the file at `source` into the blob store. When `move_source` is set
+/// the source is moved (or removed if the blob already exists) instead of copied
+pub async fn ingest_blob(source: &Path, move_source: bool) -> Option<StoredBlob> {
+    let (hash, size) = match hash_file(source).await {
+        Ok(h) => h,
+        Err(e) => {
+            error!("Failed to hash file: {}", e);
+            return None;
+        }
+    };
+    let location = get_blob_path(&hash).await?;
+    let stored = StoredBlob {
+        hash,
+        size,
+        location,
+    };
+
+    if stored.location.exists() {
+        info!("Blob already present, deduplicated : {}", stored.hash);
+        if move_source {
+            let _ = tokio::fs::remove_file(source).await;
+        }
+        return Some(stored);
+    }
+
+    if let Some(parent) = stored.location.parent() {
+        if let Err(e) = tokio::fs::create_dir_all(parent).await {
+            error!("Failed to create blob directory: {}", e);
+            return None;
+        }
+    }
+
+    // Write to a temporary name so a partially copied blob is never visible,
+    // unique per ingest as the same content may come in several times at once
+    let temp_location =
+        stored
+            .location
+            .with_file_name(format!("{}.{}.tmp", stored.hash, Uuid::new_v4()));
+    let moved = move_source && tokio::fs::rename(source, &temp_location).await.is_ok();
+    if !moved {
+        if let Err(e) = tokio::fs::copy(source, &temp_location).await {
+            error!("Failed to copy file into blob store: {}", e);
+            let _ = tokio::fs::remove_file(&temp_location).await;
+            return None;
+        }
+        if move_source {
+            let _ = tokio::fs::remove_file(source).await;
+        }
+    }
+
+    let _lock = lock_blob(&stored.hash).await;
+    if stored.location.exists() {
+        info!("Blob already present, deduplicated : {}", stored.hash);
+        let _ = tokio::fs::remove_file(&temp_location).await;
+        return Some(stored);
+    }
+    if let Err(e) = tokio::fs::rename(&temp_location, &stored.location).await {
+        error!("Failed to move file into blob store: {}", e);
+        let _ = tokio::fs::remove_file(&temp_location).await;
+        return None;
+    }
+    Some(stored)
+}
+
+// Looks up a blob by content hash, only returns it when the record matches
+// the expected size and the file is still present on disk
+pub async fn find_blob(
+    database: &dyn Repository,
+    hash: &str,
+    size: u64,
+) -> Result<Option<StoredBlob>, RepositoryError> {
+    let record = match database.get_blob(hash).await? {
+        Some(r) => r,
+        None => return Ok(None),
+    };
+
+    let location = PathBuf::from(&record.location);
+    if record.size != size || !location.exists() {
+        return Ok(None);
+    }
+
+    Ok(Some(StoredBlob {
+        hash: record.hash,
+        size: record.size,
+        location,
+    }))
+}
+
+// Adds a reference to the blob, creating the record on first use. Fails when
+// the file was removed by a release since it was ingested, the caller has
+// to push the content again
+pub async fn acquire_blob(database: &dyn Repository, blob: &StoredBlob) -> Result<Blob, BlobError> {
+    let _lock = lock_blob(&blob.hash).await;
+    if !blob.location.exists() {
+        return Err(BlobError::Missing(blob.hash.clone()));
+    }
+    let record = Blob::new(
+        blob.hash.clone(),
+        blob.size,
+        String::from(blob.location.to_str().unwrap()),
+    );
+    Ok(database.acquire_blob_ref(&record).await?)
+}
+
+// Drops a reference to the blob and deletes it once nothing points to it
+pub async fn release_blob(database: &dyn Repository, hash: &str) -> Result<(), BlobError> {
+    let _lock = lock_blob(hash).await;
+    let record = match database.release_blob_ref(hash).await? {
+        Some(r) => r,
+        None => return Ok(()),
+    };
+    if record.ref_count > 0 {
+        return Ok(());
+    }
+
+    database.delete_blob(hash).await?;
+    if let Err(e) = tokio::fs::remove_file(&record.location).await {
+        error!("Failed to remove blob {}: {}", hash, e);
+    }
+    info!("Blob released and removed : {}", hash);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::server::db::memory_repository::MemoryRepository;
+
+    fn temp_blob(content: &[u8]) -> StoredBlob {
+        let location = std::env::temp_dir().join(format!("aperture-blob-{}", Uuid::new_v4()));
+        std::fs::write(&location, content).unwrap();
+        StoredBlob {
+            hash: format!("{:x}", Sha256::digest(content)),
+            size: content.len() as u64,
+            location,
+        }
+    }
+
+    #[tokio::test]
+    async fn parallel_acquires_count_every_reference() {
+        let database = Arc::new(MemoryRepository::default());
+        let blob = temp_blob(b"shared content");
+        let tasks: Vec<_> = (0..16)
+            .map(|_i| {
+                let database = database.clone();
+                let blob = blob.clone();
+                tokio::spawn(async move { acquire_blob(database.as_ref(), &blob).await.is_ok() })
+            })
+            .collect();
+        for task in tasks {
+            assert!(task.await.unwrap());
+        }
+
+        let record = database.get_blob(&blob.hash).await.unwrap().unwrap();
+        assert_eq!(record.ref_count, 16);
+        std::fs::remove_file(&blob.location).unwrap();
+    }
+
+    #[tokio::test]
+    async fn last_release_removes_record_and_file() {
+        let database = MemoryRepository::default();
+        let blob = temp_blob(b"released content");
+        acquire_blob(&database, &blob).await.unwrap();
+        acquire_blob(&database, &blob).await.unwrap();
+
+        release_blob(&database, &blob.hash).await.unwrap();
+        let record = database.get_blob(&blob.hash).await.unwrap().unwrap();
+        assert_eq!(record.ref_count, 1);
+        assert!(blob.location.exists());
+
+        release_blob(&database, &blob.hash).await.unwrap();
+        assert!(database.get_blob(&blob.hash).await.unwrap().is_none());
+        assert!(!blob.location.exists());
+    }
+
+    #[tokio::test]
+    async fn acquire_fails_once_the_file_is_gone() {
+        let database = MemoryRepository::default();
+        let blob = temp_blob(b"missing content");
+        std::fs::remove_file(&blob.location).unwrap();
+
+        let result = acquire_blob(&database, &blob).await;
+        assert!(matches!(result, Err(BlobError::Missing(_))));
+        assert!(database.get_blob(&blob.hash).await.unwrap().is_none());
+    }
+}
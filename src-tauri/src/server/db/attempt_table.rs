@@ -0,0 +1,35 @@
+use chrono::Utc;
+
+/*  Failed PIN or password attempts for a device, a user account or a
+   source address, stored in the `attempt` table as `device_<id>` /
+   `user_<name>` / `ip_<addr>`. Removed again on the next successful
+   verification
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct FailedAttempts {
+    pub failures: u32,
+    pub last_failure: chrono::DateTime<Utc>,
+    pub locked_until: Option<chrono::DateTime<Utc>>,
+}
+
+impl FailedAttempts {
+    pub fn new() -> FailedAttempts {
+        FailedAttempts {
+            failures: 0,
+            last_failure: Utc::now(),
+            locked_until: None,
+        }
+    }
+
+    pub fn device_key(device_id: &str) -> String {
+        format!("device_{device_id}")
+    }
+
+    pub fn user_key(name: &str) -> String {
+        format!("user_{name}")
+    }
+
+    pub fn ip_key(ip: &std::net::IpAddr) -> String {
+        format!("ip_{ip}")
+    }
+}
@@ -0,0 +1,22 @@
+/*  Blob record for the content addressed store, keyed by the sha256 of the
+   content in the `blob` table. `ref_count` is the number of entries pointing
+   at the blob, the file is only deleted once it drops to zero
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Blob {
+    pub hash: String,
+    pub size: u64,
+    pub location: String,
+    pub ref_count: u64,
+}
+
+impl Blob {
+    pub fn new(hash: String, size: u64, location: String) -> Blob {
+        Blob {
+            hash,
+            size,
+            location,
+            ref_count: 0,
+        }
+    }
+}
@@ -0,0 +1,46 @@
+use chrono::Utc;
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum ChangeKind {
+    Create,
+    Update,
+    Delete,
+    // The server lost the content, clients holding it push it again
+    Lost,
+}
+
+/*  Change log entry for local entries, stored in the `change` table.
+   `seq` comes from the `counter:change` record and only ever increases,
+   clients use it as their sync cursor
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Change {
+    pub seq: u64,
+    pub device_id: String,
+    pub file_id: String,
+    pub kind: ChangeKind,
+    pub relative_path: String,
+    pub file_name: String,
+    pub date: chrono::DateTime<Utc>,
+}
+
+impl Change {
+    pub fn new(
+        seq: u64,
+        device_id: String,
+        file_id: String,
+        kind: ChangeKind,
+        relative_path: String,
+        file_name: String,
+    ) -> Change {
+        Change {
+            seq,
+            device_id,
+            file_id,
+            kind,
+            relative_path,
+            file_name,
+            date: Utc::now(),
+        }
+    }
+}
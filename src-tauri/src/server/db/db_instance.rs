@@ -1,49 +1,215 @@
-use surrealdb::{engine, Surreal};
+use std::sync::Arc;
+
+use rocket::{figment::Figment, serde::Deserialize};
+use surrealdb::{engine, opt::auth::Root, Connection, Surreal};
+
+use super::{
+    memory_repository::MemoryRepository, repository::Repository,
+    surreal_repository::SurrealRepository,
+};
+
+// Where the data lives, chosen with `engine` in the `[database]` section
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum DbEngine {
+    // Nothing is persisted, for tests and throwaway runs
+    Memory,
+    // Embedded SurrealDB stored at `datastore`
+    #[default]
+    File,
+    // SurrealDB server at `url`, signs in as root when credentials are set
+    Remote,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct DbConfig {
+    #[serde(default)]
+    pub engine: DbEngine,
+    pub namespace: String,
+    pub database: String,
+    #[serde(default = "default_datastore")]
+    pub datastore: String,
+    #[serde(default = "default_url")]
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl DbConfig {
+    // App.toml is merged `nested`, so `[database]` is read as its own profile
+    pub fn from_figment(figment: &Figment) -> Result<Self, rocket::figment::Error> {
+        figment.select("database").extract()
+    }
+}
+
+fn default_datastore() -> String {
+    "aperture.db".to_string()
+}
+
+fn default_url() -> String {
+    "localhost:8888".to_string()
+}
 
 pub struct DbInstance {
-    #[cfg(release)]
-    database: Surreal<engine::local::Db>,
-    #[cfg(not(release))]
-    pub database: Surreal<engine::remote::ws::Client>,
+    pub database: Arc<dyn Repository>,
 }
 
 impl DbInstance {
-    #[cfg(release)]
-    pub async fn new_instance(
-        namespace: String,
-        database: String,
-        datastore: String,
-    ) -> Result<Self, surrealdb::Error> {
-        let ds = Surreal::new::<engine::local::File>(datastore.as_str()).await?;
-
-        // Select a specific namespace / database
-        ds.use_ns(namespace).use_db(database).await?;
-        Ok(Self { database: ds })
-    }
-    #[cfg(not(release))]
-    pub async fn new_instance(
-        namespace: String,
-        database: String,
-        _datastore: String,
-    ) -> Result<Self, surrealdb::Error> {
-        use surrealdb::opt::auth::Root;
-        use tracing::{info, warn};
-
-        warn!("Running Surreal db instance for dev mode");
-        info!("Using remote server at 'localhost:8888'");
-
-        let ds = Surreal::new::<engine::remote::ws::Ws>("localhost:8888").
-        await.expect("Surreal db server not found on 'localhost:8888' please run the db server explicitly for dev mode");
-
-        ds.signin(Root {
-            username: "root",
-            password: "root",
-        })
-        .await
+    pub async fn new_instance(config: &DbConfig) -> Result<Self, surrealdb::Error> {
+        let database: Arc<dyn Repository> = match config.engine {
+            DbEngine::Memory => {
+                warn!("Using the in-memory database, nothing will be persisted");
+                Arc::new(MemoryRepository::default())
+            }
+            DbEngine::File => {
+                info!("Using embedded database at '{}'", config.datastore);
+                let ds = Surreal::new::<engine::local::SpeeDb>(config.datastore.as_str()).await?;
+                Arc::new(Self::select_db(ds, config).await?)
+            }
+            DbEngine::Remote => {
+                info!("Using remote database server at '{}'", config.url);
+                let ds = Surreal::new::<engine::remote::ws::Ws>(config.url.as_str()).await?;
+                if let (Some(username), Some(password)) = (&config.username, &config.password) {
+                    ds.signin(Root { username, password }).await?;
+                }
+                Arc::new(Self::select_db(ds, config).await?)
+            }
+        };
+        Ok(Self { database })
+    }
+
+    // Select a specific namespace / database
+    async fn select_db<C: Connection>(
+        ds: Surreal<C>,
+        config: &DbConfig,
+    ) -> Result<SurrealRepository<C>, surrealdb::Error> {
+        ds.use_ns(config.namespace.as_str())
+            .use_db(config.database.as_str())
+            .await?;
+        Ok(SurrealRepository::new(
+            ds,
+            &config.namespace,
+            &config.database,
+        ))
+    }
+}
+
+/*  Database handle for the Tauri commands, filled in once rocket has
+   ignited and the `DbInstance` exists
+*/
+#[derive(Default)]
+pub struct SharedDb {
+    database: tokio::sync::RwLock<Option<Arc<dyn Repository>>>,
+}
+
+impl SharedDb {
+    pub async fn set(&self, database: Arc<dyn Repository>) {
+        *self.database.write().await = Some(database);
+    }
+
+    pub async fn get(&self) -> Option<Arc<dyn Repository>> {
+        self.database.read().await.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::db::migrations::MIGRATIONS;
+    use rocket::figment::providers::{Format, Toml};
+
+    fn parse(toml: &str) -> Result<DbConfig, rocket::figment::Error> {
+        DbConfig::from_figment(&Figment::from(Toml::string(toml).nested()))
+    }
+
+    fn memory_config() -> DbConfig {
+        parse("[database]\nengine = \"memory\"\nnamespace = \"ns\"\ndatabase = \"db\"").unwrap()
+    }
+
+    #[test]
+    fn database_section_defaults() {
+        let config = parse("[database]\nnamespace = \"ns\"\ndatabase = \"db\"").unwrap();
+        assert_eq!(config.engine, DbEngine::File);
+        assert_eq!(config.namespace, "ns");
+        assert_eq!(config.database, "db");
+        assert_eq!(config.datastore, "aperture.db");
+        assert_eq!(config.url, "localhost:8888");
+        assert!(config.username.is_none() && config.password.is_none());
+    }
+
+    #[test]
+    fn database_section_is_parsed() {
+        let config = parse(
+            "[database]
+            engine = \"remote\"
+            namespace = \"ns\"
+            database = \"db\"
+            datastore = \"other.db\"
+            url = \"db.local:9000\"
+            username = \"root\"
+            password = \"secret\"",
+        )
         .unwrap();
+        assert_eq!(config.engine, DbEngine::Remote);
+        assert_eq!(config.datastore, "other.db");
+        assert_eq!(config.url, "db.local:9000");
+        assert_eq!(config.username.as_deref(), Some("root"));
+        assert_eq!(config.password.as_deref(), Some("secret"));
+        assert_eq!(memory_config().engine, DbEngine::Memory);
+    }
+
+    #[test]
+    fn invalid_database_section_is_rejected() {
+        assert!(parse("[database]\nnamespace = \"ns\"").is_err());
+        assert!(
+            parse("[database]\nengine = \"cloud\"\nnamespace = \"ns\"\ndatabase = \"db\"").is_err()
+        );
+        assert!(parse("[other]\nnamespace = \"ns\"\ndatabase = \"db\"").is_err());
+    }
+
+    #[tokio::test]
+    async fn memory_engine_is_not_persisted() {
+        let config = memory_config();
+        let first = DbInstance::new_instance(&config).await.unwrap();
+        first
+            .database
+            .apply_migration(&MIGRATIONS[0], &[])
+            .await
+            .unwrap();
+        let second = DbInstance::new_instance(&config).await.unwrap();
+        assert_eq!(second.database.schema_version().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn file_engine_opens_the_datastore() {
+        let datastore = std::env::temp_dir().join(format!("aperture-{}.db", uuid::Uuid::new_v4()));
+        let mut config = memory_config();
+        config.engine = DbEngine::File;
+        config.datastore = datastore.to_string_lossy().to_string();
+
+        let instance = DbInstance::new_instance(&config).await.unwrap();
+        let migration = &MIGRATIONS[0];
+        instance
+            .database
+            .apply_migration(migration, &[])
+            .await
+            .unwrap();
+        assert_eq!(
+            instance.database.schema_version().await.unwrap(),
+            migration.version
+        );
+        assert!(datastore.exists());
+
+        drop(instance);
+        let _ = std::fs::remove_dir_all(datastore);
+    }
 
-        // Select a specific namespace / database
-        ds.use_ns(namespace).use_db(database).await?;
-        Ok(Self { database: ds })
+    #[tokio::test]
+    async fn remote_engine_connects_to_the_url() {
+        let mut config = memory_config();
+        config.engine = DbEngine::Remote;
+        config.url = "127.0.0.1:1".to_string();
+        assert!(DbInstance::new_instance(&config).await.is_err());
     }
 }
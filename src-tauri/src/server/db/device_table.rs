@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use chrono::Utc;
 use uuid::Uuid;
 
@@ -15,6 +17,21 @@ pub struct Device {
     pub read_only: bool,
     pub os: OS,
     pub last_ip: String,
+    // Last change log sequence acknowledged by the device
+    #[serde(default)]
+    pub sync_cursor: u64,
+    // Same for the change log of the global library
+    #[serde(default)]
+    pub global_cursor: u64,
+    // Same for libraries of other devices shared through grants, keyed by owner
+    #[serde(default)]
+    pub shared_cursors: HashMap<String, u64>,
+    // User account owning the device, see `user_table`
+    #[serde(default)]
+    pub user: Option<String>,
+    // Admins may revoke other devices through the api
+    #[serde(default)]
+    pub admin: bool,
 }
 
 impl Device {
@@ -28,6 +45,11 @@ impl Device {
             created_date: Utc::now(),
             last_sync: Utc::now(),
             last_ip,
+            sync_cursor: 0,
+            global_cursor: 0,
+            shared_cursors: HashMap::new(),
+            user: None,
+            admin: false,
         }
     }
 }
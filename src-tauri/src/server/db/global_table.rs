@@ -0,0 +1,97 @@
+use std::path::Path;
+
+use chrono::Utc;
+
+use super::local_table::LocalEntry;
+
+/*  Global library shared by every device with `global = true`
+
+   Entries are `LocalEntry` records in the `global` table, keyed like the
+   device tables by `gen_file_id(relative_path, file_name)`. `file_uuid`
+   holds the device which pushed the current version. Changes and
+   tombstones use the table name as their device id and the content lives
+   in the blob store.
+   Devices of a user account share `global_<user uuid>` instead of `global`,
+   SurrealDB keeps it as the `global` table in the namespace of the user.
+   The user's library always wins, a device with a user never sees the
+   shared `global` table, which is left to devices without an account.
+*/
+pub const GLOBAL_TABLE: &str = "global";
+
+// Global library table for the devices of `user`, or the shared one
+pub fn global_table(user: Option<&str>) -> String {
+    match user {
+        Some(uuid) => format!("{GLOBAL_TABLE}_{}", uuid.replace('-', "")),
+        None => GLOBAL_TABLE.to_string(),
+    }
+}
+
+// SurrealDB namespace of the user owning the library `table`, if any
+pub fn user_namespace(table: &str) -> Option<String> {
+    table
+        .strip_prefix(&format!("{GLOBAL_TABLE}_"))
+        .map(|uuid| format!("user_{uuid}"))
+}
+
+// Device ids must not collide with the global library tables
+pub fn is_global_table(table: &str) -> bool {
+    table == GLOBAL_TABLE || table.starts_with(&format!("{GLOBAL_TABLE}_"))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GlobalPush {
+    // Nothing stored at the path yet
+    Create,
+    // Same content as the current version, nothing to do
+    Unchanged,
+    // The pushing device saw the current version, its push replaces it
+    Replace,
+    // Somebody else changed the path in the meantime, both versions are kept
+    ConflictCopy,
+}
+
+/// Conflict rules for a push to a path of the global library. A push
+/// replaces the current version if it is based on it (`base_hash`) or if
+/// the same device pushed the current version, otherwise it is stored
+/// next to it as a conflict copy
+pub fn resolve_push(
+    current: Option<&LocalEntry>,
+    device_id: &str,
+    content_hash: &str,
+    base_hash: Option<&str>,
+) -> GlobalPush {
+    let current = match current {
+        Some(c) => c,
+        None => return GlobalPush::Create,
+    };
+    let current_hash = current.content_hash.as_deref();
+    if current_hash == Some(content_hash) {
+        return GlobalPush::Unchanged;
+    }
+    let based_on_current = match (base_hash, current_hash) {
+        (Some(base), Some(current)) => base.eq_ignore_ascii_case(current),
+        _ => false,
+    };
+    if based_on_current || current.file_uuid == device_id {
+        GlobalPush::Replace
+    } else {
+        GlobalPush::ConflictCopy
+    }
+}
+
+// `photo.jpg` -> `photo (conflict Pixel 2023-06-01 142501).jpg`
+pub fn conflict_file_name(file_name: &str, device_name: &str) -> String {
+    let path = Path::new(file_name);
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_else(|| file_name.to_string());
+    let date = Utc::now().format("%Y-%m-%d %H%M%S");
+    match path.extension() {
+        Some(ext) => format!(
+            "{stem} (conflict {device_name} {date}).{}",
+            ext.to_string_lossy()
+        ),
+        None => format!("{stem} (conflict {device_name} {date})"),
+    }
+}
@@ -0,0 +1,58 @@
+use chrono::Utc;
+
+use crate::server::utility::gen_sha_256_hash;
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum Access {
+    Read,
+    ReadWrite,
+}
+
+impl Access {
+    // ReadWrite covers everything Read does
+    pub fn covers(&self, needed: Access) -> bool {
+        *self == Access::ReadWrite || needed == Access::Read
+    }
+}
+
+impl std::str::FromStr for Access {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Read" => Ok(Access::Read),
+            "ReadWrite" => Ok(Access::ReadWrite),
+            _ => Err("Unknown access, expected Read or ReadWrite"),
+        }
+    }
+}
+
+/*  Access an owner gave another device to its library, stored in the `acl`
+   table. An empty `relative_path` grants the whole library, otherwise only
+   the subtree below it
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Grant {
+    pub owner_id: String,
+    pub grantee_id: String,
+    pub relative_path: String,
+    pub access: Access,
+    pub created_date: chrono::DateTime<Utc>,
+}
+
+impl Grant {
+    pub fn new(owner_id: String, grantee_id: String, relative_path: String, access: Access) -> Grant {
+        Grant {
+            owner_id,
+            grantee_id,
+            relative_path,
+            access,
+            created_date: Utc::now(),
+        }
+    }
+
+    // Record id inside the `acl` table, one grant per owner, grantee and path
+    pub fn record_id(owner_id: &str, grantee_id: &str, relative_path: &str) -> String {
+        gen_sha_256_hash(&format!("{owner_id}\n{grantee_id}\n{relative_path}"))
+    }
+}
@@ -1,6 +1,15 @@
-use surrealdb::sql::{Algorithm, Thing};
+use surrealdb::sql::Thing;
 
-use crate::server::utility::gen_sha_256_hash;
+use crate::server::utility::{gen_pin_hash, gen_sha_256_hash, verify_pin_hash};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum HashAlgorithm {
+    // Unsalted sha256 of the PIN, records created before Argon2id have no
+    // `algorithm` field and default to this
+    #[default]
+    Sha256,
+    Argon2id,
+}
 
 // Device Hash for specific devices
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -9,20 +18,45 @@ pub struct DeviceHash {
     device: Thing,
     device_name: String,
     pub hash: String,
-    hash_type: Algorithm,
+    #[serde(default)]
+    pub algorithm: HashAlgorithm,
+    // Set by a reset from the desktop, login is refused until the PIN is changed
+    #[serde(default)]
+    pub must_change: bool,
 }
 
 impl DeviceHash {
     pub fn new(uuid: String, device_name: String, pin: String, obj: Thing) -> DeviceHash {
-        // Convert the hash to a string.
-        let hash_string = gen_sha_256_hash(&pin);
-
         DeviceHash {
             uuid,
             device: obj,
             device_name,
-            hash: hash_string,
-            hash_type: Algorithm::Hs256,
+            hash: gen_pin_hash(&pin),
+            algorithm: HashAlgorithm::Argon2id,
+            must_change: false,
+        }
+    }
+
+    pub fn verify(&self, pin: &str) -> bool {
+        match self.algorithm {
+            HashAlgorithm::Sha256 => self.hash == gen_sha_256_hash(&pin.to_string()),
+            HashAlgorithm::Argon2id => verify_pin_hash(pin, &self.hash),
         }
     }
+
+    pub fn needs_rehash(&self) -> bool {
+        self.algorithm != HashAlgorithm::Argon2id
+    }
+
+    // Replaces the stored hash with a fresh Argon2id one for `pin`
+    pub fn rehash(&mut self, pin: &str) {
+        self.hash = gen_pin_hash(pin);
+        self.algorithm = HashAlgorithm::Argon2id;
+    }
+
+    // Replaces the PIN itself, `must_change` marks it as temporary
+    pub fn set_pin(&mut self, pin: &str, must_change: bool) {
+        self.rehash(pin);
+        self.must_change = must_change;
+    }
 }
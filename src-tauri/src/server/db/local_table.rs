@@ -14,7 +14,14 @@ pub struct LocalEntry {
     pub relative_path: String,
     pub blurhash: Option<String>,
     pub file_location: String,
+    // sha256 of the content, older entries stored before the blob store have none
+    #[serde(default)]
+    pub content_hash: Option<String>,
     pub metadata: SerializedMetadata,
+    // The content is gone from the server, clients holding a copy are asked
+    // to push it again. Lost entries have no file and no content hash
+    #[serde(default)]
+    pub lost: bool,
 }
 
 impl LocalEntry {
@@ -23,25 +30,35 @@ impl LocalEntry {
         file_name: String,
         file_size: u64,
         file_location: String,
+        content_hash: Option<String>,
         mime: Option<Mime>,
         blurhash: Option<String>,
         dir_path: String,
         client_path: String,
         relative_path: String,
-    ) -> Self {
-        let metadata = fs::metadata(file_location.clone()).unwrap();
+    ) -> std::io::Result<Self> {
+        let metadata = fs::metadata(&file_location)?;
         let serialized_meta = SerializedMetadata::from(metadata, mime);
-        Self {
+        Ok(Self {
             file_uuid: device_uuid,
             file_name,
             file_size,
             file_location,
+            content_hash,
             metadata: serialized_meta,
             blurhash,
             dir_path,
             client_path,
             relative_path,
-        }
+            lost: false,
+        })
+    }
+
+    // Keeps the entry while dropping its reference to the missing content
+    pub fn mark_lost(&mut self) {
+        self.lost = true;
+        self.content_hash = None;
+        self.file_location = String::new();
     }
 
     // fn get_metadata(&self) -> &SerializedMetadata {
@@ -61,6 +78,31 @@ pub struct SerializedMetadata {
     accessed: Option<u64>,
     created: Option<u64>,
     len: Option<u64>,
+    // mtime of the file on the pushing client, `modified` is the stored copy
+    #[serde(default)]
+    client_modified: Option<u64>,
+}
+
+impl SerializedMetadata {
+    pub fn file_type(&self) -> Option<&str> {
+        self.file_type.as_deref()
+    }
+
+    pub fn modified(&self) -> Option<u64> {
+        self.modified
+    }
+
+    pub fn len(&self) -> Option<u64> {
+        self.len
+    }
+
+    pub fn client_modified(&self) -> Option<u64> {
+        self.client_modified
+    }
+
+    pub fn set_client_modified(&mut self, client_modified: Option<u64>) {
+        self.client_modified = client_modified;
+    }
 }
 
 #[cfg(unix)]
@@ -83,6 +125,7 @@ impl SerializedMetadata {
                 .ok()
                 .map(|t| t.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()),
             len: Some(metadata.len()),
+            client_modified: None,
         }
     }
 }
@@ -99,6 +142,9 @@ pub struct SerializedMetadata {
     accessed: Option<u64>,
     created: Option<u64>,
     len: Option<u64>,
+    // mtime of the file on the pushing client, `modified` is the stored copy
+    #[serde(default)]
+    client_modified: Option<u64>,
 }
 
 #[cfg(windows)]
@@ -112,6 +158,7 @@ impl SerializedMetadata {
             accessed: Some(metadata.last_access_time()),
             created: Some(metadata.creation_time()),
             len: Some(metadata.file_size()),
+            client_modified: None,
         }
     }
 }
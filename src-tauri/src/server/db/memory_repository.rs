@@ -0,0 +1,427 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+};
+
+use chrono::{DateTime, Utc};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use super::{
+    attempt_table::FailedAttempts,
+    blob_table::Blob,
+    change_table::Change,
+    device_table::Device,
+    grant_table::Grant,
+    hash_table::DeviceHash,
+    is_server_table,
+    local_table::LocalEntry,
+    migrations::Migration,
+    repository::{DbResult, Repository, RepositoryError},
+    session_table::{AuthConfig, Session},
+    tombstone_table::Tombstone,
+    upload_table::UploadSession,
+    user_table::User,
+};
+
+/*  Repository that keeps every table in memory, nothing survives a restart.
+   Records are stored serialized so they round trip through serde exactly
+   like they do with SurrealDB
+*/
+#[derive(Default)]
+pub struct MemoryRepository {
+    tables: Mutex<HashMap<String, BTreeMap<String, Value>>>,
+    change_seq: Mutex<u64>,
+    schema_version: Mutex<u32>,
+}
+
+impl MemoryRepository {
+    fn get<T: DeserializeOwned>(&self, table: &str, id: &str) -> DbResult<Option<T>> {
+        let tables = self.tables.lock().unwrap();
+        match tables.get(table).and_then(|t| t.get(id)) {
+            Some(v) => Ok(Some(serde_json::from_value(v.clone())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn put<T: Serialize>(&self, table: &str, id: &str, record: &T) -> DbResult<()> {
+        let value = serde_json::to_value(record)?;
+        let mut tables = self.tables.lock().unwrap();
+        tables
+            .entry(table.to_string())
+            .or_default()
+            .insert(id.to_string(), value);
+        Ok(())
+    }
+
+    fn remove<T: DeserializeOwned>(&self, table: &str, id: &str) -> DbResult<Option<T>> {
+        let mut tables = self.tables.lock().unwrap();
+        match tables.get_mut(table).and_then(|t| t.remove(id)) {
+            Some(v) => Ok(Some(serde_json::from_value(v)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn list<T: DeserializeOwned>(&self, table: &str) -> DbResult<Vec<(String, T)>> {
+        let tables = self.tables.lock().unwrap();
+        let records = match tables.get(table) {
+            Some(t) => t,
+            None => return Ok(Vec::new()),
+        };
+        let mut list = Vec::with_capacity(records.len());
+        for (id, value) in records {
+            list.push((id.clone(), serde_json::from_value(value.clone())?));
+        }
+        Ok(list)
+    }
+
+    fn values<T: DeserializeOwned>(&self, table: &str) -> DbResult<Vec<T>> {
+        Ok(self.list(table)?.into_iter().map(|(_id, r)| r).collect())
+    }
+
+    // Read-modify-write of one record under the table lock
+    fn update<T: Serialize + DeserializeOwned>(
+        &self,
+        table: &str,
+        id: &str,
+        change: impl FnOnce(Option<T>) -> Option<T>,
+    ) -> DbResult<Option<T>> {
+        let mut tables = self.tables.lock().unwrap();
+        let records = tables.entry(table.to_string()).or_default();
+        let current = match records.get(id) {
+            Some(v) => Some(serde_json::from_value(v.clone())?),
+            None => None,
+        };
+        let updated = change(current);
+        if let Some(record) = &updated {
+            records.insert(id.to_string(), serde_json::to_value(record)?);
+        }
+        Ok(updated)
+    }
+
+    // Removes the records of `table` matching the predicate and returns them
+    fn remove_where<T: DeserializeOwned>(
+        &self,
+        table: &str,
+        matches: impl Fn(&T) -> bool,
+    ) -> DbResult<Vec<T>> {
+        let mut removed = Vec::new();
+        for (id, record) in self.list::<T>(table)? {
+            if matches(&record) {
+                self.remove::<Value>(table, &id)?;
+                removed.push(record);
+            }
+        }
+        Ok(removed)
+    }
+}
+
+#[rocket::async_trait]
+impl Repository for MemoryRepository {
+    // Records are plain serde values here, only the version is tracked
+    async fn schema_version(&self) -> DbResult<u32> {
+        Ok(*self.schema_version.lock().unwrap())
+    }
+
+    async fn apply_migration(&self, migration: &Migration, _libraries: &[String]) -> DbResult<()> {
+        *self.schema_version.lock().unwrap() = migration.version;
+        Ok(())
+    }
+
+    async fn prepare_library(&self, _library: &str) -> DbResult<()> {
+        Ok(())
+    }
+
+    async fn get_device(&self, device_id: &str) -> DbResult<Option<Device>> {
+        self.get("device", device_id)
+    }
+
+    async fn list_devices(&self) -> DbResult<Vec<(String, Device)>> {
+        self.list("device")
+    }
+
+    async fn user_devices(&self, user: &str) -> DbResult<Vec<(String, Device)>> {
+        let devices: Vec<(String, Device)> = self.list("device")?;
+        Ok(devices
+            .into_iter()
+            .filter(|(_id, d)| d.user.as_deref() == Some(user))
+            .collect())
+    }
+
+    async fn save_device(&self, device_id: &str, device: &Device) -> DbResult<()> {
+        self.put("device", device_id, device)
+    }
+
+    async fn delete_device(&self, device_id: &str) -> DbResult<Option<Device>> {
+        self.remove("device", device_id)
+    }
+
+    async fn get_hash(&self, device_id: &str) -> DbResult<Option<DeviceHash>> {
+        self.get("hash", device_id)
+    }
+
+    async fn save_hash(&self, device_id: &str, hash: &DeviceHash) -> DbResult<()> {
+        self.put("hash", device_id, hash)
+    }
+
+    async fn delete_hash(&self, device_id: &str) -> DbResult<Option<DeviceHash>> {
+        self.remove("hash", device_id)
+    }
+
+    async fn get_entry(&self, library: &str, file_id: &str) -> DbResult<Option<LocalEntry>> {
+        self.get(library, file_id)
+    }
+
+    async fn list_entries(&self, library: &str) -> DbResult<Vec<(String, LocalEntry)>> {
+        self.list(library)
+    }
+
+    async fn save_entry(&self, library: &str, file_id: &str, entry: &LocalEntry) -> DbResult<()> {
+        self.put(library, file_id, entry)
+    }
+
+    async fn delete_entry(&self, library: &str, file_id: &str) -> DbResult<Option<LocalEntry>> {
+        self.remove(library, file_id)
+    }
+
+    async fn delete_library(&self, library: &str) -> DbResult<Vec<LocalEntry>> {
+        if is_server_table(library) {
+            return Err(RepositoryError::Reserved(library.to_string()));
+        }
+        let entries = self.values(library)?;
+        self.tables.lock().unwrap().remove(library);
+        Ok(entries)
+    }
+
+    async fn list_libraries(&self) -> DbResult<Vec<String>> {
+        let tables = self.tables.lock().unwrap();
+        Ok(tables
+            .keys()
+            .filter(|t| !is_server_table(t))
+            .cloned()
+            .collect())
+    }
+
+    async fn append_change(&self, change: &mut Change) -> DbResult<()> {
+        // The counter stays locked until the change is stored
+        let mut seq = self.change_seq.lock().unwrap();
+        change.seq = *seq + 1;
+        // Zero padded so the BTreeMap keeps the log in sequence order
+        self.put("change", &format!("{:020}", change.seq), change)?;
+        *seq = change.seq;
+        Ok(())
+    }
+
+    async fn latest_change_seq(&self) -> DbResult<u64> {
+        Ok(*self.change_seq.lock().unwrap())
+    }
+
+    async fn changes_after(
+        &self,
+        library: &str,
+        cursor: u64,
+        limit: u64,
+    ) -> DbResult<Vec<Change>> {
+        let changes: Vec<Change> = self.values("change")?;
+        Ok(changes
+            .into_iter()
+            .filter(|c| c.device_id == library && c.seq > cursor)
+            .take(limit as usize)
+            .collect())
+    }
+
+    async fn delete_changes(&self, library: &str) -> DbResult<()> {
+        self.remove_where("change", |c: &Change| c.device_id == library)?;
+        Ok(())
+    }
+
+    async fn list_tombstones(&self, library: &str) -> DbResult<Vec<Tombstone>> {
+        let tombstones: Vec<Tombstone> = self.values("tombstone")?;
+        Ok(tombstones
+            .into_iter()
+            .filter(|t| t.device_id == library)
+            .collect())
+    }
+
+    async fn save_tombstone(&self, tombstone: &Tombstone) -> DbResult<()> {
+        let id = Tombstone::record_id(&tombstone.device_id, &tombstone.file_id);
+        self.put("tombstone", &id, tombstone)
+    }
+
+    async fn delete_tombstone(&self, library: &str, file_id: &str) -> DbResult<()> {
+        let id = Tombstone::record_id(library, file_id);
+        self.remove::<Value>("tombstone", &id)?;
+        Ok(())
+    }
+
+    async fn delete_tombstones(&self, library: &str) -> DbResult<()> {
+        self.remove_where("tombstone", |t: &Tombstone| t.device_id == library)?;
+        Ok(())
+    }
+
+    async fn get_session(&self, session_id: &str) -> DbResult<Option<Session>> {
+        self.get("session", session_id)
+    }
+
+    async fn save_session(&self, session: &Session) -> DbResult<()> {
+        self.put("session", &session.session_id, session)
+    }
+
+    async fn delete_session(&self, session_id: &str) -> DbResult<()> {
+        self.remove::<Value>("session", session_id)?;
+        Ok(())
+    }
+
+    async fn delete_sessions(&self, device_id: &str) -> DbResult<()> {
+        self.remove_where("session", |s: &Session| s.device_id == device_id)?;
+        Ok(())
+    }
+
+    async fn get_auth_config(&self) -> DbResult<Option<AuthConfig>> {
+        self.get("config", "auth")
+    }
+
+    async fn save_auth_config(&self, config: &AuthConfig) -> DbResult<()> {
+        self.put("config", "auth", config)
+    }
+
+    async fn get_blob(&self, hash: &str) -> DbResult<Option<Blob>> {
+        self.get("blob", hash)
+    }
+
+    async fn save_blob(&self, blob: &Blob) -> DbResult<()> {
+        self.put("blob", &blob.hash, blob)
+    }
+
+    async fn delete_blob(&self, hash: &str) -> DbResult<()> {
+        self.remove::<Value>("blob", hash)?;
+        Ok(())
+    }
+
+    async fn acquire_blob_ref(&self, blob: &Blob) -> DbResult<Blob> {
+        let updated = self.update("blob", &blob.hash, |current: Option<Blob>| {
+            let mut record = current.unwrap_or_else(|| blob.clone());
+            record.ref_count += 1;
+            Some(record)
+        })?;
+        Ok(updated.unwrap_or_else(|| blob.clone()))
+    }
+
+    async fn release_blob_ref(&self, hash: &str) -> DbResult<Option<Blob>> {
+        self.update("blob", hash, |current: Option<Blob>| {
+            current.map(|mut record| {
+                record.ref_count = record.ref_count.saturating_sub(1);
+                record
+            })
+        })
+    }
+
+    async fn get_attempts(&self, key: &str) -> DbResult<Option<FailedAttempts>> {
+        self.get("attempt", key)
+    }
+
+    async fn save_attempts(&self, key: &str, attempts: &FailedAttempts) -> DbResult<()> {
+        self.put("attempt", key, attempts)
+    }
+
+    async fn delete_attempts(&self, key: &str) -> DbResult<()> {
+        self.remove::<Value>("attempt", key)?;
+        Ok(())
+    }
+
+    async fn get_upload(&self, session_id: &str) -> DbResult<Option<UploadSession>> {
+        self.get("upload", session_id)
+    }
+
+    async fn save_upload(&self, session: &UploadSession) -> DbResult<()> {
+        self.put("upload", &session.session_id, session)
+    }
+
+    async fn delete_upload(&self, session_id: &str) -> DbResult<()> {
+        self.remove::<Value>("upload", session_id)?;
+        Ok(())
+    }
+
+    async fn delete_uploads(&self, device_id: &str) -> DbResult<Vec<UploadSession>> {
+        self.remove_where("upload", |u: &UploadSession| u.device_id == device_id)
+    }
+
+    async fn delete_uploads_before(
+        &self,
+        created: DateTime<Utc>,
+    ) -> DbResult<Vec<UploadSession>> {
+        self.remove_where("upload", |u: &UploadSession| u.created_date < created)
+    }
+
+    async fn save_grant(&self, grant: &Grant) -> DbResult<()> {
+        let id = Grant::record_id(&grant.owner_id, &grant.grantee_id, &grant.relative_path);
+        self.put("acl", &id, grant)
+    }
+
+    async fn delete_grant(
+        &self,
+        owner_id: &str,
+        grantee_id: &str,
+        relative_path: &str,
+    ) -> DbResult<Option<Grant>> {
+        let id = Grant::record_id(owner_id, grantee_id, relative_path);
+        self.remove("acl", &id)
+    }
+
+    async fn grants_given(&self, owner_id: &str) -> DbResult<Vec<Grant>> {
+        let grants: Vec<Grant> = self.values("acl")?;
+        Ok(grants.into_iter().filter(|g| g.owner_id == owner_id).collect())
+    }
+
+    async fn grants_received(&self, grantee_id: &str) -> DbResult<Vec<Grant>> {
+        let grants: Vec<Grant> = self.values("acl")?;
+        Ok(grants
+            .into_iter()
+            .filter(|g| g.grantee_id == grantee_id)
+            .collect())
+    }
+
+    async fn grants_between(&self, owner_id: &str, grantee_id: &str) -> DbResult<Vec<Grant>> {
+        let grants: Vec<Grant> = self.values("acl")?;
+        Ok(grants
+            .into_iter()
+            .filter(|g| g.owner_id == owner_id && g.grantee_id == grantee_id)
+            .collect())
+    }
+
+    async fn delete_grants(&self, device_id: &str) -> DbResult<()> {
+        self.remove_where("acl", |g: &Grant| {
+            g.owner_id == device_id || g.grantee_id == device_id
+        })?;
+        Ok(())
+    }
+
+    async fn get_user(&self, name: &str) -> DbResult<Option<User>> {
+        self.get("user", name)
+    }
+
+    async fn user_by_uuid(&self, uuid: &str) -> DbResult<Option<User>> {
+        let users: Vec<User> = self.values("user")?;
+        Ok(users.into_iter().find(|u| u.uuid == uuid))
+    }
+
+    async fn save_user(&self, user: &User) -> DbResult<()> {
+        self.put("user", &user.name, user)
+    }
+
+    async fn delete_user(&self, name: &str) -> DbResult<Option<User>> {
+        self.remove("user", name)
+    }
+
+    async fn create_user(&self, user: &User) -> DbResult<bool> {
+        let mut created = false;
+        self.update("user", &user.name, |current: Option<User>| match current {
+            Some(_u) => None,
+            None => {
+                created = true;
+                Some(user.clone())
+            }
+        })?;
+        Ok(created)
+    }
+}
@@ -1,21 +1,15 @@
 use rocket::{
     fairing::{Fairing, Info, Kind, Result},
-    serde::Deserialize,
     Build, Rocket,
 };
 
-use super::db_instance::DbInstance;
+use super::{
+    db_instance::{DbConfig, DbInstance},
+    migrations::migrate,
+};
 
 pub struct DbMiddleware;
 
-#[derive(Deserialize)]
-#[serde(crate = "rocket::serde")]
-struct DbConfig {
-    namespace: String,
-    database: String,
-    datastore: String,
-}
-
 #[rocket::async_trait]
 impl Fairing for DbMiddleware {
     fn info(&self) -> Info {
@@ -28,19 +22,31 @@ impl Fairing for DbMiddleware {
     async fn on_ignite(&self, rocket: Rocket<Build>) -> Result {
         let figment = rocket.figment().clone();
 
-        let db_config: DbConfig = figment.select("database").extract().unwrap();
+        let db_config: DbConfig = match DbConfig::from_figment(&figment) {
+            Ok(c) => c,
+            Err(e) => {
+                error!("Invalid [database] configuration : {e}");
+                return Err(rocket);
+            }
+        };
 
-        let db = match DbInstance::new_instance(
-            db_config.namespace.clone(),
-            db_config.database.clone(),
-            db_config.datastore.clone(),
-        )
-        .await
-        {
+        let db = match DbInstance::new_instance(&db_config).await {
             Ok(db) => db,
-            Err(e) => panic!("{}", e),
+            Err(e) => {
+                error!("Failed to open the {:?} database : {e}", db_config.engine);
+                return Err(rocket);
+            }
         };
 
+        // Nothing may touch the tables before they are at the current schema
+        match migrate(db.database.as_ref()).await {
+            Ok(version) => info!("Database schema at version {}", version),
+            Err(e) => {
+                error!("Failed to migrate the database : {e}");
+                return Err(rocket);
+            }
+        }
+
         Ok(rocket.manage(db))
     }
 }
@@ -0,0 +1,192 @@
+use super::repository::{all_libraries, DbResult, Repository, RepositoryError};
+
+/*  Schema definitions and versioned migrations
+
+   Migrations run in order on ignite, each one inside a transaction that
+   also bumps `schema:version`, so a failed migration leaves the previous
+   version in place. `statements` work on the fixed tables, `library` is
+   run once for every library table with `{table}` replaced by its name
+   and again by `prepare_library` whenever a new library shows up.
+
+   Tables stay SCHEMALESS, fields are only defined where a type or an index
+   is worth enforcing. A new field on a stored struct (e.g. `LocalEntry`)
+   needs `#[serde(default)]` so existing records still load, and a
+   migration here when existing records need a real value for it.
+   Never edit a migration which has shipped, append a new one.
+*/
+
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    pub statements: &'static [&'static str],
+    pub library: &'static [&'static str],
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial schema",
+        statements: &[
+            "DEFINE TABLE device SCHEMALESS",
+            "DEFINE FIELD uuid ON TABLE device TYPE string",
+            "DEFINE FIELD name ON TABLE device TYPE string",
+            "DEFINE FIELD global ON TABLE device TYPE bool",
+            "DEFINE FIELD read_only ON TABLE device TYPE bool",
+            "DEFINE INDEX device_user ON TABLE device FIELDS user",
+            "DEFINE TABLE hash SCHEMALESS",
+            "DEFINE FIELD hash ON TABLE hash TYPE string",
+            "DEFINE TABLE session SCHEMALESS",
+            "DEFINE INDEX session_device ON TABLE session FIELDS device_id",
+            "DEFINE TABLE change SCHEMALESS",
+            "DEFINE INDEX change_device_seq ON TABLE change FIELDS device_id, seq",
+            "DEFINE TABLE tombstone SCHEMALESS",
+            "DEFINE INDEX tombstone_device ON TABLE tombstone FIELDS device_id",
+            "DEFINE TABLE upload SCHEMALESS",
+            "DEFINE INDEX upload_device ON TABLE upload FIELDS device_id",
+            "DEFINE TABLE acl SCHEMALESS",
+            "DEFINE INDEX acl_owner ON TABLE acl FIELDS owner_id",
+            "DEFINE INDEX acl_grantee ON TABLE acl FIELDS grantee_id",
+            "DEFINE TABLE user SCHEMALESS",
+            "DEFINE INDEX user_uuid ON TABLE user FIELDS uuid UNIQUE",
+            "DEFINE TABLE blob SCHEMALESS",
+            "DEFINE TABLE attempt SCHEMALESS",
+        ],
+        library: &[
+            "DEFINE TABLE {table} SCHEMALESS",
+            "DEFINE FIELD file_name ON TABLE {table} TYPE string",
+            "DEFINE FIELD relative_path ON TABLE {table} TYPE string",
+            "DEFINE FIELD file_location ON TABLE {table} TYPE string",
+            "DEFINE INDEX content_hash ON TABLE {table} FIELDS content_hash",
+        ],
+    },
+    Migration {
+        // Fields added after the first release only had serde defaults
+        version: 2,
+        name: "backfill device and hash defaults",
+        statements: &[
+            "UPDATE device SET sync_cursor = 0 WHERE sync_cursor = NONE",
+            "UPDATE device SET global_cursor = 0 WHERE global_cursor = NONE",
+            "UPDATE device SET admin = false WHERE admin = NONE",
+            "UPDATE hash SET must_change = false WHERE must_change = NONE",
+        ],
+        library: &[],
+    },
+    Migration {
+        // Records are keyed by name already, the index also covers records
+        // written by hand or restored from a backup
+        version: 3,
+        name: "unique user names",
+        statements: &["DEFINE INDEX user_name ON TABLE user FIELDS name UNIQUE"],
+        library: &[],
+    },
+    Migration {
+        // File ids were the hash of path and name glued together, so
+        // `a` + `bc` and `ab` + `c` shared one, see `gen_file_id`
+        version: 4,
+        name: "unambiguous file ids",
+        statements: &[
+            "UPDATE change SET file_id = crypto::sha256(string::concat(relative_path, '/', file_name))",
+            "LET $tombstones = (SELECT *, string::concat(device_id, '_', file_id) AS id FROM (SELECT *, crypto::sha256(string::concat(relative_path, '/', file_name)) AS file_id FROM tombstone))",
+            "DELETE tombstone",
+            "INSERT INTO tombstone $tombstones",
+        ],
+        library: &[
+            "LET $entries = (SELECT *, crypto::sha256(string::concat(relative_path, '/', file_name)) AS id FROM {table})",
+            "DELETE {table}",
+            "INSERT INTO {table} $entries",
+        ],
+    },
+];
+
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or_default()
+}
+
+// Library statements with the table name filled in, names which cannot be
+// quoted safely are refused
+pub fn library_statements(migration: &Migration, library: &str) -> DbResult<Vec<String>> {
+    if library.is_empty() || library.contains('`') {
+        return Err(RepositoryError::Schema(format!(
+            "invalid library table name '{library}'"
+        )));
+    }
+    let table = format!("`{library}`");
+    Ok(migration
+        .library
+        .iter()
+        .map(|s| s.replace("{table}", &table))
+        .collect())
+}
+
+/// Brings the schema up to the latest version, returns the version
+/// the database is at afterwards
+pub async fn migrate(database: &dyn Repository) -> DbResult<u32> {
+    let current = database.schema_version().await?;
+    let latest = latest_version();
+    if current > latest {
+        return Err(RepositoryError::Schema(format!(
+            "database schema version {current} is newer than this build ({latest})"
+        )));
+    }
+    if current == latest {
+        return Ok(current);
+    }
+
+    let libraries = all_libraries(database).await?;
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        info!(
+            "Applying schema migration {} : {}",
+            migration.version, migration.name
+        );
+        database.apply_migration(migration, &libraries).await?;
+    }
+    Ok(latest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::db::memory_repository::MemoryRepository;
+
+    #[tokio::test]
+    async fn migrates_a_fresh_database_to_the_latest_version() {
+        let database = MemoryRepository::default();
+        assert_eq!(migrate(&database).await.unwrap(), latest_version());
+        assert_eq!(database.schema_version().await.unwrap(), latest_version());
+
+        // Nothing left to do on the next start
+        assert_eq!(migrate(&database).await.unwrap(), latest_version());
+    }
+
+    #[tokio::test]
+    async fn refuses_a_database_newer_than_the_build() {
+        let database = MemoryRepository::default();
+        let newer = Migration {
+            version: latest_version() + 1,
+            name: "from the future",
+            statements: &[],
+            library: &[],
+        };
+        database.apply_migration(&newer, &[]).await.unwrap();
+
+        assert!(matches!(
+            migrate(&database).await,
+            Err(RepositoryError::Schema(_))
+        ));
+    }
+
+    #[test]
+    fn versions_are_in_order() {
+        for pair in MIGRATIONS.windows(2) {
+            assert_eq!(pair[0].version + 1, pair[1].version);
+        }
+    }
+
+    #[test]
+    fn library_names_which_cannot_be_quoted_are_refused() {
+        assert!(library_statements(&MIGRATIONS[0], "phone`; REMOVE TABLE device").is_err());
+        assert!(library_statements(&MIGRATIONS[0], "").is_err());
+        let statements = library_statements(&MIGRATIONS[0], "phone").unwrap();
+        assert!(statements[0].contains("`phone`"));
+    }
+}
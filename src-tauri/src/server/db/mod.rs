@@ -1,10 +1,55 @@
+pub mod attempt_table;
+pub mod blob_table;
+pub mod change_table;
 pub mod db_instance;
 pub mod device_table;
+pub mod global_table;
+pub mod grant_table;
 pub mod hash_table;
 pub mod local_table;
+pub mod memory_repository;
 pub mod middleware;
+pub mod migrations;
+pub mod repository;
+pub mod session_table;
+pub mod surreal_repository;
+pub mod tombstone_table;
+pub mod upload_table;
+pub mod user_table;
 
-use surrealdb::sql::Thing;
+use global_table::is_global_table;
+
+/*  Tables of the server itself. A device id names the library table of the
+   device, so ids taking one of these names, a global library name or the
+   name of a revoked library are refused when pairing, and the tables are
+   never dropped as a library
+*/
+pub const SERVER_TABLES: [&str; 13] = [
+    "device",
+    "hash",
+    "session",
+    "config",
+    "change",
+    "counter",
+    "tombstone",
+    "upload",
+    "acl",
+    "user",
+    "blob",
+    "attempt",
+    "schema",
+];
+
+// Libraries of revoked devices whose files were kept
+pub const REVOKED_PREFIX: &str = "revoked_";
+
+pub fn is_server_table(table: &str) -> bool {
+    SERVER_TABLES.iter().any(|t| t.eq_ignore_ascii_case(table))
+}
+
+pub fn is_reserved_table(table: &str) -> bool {
+    is_server_table(table) || is_global_table(table) || table.starts_with(REVOKED_PREFIX)
+}
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub enum OS {
@@ -13,12 +58,6 @@ pub enum OS {
     Windows(String),
 }
 
-#[derive(Debug, serde::Deserialize)]
-pub struct Record {
-    #[allow(dead_code)]
-    pub id: Thing,
-}
-
 /*  These are all persistant settings, these are immutable
    for rollback etc.
 */
@@ -0,0 +1,175 @@
+use std::fmt;
+
+use chrono::{DateTime, Utc};
+
+use super::{
+    attempt_table::FailedAttempts,
+    blob_table::Blob,
+    change_table::Change,
+    device_table::Device,
+    global_table::{global_table, GLOBAL_TABLE},
+    grant_table::Grant,
+    hash_table::DeviceHash,
+    local_table::LocalEntry,
+    migrations::Migration,
+    session_table::{AuthConfig, Session},
+    tombstone_table::Tombstone,
+    upload_table::UploadSession,
+    user_table::User,
+};
+
+#[derive(Debug)]
+pub enum RepositoryError {
+    Surreal(surrealdb::Error),
+    Serialize(serde_json::Error),
+    Schema(String),
+    // A server table was about to be treated as a library
+    Reserved(String),
+}
+
+impl fmt::Display for RepositoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RepositoryError::Surreal(e) => write!(f, "database error: {e}"),
+            RepositoryError::Serialize(e) => write!(f, "record (de)serialization error: {e}"),
+            RepositoryError::Schema(e) => write!(f, "schema error: {e}"),
+            RepositoryError::Reserved(t) => write!(f, "{t} is a server table, not a library"),
+        }
+    }
+}
+
+impl std::error::Error for RepositoryError {}
+
+impl From<surrealdb::Error> for RepositoryError {
+    fn from(e: surrealdb::Error) -> Self {
+        RepositoryError::Surreal(e)
+    }
+}
+
+impl From<serde_json::Error> for RepositoryError {
+    fn from(e: serde_json::Error) -> Self {
+        RepositoryError::Serialize(e)
+    }
+}
+
+pub type DbResult<T> = Result<T, RepositoryError>;
+
+/*  Everything the server stores goes through this trait, handlers never see
+   the engine behind it. `library` is the table holding a set of local
+   entries, the device id for a device library or the global table name.
+   Records keyed by id are upserted by the `save_*` methods and `delete_*`
+   returns the removed record when there was one
+*/
+#[rocket::async_trait]
+pub trait Repository: Send + Sync {
+    // Schema, see `migrations`
+    async fn schema_version(&self) -> DbResult<u32>;
+    async fn apply_migration(&self, migration: &Migration, libraries: &[String]) -> DbResult<()>;
+    // Defines a newly created library table like the migrated ones
+    async fn prepare_library(&self, library: &str) -> DbResult<()>;
+
+    // Devices
+    async fn get_device(&self, device_id: &str) -> DbResult<Option<Device>>;
+    async fn list_devices(&self) -> DbResult<Vec<(String, Device)>>;
+    async fn user_devices(&self, user: &str) -> DbResult<Vec<(String, Device)>>;
+    async fn save_device(&self, device_id: &str, device: &Device) -> DbResult<()>;
+    async fn delete_device(&self, device_id: &str) -> DbResult<Option<Device>>;
+
+    // PIN hashes, keyed by device id
+    async fn get_hash(&self, device_id: &str) -> DbResult<Option<DeviceHash>>;
+    async fn save_hash(&self, device_id: &str, hash: &DeviceHash) -> DbResult<()>;
+    async fn delete_hash(&self, device_id: &str) -> DbResult<Option<DeviceHash>>;
+
+    // Local entries, keyed by file id inside a library
+    async fn get_entry(&self, library: &str, file_id: &str) -> DbResult<Option<LocalEntry>>;
+    async fn list_entries(&self, library: &str) -> DbResult<Vec<(String, LocalEntry)>>;
+    async fn save_entry(&self, library: &str, file_id: &str, entry: &LocalEntry) -> DbResult<()>;
+    async fn delete_entry(&self, library: &str, file_id: &str) -> DbResult<Option<LocalEntry>>;
+    async fn delete_library(&self, library: &str) -> DbResult<Vec<LocalEntry>>;
+    // Every table holding entries, also the ones no device points at anymore
+    async fn list_libraries(&self) -> DbResult<Vec<String>>;
+
+    // Change log, `seq` comes from a single counter shared by all libraries.
+    // Takes the next number and stores the change in one transaction
+    async fn append_change(&self, change: &mut Change) -> DbResult<()>;
+    // Largest sequence number handed out so far
+    async fn latest_change_seq(&self) -> DbResult<u64>;
+    async fn changes_after(&self, library: &str, cursor: u64, limit: u64)
+        -> DbResult<Vec<Change>>;
+    async fn delete_changes(&self, library: &str) -> DbResult<()>;
+
+    // Tombstones
+    async fn list_tombstones(&self, library: &str) -> DbResult<Vec<Tombstone>>;
+    async fn save_tombstone(&self, tombstone: &Tombstone) -> DbResult<()>;
+    async fn delete_tombstone(&self, library: &str, file_id: &str) -> DbResult<()>;
+    async fn delete_tombstones(&self, library: &str) -> DbResult<()>;
+
+    // Sessions and the token signing key
+    async fn get_session(&self, session_id: &str) -> DbResult<Option<Session>>;
+    async fn save_session(&self, session: &Session) -> DbResult<()>;
+    async fn delete_session(&self, session_id: &str) -> DbResult<()>;
+    async fn delete_sessions(&self, device_id: &str) -> DbResult<()>;
+    async fn get_auth_config(&self) -> DbResult<Option<AuthConfig>>;
+    async fn save_auth_config(&self, config: &AuthConfig) -> DbResult<()>;
+
+    // Blobs, keyed by content hash
+    async fn get_blob(&self, hash: &str) -> DbResult<Option<Blob>>;
+    async fn save_blob(&self, blob: &Blob) -> DbResult<()>;
+    async fn delete_blob(&self, hash: &str) -> DbResult<()>;
+    // Atomic reference counting, `acquire` creates the record on first use
+    // and `release` returns the record after the decrement
+    async fn acquire_blob_ref(&self, blob: &Blob) -> DbResult<Blob>;
+    async fn release_blob_ref(&self, hash: &str) -> DbResult<Option<Blob>>;
+
+    // Failed login attempts, keyed by lockout key
+    async fn get_attempts(&self, key: &str) -> DbResult<Option<FailedAttempts>>;
+    async fn save_attempts(&self, key: &str, attempts: &FailedAttempts) -> DbResult<()>;
+    async fn delete_attempts(&self, key: &str) -> DbResult<()>;
+
+    // Resumable uploads
+    async fn get_upload(&self, session_id: &str) -> DbResult<Option<UploadSession>>;
+    async fn save_upload(&self, session: &UploadSession) -> DbResult<()>;
+    async fn delete_upload(&self, session_id: &str) -> DbResult<()>;
+    async fn delete_uploads(&self, device_id: &str) -> DbResult<Vec<UploadSession>>;
+    // Removes the sessions opened before `created` and returns them
+    async fn delete_uploads_before(&self, created: DateTime<Utc>)
+        -> DbResult<Vec<UploadSession>>;
+
+    // Access grants
+    async fn save_grant(&self, grant: &Grant) -> DbResult<()>;
+    async fn delete_grant(&self, owner_id: &str, grantee_id: &str, relative_path: &str)
+        -> DbResult<Option<Grant>>;
+    async fn grants_given(&self, owner_id: &str) -> DbResult<Vec<Grant>>;
+    async fn grants_received(&self, grantee_id: &str) -> DbResult<Vec<Grant>>;
+    async fn grants_between(&self, owner_id: &str, grantee_id: &str) -> DbResult<Vec<Grant>>;
+    // Removes grants the device gave as well as the ones it received
+    async fn delete_grants(&self, device_id: &str) -> DbResult<()>;
+
+    // User accounts, keyed by name
+    async fn get_user(&self, name: &str) -> DbResult<Option<User>>;
+    async fn user_by_uuid(&self, uuid: &str) -> DbResult<Option<User>>;
+    async fn save_user(&self, user: &User) -> DbResult<()>;
+    async fn delete_user(&self, name: &str) -> DbResult<Option<User>>;
+    // Creates the account unless the name is taken, returns whether it did
+    async fn create_user(&self, user: &User) -> DbResult<bool>;
+}
+
+// Every library table: the device tables, the global ones and the tables
+// without a device, kept files of revoked devices or globals of users whose
+// devices are all gone
+pub async fn all_libraries(database: &dyn Repository) -> DbResult<Vec<String>> {
+    let mut libraries = vec![GLOBAL_TABLE.to_string()];
+    for (device_id, device) in database.list_devices().await? {
+        let global = global_table(device.user.as_deref());
+        if !libraries.contains(&global) {
+            libraries.push(global);
+        }
+        libraries.push(device_id);
+    }
+    for library in database.list_libraries().await? {
+        if !libraries.contains(&library) {
+            libraries.push(library);
+        }
+    }
+    Ok(libraries)
+}
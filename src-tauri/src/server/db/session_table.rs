@@ -0,0 +1,34 @@
+use chrono::{Duration, Utc};
+use uuid::Uuid;
+
+/*  Login session of a device, stored in the `session` table keyed by
+   `session_id`. Only the sha256 of the refresh token secret is kept,
+   deleting the record invalidates the refresh token and every access
+   token issued for it
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Session {
+    pub session_id: String,
+    pub device_id: String,
+    pub refresh_hash: String,
+    pub created_date: chrono::DateTime<Utc>,
+    pub refresh_expiry: chrono::DateTime<Utc>,
+}
+
+impl Session {
+    pub fn new(device_id: String, refresh_hash: String, refresh_ttl: Duration) -> Session {
+        Session {
+            session_id: Uuid::new_v4().to_string(),
+            device_id,
+            refresh_hash,
+            created_date: Utc::now(),
+            refresh_expiry: Utc::now() + refresh_ttl,
+        }
+    }
+}
+
+// Key used to sign access tokens, stored once as `config:auth`
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct AuthConfig {
+    pub token_secret: String,
+}
@@ -0,0 +1,599 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use serde::{de::DeserializeOwned, Deserialize};
+use surrealdb::{sql::Thing, Connection, Surreal};
+
+use super::{
+    attempt_table::FailedAttempts,
+    blob_table::Blob,
+    change_table::Change,
+    device_table::Device,
+    global_table::{global_table, user_namespace, GLOBAL_TABLE},
+    grant_table::Grant,
+    hash_table::DeviceHash,
+    is_server_table,
+    local_table::LocalEntry,
+    migrations::{library_statements, Migration, MIGRATIONS},
+    repository::{DbResult, Repository, RepositoryError},
+    session_table::{AuthConfig, Session},
+    tombstone_table::Tombstone,
+    upload_table::UploadSession,
+    user_table::User,
+};
+
+// `INFO FOR DB`, only the table definitions keyed by table name are read
+#[derive(Deserialize)]
+struct DatabaseInfo {
+    tb: HashMap<String, String>,
+}
+
+// A record together with the key it is stored under
+#[derive(Deserialize)]
+#[serde(bound = "T: DeserializeOwned")]
+struct Keyed<T> {
+    id: Thing,
+    #[serde(flatten)]
+    record: T,
+}
+
+impl<T> Keyed<T> {
+    fn into_pair(self) -> (String, T) {
+        (self.id.id.to_raw(), self.record)
+    }
+}
+
+// Where the records of a library are kept
+struct Location {
+    // Switches to the namespace of the user owning the library
+    use_ns: Option<String>,
+    table: String,
+}
+
+impl Location {
+    // Prefix for a query on the library and the index of its first result
+    fn query(&self, query: &str) -> (String, usize) {
+        match &self.use_ns {
+            Some(use_ns) => (format!("{use_ns};\n{query}"), 1),
+            None => (query.to_string(), 0),
+        }
+    }
+}
+
+/*  Repository backed by SurrealDB, generic over the connection so the same
+   code serves the embedded engines and the remote websocket client.
+
+   Server tables and device libraries live in the configured namespace,
+   the global library of a user account is the `global` table of the same
+   database in the namespace `user_<uuid>`. A `USE` at the start of a query
+   only applies to that query, so library queries switch per query
+*/
+pub struct SurrealRepository<C: Connection> {
+    database: Surreal<C>,
+    namespace: String,
+    database_name: String,
+}
+
+impl<C: Connection> SurrealRepository<C> {
+    pub fn new(database: Surreal<C>, namespace: &str, database_name: &str) -> Self {
+        Self {
+            database,
+            namespace: namespace.to_string(),
+            database_name: database_name.to_string(),
+        }
+    }
+
+    fn use_statement(&self, namespace: &str) -> String {
+        format!("USE NS `{namespace}` DB `{}`", self.database_name)
+    }
+
+    fn locate(&self, library: &str) -> DbResult<Location> {
+        if library.contains('`') {
+            return Err(RepositoryError::Schema(format!(
+                "invalid library table name '{library}'"
+            )));
+        }
+        Ok(match user_namespace(library) {
+            Some(namespace) => Location {
+                use_ns: Some(self.use_statement(&namespace)),
+                table: GLOBAL_TABLE.to_string(),
+            },
+            None => Location {
+                use_ns: None,
+                table: library.to_string(),
+            },
+        })
+    }
+
+    // Library statements of a migration, run in the namespace of the
+    // library and switching back to the configured one afterwards
+    fn located_statements(&self, migration: &Migration, library: &str) -> DbResult<Vec<String>> {
+        let location = self.locate(library)?;
+        let statements = library_statements(migration, &location.table)?;
+        Ok(match location.use_ns {
+            Some(use_ns) => std::iter::once(use_ns)
+                .chain(statements)
+                .chain(std::iter::once(self.use_statement(&self.namespace)))
+                .collect(),
+            None => statements,
+        })
+    }
+
+    async fn get<T: DeserializeOwned>(&self, table: &str, id: &str) -> DbResult<Option<T>> {
+        Ok(self.database.select((table, id)).await?)
+    }
+
+    async fn put<T: serde::Serialize + DeserializeOwned>(
+        &self,
+        table: &str,
+        id: &str,
+        record: &T,
+    ) -> DbResult<()> {
+        let _r: Option<T> = self.database.update((table, id)).content(record).await?;
+        Ok(())
+    }
+
+    async fn remove<T: DeserializeOwned>(&self, table: &str, id: &str) -> DbResult<Option<T>> {
+        Ok(self.database.delete((table, id)).await?)
+    }
+
+    async fn list<T: DeserializeOwned>(&self, table: &str) -> DbResult<Vec<(String, T)>> {
+        let records: Vec<Keyed<T>> = self.database.select(table).await?;
+        Ok(records.into_iter().map(Keyed::into_pair).collect())
+    }
+
+    async fn run(&self, query: &str, key: &str, value: &str) -> DbResult<()> {
+        self.database.query(query).bind((key, value)).await?;
+        Ok(())
+    }
+
+    // Runs the statements as one transaction, failing on the first error
+    async fn transaction(&self, statements: Vec<String>) -> DbResult<()> {
+        let mut query = String::from("BEGIN TRANSACTION;\n");
+        for statement in statements {
+            query.push_str(&statement);
+            query.push_str(";\n");
+        }
+        query.push_str("COMMIT TRANSACTION;");
+        self.database.query(query).await?.check()?;
+        Ok(())
+    }
+}
+
+#[rocket::async_trait]
+impl<C: Connection> Repository for SurrealRepository<C> {
+    async fn schema_version(&self) -> DbResult<u32> {
+        let version: Option<u32> = self
+            .database
+            .query("SELECT VALUE version FROM schema:version")
+            .await?
+            .take(0)?;
+        Ok(version.unwrap_or_default())
+    }
+
+    async fn apply_migration(&self, migration: &Migration, libraries: &[String]) -> DbResult<()> {
+        let mut statements: Vec<String> =
+            migration.statements.iter().map(|s| s.to_string()).collect();
+        for library in libraries {
+            statements.extend(self.located_statements(migration, library)?);
+        }
+        statements.push(format!(
+            "UPDATE schema:version SET version = {}, name = '{}', applied = time::now()",
+            migration.version, migration.name
+        ));
+        self.transaction(statements).await
+    }
+
+    async fn prepare_library(&self, library: &str) -> DbResult<()> {
+        let mut statements = Vec::new();
+        for migration in MIGRATIONS {
+            statements.extend(self.located_statements(migration, library)?);
+        }
+        self.transaction(statements).await
+    }
+
+    async fn get_device(&self, device_id: &str) -> DbResult<Option<Device>> {
+        self.get("device", device_id).await
+    }
+
+    async fn list_devices(&self) -> DbResult<Vec<(String, Device)>> {
+        self.list("device").await
+    }
+
+    async fn user_devices(&self, user: &str) -> DbResult<Vec<(String, Device)>> {
+        let devices: Vec<Keyed<Device>> = self
+            .database
+            .query("SELECT * FROM device WHERE user = $user")
+            .bind(("user", user))
+            .await?
+            .take(0)?;
+        Ok(devices.into_iter().map(Keyed::into_pair).collect())
+    }
+
+    async fn save_device(&self, device_id: &str, device: &Device) -> DbResult<()> {
+        self.put("device", device_id, device).await
+    }
+
+    async fn delete_device(&self, device_id: &str) -> DbResult<Option<Device>> {
+        self.remove("device", device_id).await
+    }
+
+    async fn get_hash(&self, device_id: &str) -> DbResult<Option<DeviceHash>> {
+        self.get("hash", device_id).await
+    }
+
+    async fn save_hash(&self, device_id: &str, hash: &DeviceHash) -> DbResult<()> {
+        self.put("hash", device_id, hash).await
+    }
+
+    async fn delete_hash(&self, device_id: &str) -> DbResult<Option<DeviceHash>> {
+        self.remove("hash", device_id).await
+    }
+
+    async fn get_entry(&self, library: &str, file_id: &str) -> DbResult<Option<LocalEntry>> {
+        let location = self.locate(library)?;
+        let (query, index) = location.query("SELECT * FROM type::thing($table, $id)");
+        Ok(self
+            .database
+            .query(query)
+            .bind(("table", &location.table))
+            .bind(("id", file_id))
+            .await?
+            .take(index)?)
+    }
+
+    async fn list_entries(&self, library: &str) -> DbResult<Vec<(String, LocalEntry)>> {
+        let location = self.locate(library)?;
+        let (query, index) = location.query("SELECT * FROM type::table($table)");
+        let entries: Vec<Keyed<LocalEntry>> = self
+            .database
+            .query(query)
+            .bind(("table", &location.table))
+            .await?
+            .take(index)?;
+        Ok(entries.into_iter().map(Keyed::into_pair).collect())
+    }
+
+    async fn save_entry(&self, library: &str, file_id: &str, entry: &LocalEntry) -> DbResult<()> {
+        let location = self.locate(library)?;
+        let (query, _index) =
+            location.query("UPDATE type::thing($table, $id) CONTENT $entry RETURN NONE");
+        self.database
+            .query(query)
+            .bind(("table", &location.table))
+            .bind(("id", file_id))
+            .bind(("entry", entry))
+            .await?
+            .check()?;
+        Ok(())
+    }
+
+    async fn delete_entry(&self, library: &str, file_id: &str) -> DbResult<Option<LocalEntry>> {
+        let location = self.locate(library)?;
+        let (query, index) = location.query("DELETE type::thing($table, $id) RETURN BEFORE");
+        Ok(self
+            .database
+            .query(query)
+            .bind(("table", &location.table))
+            .bind(("id", file_id))
+            .await?
+            .take(index)?)
+    }
+
+    async fn delete_library(&self, library: &str) -> DbResult<Vec<LocalEntry>> {
+        if is_server_table(library) {
+            return Err(RepositoryError::Reserved(library.to_string()));
+        }
+        let location = self.locate(library)?;
+        let (query, index) = location.query("DELETE type::table($table) RETURN BEFORE");
+        Ok(self
+            .database
+            .query(query)
+            .bind(("table", &location.table))
+            .await?
+            .take(index)?)
+    }
+
+    // Tables of the configured database plus the library of every user
+    async fn list_libraries(&self) -> DbResult<Vec<String>> {
+        let mut response = self
+            .database
+            .query("INFO FOR DB")
+            .query("SELECT VALUE uuid FROM user")
+            .await?;
+        let info: Option<DatabaseInfo> = response.take(0)?;
+        let users: Vec<String> = response.take(1)?;
+        let mut libraries: Vec<String> = info
+            .map(|i| i.tb.into_keys().filter(|t| !is_server_table(t)).collect())
+            .unwrap_or_default();
+        libraries.extend(users.iter().map(|uuid| global_table(Some(uuid))));
+        Ok(libraries)
+    }
+
+    async fn append_change(&self, change: &mut Change) -> DbResult<()> {
+        let seq: Option<u64> = self
+            .database
+            .query(
+                "BEGIN TRANSACTION;
+                LET $counter = (UPDATE ONLY counter:change SET value += 1 RETURN value);
+                CREATE type::thing('change', $counter.value) CONTENT $change RETURN NONE;
+                UPDATE type::thing('change', $counter.value) SET seq = $counter.value RETURN seq;
+                COMMIT TRANSACTION;",
+            )
+            .bind(("change", &*change))
+            .await?
+            .check()?
+            .take((2, "seq"))?;
+        match seq {
+            Some(seq) => {
+                change.seq = seq;
+                Ok(())
+            }
+            None => Err(RepositoryError::Schema(
+                "change was not numbered".to_string(),
+            )),
+        }
+    }
+
+    async fn latest_change_seq(&self) -> DbResult<u64> {
+        let seq: Option<u64> = self
+            .database
+            .query("SELECT VALUE value FROM counter:change")
+            .await?
+            .take(0)?;
+        Ok(seq.unwrap_or_default())
+    }
+
+    async fn changes_after(
+        &self,
+        library: &str,
+        cursor: u64,
+        limit: u64,
+    ) -> DbResult<Vec<Change>> {
+        Ok(self
+            .database
+            .query("SELECT * FROM change WHERE device_id = $device AND seq > $cursor ORDER BY seq LIMIT $limit")
+            .bind(("device", library))
+            .bind(("cursor", cursor))
+            .bind(("limit", limit))
+            .await?
+            .take(0)?)
+    }
+
+    async fn delete_changes(&self, library: &str) -> DbResult<()> {
+        self.run("DELETE change WHERE device_id = $device", "device", library)
+            .await
+    }
+
+    async fn list_tombstones(&self, library: &str) -> DbResult<Vec<Tombstone>> {
+        Ok(self
+            .database
+            .query("SELECT * FROM tombstone WHERE device_id = $device")
+            .bind(("device", library))
+            .await?
+            .take(0)?)
+    }
+
+    async fn save_tombstone(&self, tombstone: &Tombstone) -> DbResult<()> {
+        let id = Tombstone::record_id(&tombstone.device_id, &tombstone.file_id);
+        self.put("tombstone", &id, tombstone).await
+    }
+
+    async fn delete_tombstone(&self, library: &str, file_id: &str) -> DbResult<()> {
+        let id = Tombstone::record_id(library, file_id);
+        let _r: Option<Tombstone> = self.remove("tombstone", &id).await?;
+        Ok(())
+    }
+
+    async fn delete_tombstones(&self, library: &str) -> DbResult<()> {
+        self.run("DELETE tombstone WHERE device_id = $device", "device", library)
+            .await
+    }
+
+    async fn get_session(&self, session_id: &str) -> DbResult<Option<Session>> {
+        self.get("session", session_id).await
+    }
+
+    async fn save_session(&self, session: &Session) -> DbResult<()> {
+        self.put("session", &session.session_id, session).await
+    }
+
+    async fn delete_session(&self, session_id: &str) -> DbResult<()> {
+        let _r: Option<Session> = self.remove("session", session_id).await?;
+        Ok(())
+    }
+
+    async fn delete_sessions(&self, device_id: &str) -> DbResult<()> {
+        self.run("DELETE session WHERE device_id = $device", "device", device_id)
+            .await
+    }
+
+    async fn get_auth_config(&self) -> DbResult<Option<AuthConfig>> {
+        self.get("config", "auth").await
+    }
+
+    async fn save_auth_config(&self, config: &AuthConfig) -> DbResult<()> {
+        self.put("config", "auth", config).await
+    }
+
+    async fn get_blob(&self, hash: &str) -> DbResult<Option<Blob>> {
+        self.get("blob", hash).await
+    }
+
+    async fn save_blob(&self, blob: &Blob) -> DbResult<()> {
+        self.put("blob", &blob.hash, blob).await
+    }
+
+    async fn delete_blob(&self, hash: &str) -> DbResult<()> {
+        let _r: Option<Blob> = self.remove("blob", hash).await?;
+        Ok(())
+    }
+
+    async fn acquire_blob_ref(&self, blob: &Blob) -> DbResult<Blob> {
+        let record: Option<Blob> = self
+            .database
+            .query(
+                "UPDATE type::thing('blob', $hash) SET hash = $hash, size = $size, \
+                 location = $location, ref_count = (ref_count OR 0) + 1 RETURN AFTER",
+            )
+            .bind(("hash", &blob.hash))
+            .bind(("size", blob.size))
+            .bind(("location", &blob.location))
+            .await?
+            .take(0)?;
+        Ok(record.unwrap_or_else(|| blob.clone()))
+    }
+
+    async fn release_blob_ref(&self, hash: &str) -> DbResult<Option<Blob>> {
+        // The WHERE keeps a missing record from being created
+        Ok(self
+            .database
+            .query(
+                "UPDATE type::thing('blob', $hash) SET ref_count = math::max([ref_count - 1, 0]) \
+                 WHERE hash = $hash RETURN AFTER",
+            )
+            .bind(("hash", hash))
+            .await?
+            .take(0)?)
+    }
+
+    async fn get_attempts(&self, key: &str) -> DbResult<Option<FailedAttempts>> {
+        self.get("attempt", key).await
+    }
+
+    async fn save_attempts(&self, key: &str, attempts: &FailedAttempts) -> DbResult<()> {
+        self.put("attempt", key, attempts).await
+    }
+
+    async fn delete_attempts(&self, key: &str) -> DbResult<()> {
+        let _r: Option<FailedAttempts> = self.remove("attempt", key).await?;
+        Ok(())
+    }
+
+    async fn get_upload(&self, session_id: &str) -> DbResult<Option<UploadSession>> {
+        self.get("upload", session_id).await
+    }
+
+    async fn save_upload(&self, session: &UploadSession) -> DbResult<()> {
+        self.put("upload", &session.session_id, session).await
+    }
+
+    async fn delete_upload(&self, session_id: &str) -> DbResult<()> {
+        let _r: Option<UploadSession> = self.remove("upload", session_id).await?;
+        Ok(())
+    }
+
+    async fn delete_uploads(&self, device_id: &str) -> DbResult<Vec<UploadSession>> {
+        Ok(self
+            .database
+            .query("DELETE upload WHERE device_id = $device RETURN BEFORE")
+            .bind(("device", device_id))
+            .await?
+            .take(0)?)
+    }
+
+    async fn delete_uploads_before(
+        &self,
+        created: DateTime<Utc>,
+    ) -> DbResult<Vec<UploadSession>> {
+        Ok(self
+            .database
+            .query("DELETE upload WHERE created_date < $created RETURN BEFORE")
+            .bind(("created", created))
+            .await?
+            .take(0)?)
+    }
+
+    async fn save_grant(&self, grant: &Grant) -> DbResult<()> {
+        let id = Grant::record_id(&grant.owner_id, &grant.grantee_id, &grant.relative_path);
+        self.put("acl", &id, grant).await
+    }
+
+    async fn delete_grant(
+        &self,
+        owner_id: &str,
+        grantee_id: &str,
+        relative_path: &str,
+    ) -> DbResult<Option<Grant>> {
+        let id = Grant::record_id(owner_id, grantee_id, relative_path);
+        self.remove("acl", &id).await
+    }
+
+    async fn grants_given(&self, owner_id: &str) -> DbResult<Vec<Grant>> {
+        Ok(self
+            .database
+            .query("SELECT * FROM acl WHERE owner_id = $device")
+            .bind(("device", owner_id))
+            .await?
+            .take(0)?)
+    }
+
+    async fn grants_received(&self, grantee_id: &str) -> DbResult<Vec<Grant>> {
+        Ok(self
+            .database
+            .query("SELECT * FROM acl WHERE grantee_id = $device")
+            .bind(("device", grantee_id))
+            .await?
+            .take(0)?)
+    }
+
+    async fn grants_between(&self, owner_id: &str, grantee_id: &str) -> DbResult<Vec<Grant>> {
+        Ok(self
+            .database
+            .query("SELECT * FROM acl WHERE owner_id = $owner AND grantee_id = $grantee")
+            .bind(("owner", owner_id))
+            .bind(("grantee", grantee_id))
+            .await?
+            .take(0)?)
+    }
+
+    async fn delete_grants(&self, device_id: &str) -> DbResult<()> {
+        self.run(
+            "DELETE acl WHERE owner_id = $device OR grantee_id = $device",
+            "device",
+            device_id,
+        )
+        .await
+    }
+
+    async fn get_user(&self, name: &str) -> DbResult<Option<User>> {
+        self.get("user", name).await
+    }
+
+    async fn user_by_uuid(&self, uuid: &str) -> DbResult<Option<User>> {
+        let user: Option<User> = self
+            .database
+            .query("SELECT * FROM user WHERE uuid = $user")
+            .bind(("user", uuid))
+            .await?
+            .take(0)?;
+        Ok(user)
+    }
+
+    async fn save_user(&self, user: &User) -> DbResult<()> {
+        self.put("user", &user.name, user).await
+    }
+
+    async fn delete_user(&self, name: &str) -> DbResult<Option<User>> {
+        self.remove("user", name).await
+    }
+
+    // CREATE fails on an existing record and the unique `user_name` index,
+    // a name taken in the meantime shows up as a user under that name
+    async fn create_user(&self, user: &User) -> DbResult<bool> {
+        let created = self
+            .database
+            .query("CREATE type::thing('user', $name) CONTENT $user")
+            .bind(("name", &user.name))
+            .bind(("user", user))
+            .await
+            .and_then(|mut r| r.take::<Option<User>>(0));
+        match created {
+            Ok(_u) => Ok(true),
+            Err(e) => match self.get_user(&user.name).await? {
+                Some(_u) => Ok(false),
+                None => Err(e.into()),
+            },
+        }
+    }
+}
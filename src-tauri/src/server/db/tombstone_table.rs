@@ -0,0 +1,39 @@
+use chrono::Utc;
+
+/*  Tombstone for a deleted local entry, stored in the `tombstone` table so
+   syncing clients remove their copy instead of uploading it again.
+   Removed again when a file is pushed to the same path
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Tombstone {
+    pub device_id: String,
+    pub file_id: String,
+    pub relative_path: String,
+    pub file_name: String,
+    pub content_hash: Option<String>,
+    pub deleted_date: chrono::DateTime<Utc>,
+}
+
+impl Tombstone {
+    pub fn new(
+        device_id: String,
+        file_id: String,
+        relative_path: String,
+        file_name: String,
+        content_hash: Option<String>,
+    ) -> Tombstone {
+        Tombstone {
+            device_id,
+            file_id,
+            relative_path,
+            file_name,
+            content_hash,
+            deleted_date: Utc::now(),
+        }
+    }
+
+    // Record id inside the `tombstone` table
+    pub fn record_id(device_id: &str, file_id: &str) -> String {
+        format!("{device_id}_{file_id}")
+    }
+}
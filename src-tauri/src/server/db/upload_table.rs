@@ -0,0 +1,79 @@
+use chrono::{Duration, Utc};
+use uuid::Uuid;
+
+// Sessions not committed within this time are dropped with their chunks
+pub const UPLOAD_SESSION_TTL_HOURS: i64 = 24;
+
+/*  Upload session for chunked pushes, stored in the `upload` table
+   keyed by the session id until the upload is committed or expires
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct UploadSession {
+    pub session_id: String,
+    pub device_id: String,
+    pub device_name: String,
+    pub file_name: String,
+    pub file_size: u64,
+    pub content_type: Option<String>,
+    pub dir_path: String,
+    pub client_path: String,
+    pub relative_path: String,
+    pub created_date: chrono::DateTime<Utc>,
+    // Committed into the global library
+    #[serde(default)]
+    pub global: bool,
+    // Library of another device the upload goes to, see `grant_table`
+    #[serde(default)]
+    pub owner: Option<String>,
+    // Content hash of the global version the upload is based on
+    #[serde(default)]
+    pub base_hash: Option<String>,
+    // mtime of the file on the client, see `SerializedMetadata`
+    #[serde(default)]
+    pub modified: Option<u64>,
+}
+
+impl UploadSession {
+    pub fn new(
+        device_id: String,
+        device_name: String,
+        file_name: String,
+        file_size: u64,
+        content_type: Option<String>,
+        dir_path: String,
+        client_path: String,
+        relative_path: String,
+        global: bool,
+        owner: Option<String>,
+        base_hash: Option<String>,
+        modified: Option<u64>,
+    ) -> UploadSession {
+        UploadSession {
+            session_id: Uuid::new_v4().to_string(),
+            device_id,
+            device_name,
+            file_name,
+            file_size,
+            content_type,
+            dir_path,
+            client_path,
+            relative_path,
+            created_date: Utc::now(),
+            global,
+            owner,
+            base_hash,
+            modified,
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.created_date + Duration::hours(UPLOAD_SESSION_TTL_HOURS) < Utc::now()
+    }
+}
+
+// A chunk already stored for a session, reported back so clients can resume
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct StoredChunk {
+    pub offset: u64,
+    pub len: u64,
+}
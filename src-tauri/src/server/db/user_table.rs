@@ -0,0 +1,32 @@
+use chrono::Utc;
+use uuid::Uuid;
+
+use crate::server::utility::{gen_pin_hash, verify_pin_hash};
+
+/*  User account owning several devices, stored in the `user` table keyed
+   by the user name. Devices point at `uuid` through `Device.user`, which
+   also names the user's own global library and SurrealDB namespace
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct User {
+    pub uuid: String,
+    pub name: String,
+    // Argon2id PHC string of the password
+    hash: String,
+    pub created_date: chrono::DateTime<Utc>,
+}
+
+impl User {
+    pub fn new(name: String, password: &str) -> User {
+        User {
+            uuid: Uuid::new_v4().to_string(),
+            name,
+            hash: gen_pin_hash(password),
+            created_date: Utc::now(),
+        }
+    }
+
+    pub fn verify(&self, password: &str) -> bool {
+        verify_pin_hash(password, &self.hash)
+    }
+}
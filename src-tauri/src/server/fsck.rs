@@ -0,0 +1,556 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, OnceLock,
+    },
+    time::SystemTime,
+};
+
+use chrono::{DateTime, Utc};
+use tauri::Window;
+use tokio::sync::{RwLock, RwLockReadGuard};
+
+use super::{
+    api::utility::{get_documents_dir, record_change},
+    blob_store::{acquire_blob, get_blob_path, hash_file, ingest_blob},
+    db::{
+        change_table::ChangeKind,
+        local_table::{LocalEntry, SerializedMetadata},
+        repository::{all_libraries, Repository, RepositoryError},
+    },
+};
+
+/*
+   Filesystem / database consistency check
+
+   Compares every `LocalEntry` of every library table, including the ones
+   no device points at anymore, with the file it points at and looks for
+   files under `Documents/Aperture` which nothing points at. `Report` only
+   lists the findings, `Repair` also fixes them:
+
+   - missing files are re-pointed at their blob when it still exists,
+     otherwise every entry sharing the content is marked lost
+   - size / mtime mismatches are re-hashed, unchanged content only gets
+     its metadata refreshed, files without a content hash are moved into
+     the blob store and corrupted blobs are quarantined, which marks every
+     entry sharing them lost
+   - orphaned files are moved to `Documents/Aperture/.quarantine/<time>`
+
+   Lost entries are kept without a tombstone, so clients don't delete their
+   copies, and a `Lost` change asks them to push the content again.
+
+   Anything taking files into the blob store holds `hold_off_repairs` until
+   its entry is stored, a repair waits for those and blocks new ones while
+   it runs. Files written after the check started and blob store temp files
+   are never orphans.
+*/
+
+// Directories below `Documents/Aperture` which never hold library files
+const SKIPPED_DIRS: [&str; 4] = [".tls", ".staging", ".quarantine", "Archive"];
+const BACKGROUND_INTERVAL_HOURS: u64 = 24;
+
+// Only one check at a time, repairs of two runs would race each other
+static RUNNING: AtomicBool = AtomicBool::new(false);
+static STORE_LOCK: OnceLock<RwLock<()>> = OnceLock::new();
+
+fn store_lock() -> &'static RwLock<()> {
+    STORE_LOCK.get_or_init(|| RwLock::new(()))
+}
+
+/// Held from taking a file into the blob store until its entry is saved
+pub async fn hold_off_repairs() -> RwLockReadGuard<'static, ()> {
+    store_lock().read().await
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum FsckMode {
+    Report,
+    Repair,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+pub enum FsckProblem {
+    MissingFile,
+    SizeMismatch,
+    ModifiedMismatch,
+    Orphaned,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+pub enum FsckRepair {
+    Reindexed,
+    Rehashed,
+    MarkedLost,
+    Quarantined,
+    Failed,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct FsckIssue {
+    #[serde(rename = "Problem")]
+    pub problem: FsckProblem,
+    #[serde(rename = "Library")]
+    pub library: Option<String>,
+    #[serde(rename = "FileID")]
+    pub file_id: Option<String>,
+    #[serde(rename = "Path")]
+    pub path: String,
+    #[serde(rename = "Repair")]
+    pub repair: Option<FsckRepair>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct FsckReport {
+    #[serde(rename = "Mode")]
+    pub mode: FsckMode,
+    #[serde(rename = "Started")]
+    pub started: DateTime<Utc>,
+    #[serde(rename = "Finished")]
+    pub finished: DateTime<Utc>,
+    #[serde(rename = "EntriesChecked")]
+    pub entries_checked: usize,
+    #[serde(rename = "FilesScanned")]
+    pub files_scanned: usize,
+    #[serde(rename = "Issues")]
+    pub issues: Vec<FsckIssue>,
+}
+
+struct RunGuard;
+
+impl RunGuard {
+    fn acquire() -> Option<RunGuard> {
+        match RUNNING.swap(true, Ordering::SeqCst) {
+            true => None,
+            false => Some(RunGuard),
+        }
+    }
+}
+
+impl Drop for RunGuard {
+    fn drop(&mut self) {
+        RUNNING.store(false, Ordering::SeqCst);
+    }
+}
+
+fn refresh_metadata(entry: &mut LocalEntry, metadata: std::fs::Metadata) {
+    let mime = entry.metadata.file_type().and_then(|t| t.parse().ok());
+    let client_modified = entry.metadata.client_modified();
+    entry.file_size = metadata.len();
+    entry.metadata = SerializedMetadata::from(metadata, mime);
+    entry.metadata.set_client_modified(client_modified);
+}
+
+async fn quarantine(root: &Path, quarantine_dir: &Path, path: &Path) -> bool {
+    let target = match path.strip_prefix(root) {
+        Ok(relative) => quarantine_dir.join(relative),
+        Err(_e) => return false,
+    };
+    if let Some(parent) = target.parent() {
+        if let Err(e) = tokio::fs::create_dir_all(parent).await {
+            error!("Failed to create {:?}: {}", parent, e);
+            return false;
+        }
+    }
+    match tokio::fs::rename(path, &target).await {
+        Ok(()) => true,
+        Err(e) => {
+            error!("Failed to quarantine {:?}: {}", path, e);
+            false
+        }
+    }
+}
+
+// Marks the entry lost and tells the clients, see `LocalEntry::mark_lost`
+async fn lose_entry(
+    database: &dyn Repository,
+    library: &str,
+    file_id: &str,
+    mut entry: LocalEntry,
+) -> Result<(), RepositoryError> {
+    entry.mark_lost();
+    database.save_entry(library, file_id, &entry).await?;
+    record_change(
+        database,
+        library,
+        file_id,
+        ChangeKind::Lost,
+        &entry.relative_path,
+        &entry.file_name,
+    )
+    .await?;
+    Ok(())
+}
+
+// The content `hash` is gone for good, every entry of every library holding
+// it is marked lost and the blob record goes away with the references
+async fn lose_blob(
+    database: &dyn Repository,
+    libraries: &[String],
+    hash: &str,
+) -> Result<(), RepositoryError> {
+    for library in libraries {
+        for (file_id, entry) in database.list_entries(library).await? {
+            if entry.content_hash.as_deref() == Some(hash) {
+                lose_entry(database, library, &file_id, entry).await?;
+            }
+        }
+    }
+    database.delete_blob(hash).await
+}
+
+fn lost_result(result: Result<(), RepositoryError>, done: FsckRepair) -> FsckRepair {
+    match result {
+        Ok(()) => done,
+        Err(e) => {
+            error!("{e}");
+            FsckRepair::Failed
+        }
+    }
+}
+
+// Entries are listed before the repairs of a library start, re-read the
+// entry as a repair may have marked it lost since
+async fn is_lost(database: &dyn Repository, library: &str, file_id: &str) -> bool {
+    match database.get_entry(library, file_id).await {
+        Ok(Some(entry)) => entry.lost,
+        _ => false,
+    }
+}
+
+// False for blob store temp files and files written after the check
+// started, both belong to a store which is still in progress
+async fn settled(path: &Path, started: SystemTime) -> bool {
+    if path.extension().and_then(|e| e.to_str()) == Some("tmp") {
+        return false;
+    }
+    match tokio::fs::metadata(path).await.and_then(|m| m.modified()) {
+        Ok(modified) => modified <= started,
+        Err(_e) => false,
+    }
+}
+
+// Every file below `root` outside of the skipped directories
+fn walk_files(root: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(e) => e,
+            Err(e) => {
+                error!("Failed to read {:?}: {}", dir, e);
+                continue;
+            }
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            match entry.file_type() {
+                Ok(t) if t.is_dir() => {
+                    let skipped = dir == root
+                        && SKIPPED_DIRS
+                            .iter()
+                            .any(|s| entry.file_name().to_str() == Some(*s));
+                    if !skipped {
+                        pending.push(path);
+                    }
+                }
+                Ok(t) if t.is_file() => files.push(path),
+                _ => {}
+            }
+        }
+    }
+    files
+}
+
+// Checks a single entry, returns the problem and in repair mode what was done
+async fn check_entry(
+    database: &dyn Repository,
+    libraries: &[String],
+    library: &String,
+    file_id: &String,
+    mut entry: LocalEntry,
+    repair: bool,
+    root: &Path,
+    quarantine_dir: &Path,
+) -> Option<(FsckProblem, Option<FsckRepair>)> {
+    // Already known to be gone and waiting for a client
+    if entry.lost {
+        return None;
+    }
+
+    let location = PathBuf::from(&entry.file_location);
+    let metadata = match tokio::fs::metadata(&location).await {
+        Ok(m) if m.is_file() => Some(m),
+        _ => None,
+    };
+
+    let metadata = match metadata {
+        Some(m) => m,
+        None => {
+            if !repair {
+                return Some((FsckProblem::MissingFile, None));
+            }
+            // The blob may still be there when only the recorded path is stale
+            let blob_path = match &entry.content_hash {
+                Some(hash) => get_blob_path(hash).await.filter(|p| p.is_file()),
+                None => None,
+            };
+            let result = match blob_path {
+                Some(blob_path) => {
+                    entry.file_location = blob_path.to_string_lossy().to_string();
+                    if let Ok(m) = tokio::fs::metadata(&blob_path).await {
+                        refresh_metadata(&mut entry, m);
+                    }
+                    match database.save_entry(library, file_id, &entry).await {
+                        Ok(()) => FsckRepair::Reindexed,
+                        Err(e) => {
+                            error!("{e}");
+                            FsckRepair::Failed
+                        }
+                    }
+                }
+                None => {
+                    let lost = match entry.content_hash.clone() {
+                        Some(hash) => lose_blob(database, libraries, &hash).await,
+                        None => lose_entry(database, library, file_id, entry).await,
+                    };
+                    lost_result(lost, FsckRepair::MarkedLost)
+                }
+            };
+            return Some((FsckProblem::MissingFile, Some(result)));
+        }
+    };
+
+    // Compared through `SerializedMetadata` so the units match on every platform
+    let current = SerializedMetadata::from(metadata.clone(), None);
+    let problem = if current.len() != entry.metadata.len() || metadata.len() != entry.file_size {
+        FsckProblem::SizeMismatch
+    } else if current.modified() != entry.metadata.modified() {
+        FsckProblem::ModifiedMismatch
+    } else {
+        return None;
+    };
+    if !repair {
+        return Some((problem, None));
+    }
+
+    let result = match entry.content_hash.clone() {
+        // Stored before the blob store, take the file in as it is now
+        None => match ingest_blob(&location, true).await {
+            Some(blob) => {
+                // The entry keeps pointing at the old file rather than at a
+                // blob nothing holds a reference to
+                if let Err(e) = acquire_blob(database, &blob).await {
+                    error!("{e}");
+                    return Some((problem, Some(FsckRepair::Failed)));
+                }
+                entry.file_location = blob.location.to_string_lossy().to_string();
+                entry.content_hash = Some(blob.hash.clone());
+                if let Ok(m) = tokio::fs::metadata(&blob.location).await {
+                    refresh_metadata(&mut entry, m);
+                }
+                match database.save_entry(library, file_id, &entry).await {
+                    Ok(()) => FsckRepair::Rehashed,
+                    Err(e) => {
+                        error!("{e}");
+                        FsckRepair::Failed
+                    }
+                }
+            }
+            None => FsckRepair::Failed,
+        },
+        Some(content_hash) => match hash_file(&location).await {
+            // Same content, only the recorded metadata was off
+            Ok((hash, _size)) if hash == content_hash => {
+                refresh_metadata(&mut entry, metadata);
+                match database.save_entry(library, file_id, &entry).await {
+                    Ok(()) => FsckRepair::Rehashed,
+                    Err(e) => {
+                        error!("{e}");
+                        FsckRepair::Failed
+                    }
+                }
+            }
+            // A blob never changes, different content means it is corrupted
+            Ok(_hash) => {
+                warn!("Blob {} is corrupted, quarantining it", content_hash);
+                match quarantine(root, quarantine_dir, &location).await {
+                    true => {
+                        let lost = lose_blob(database, libraries, &content_hash).await;
+                        lost_result(lost, FsckRepair::Quarantined)
+                    }
+                    false => FsckRepair::Failed,
+                }
+            }
+            Err(e) => {
+                error!("Failed to hash {:?}: {}", location, e);
+                FsckRepair::Failed
+            }
+        },
+    };
+    Some((problem, Some(result)))
+}
+
+/// Runs the consistency check over every library, `None` while another
+/// check is still running
+pub async fn run_fsck(database: &dyn Repository, mode: FsckMode) -> Option<FsckReport> {
+    let _guard = RunGuard::acquire()?;
+    let repair = mode == FsckMode::Repair;
+    let _stores = match repair {
+        true => Some(store_lock().write().await),
+        false => None,
+    };
+    let started = Utc::now();
+    let mut report = FsckReport {
+        mode,
+        started,
+        finished: started,
+        entries_checked: 0,
+        files_scanned: 0,
+        issues: Vec::new(),
+    };
+
+    let root = match get_documents_dir().await {
+        Ok(dir) => dir.join("Aperture"),
+        Err(e) => {
+            error!("Failed to get documents directory: {}", e);
+            return Some(report);
+        }
+    };
+    let quarantine_dir = root
+        .join(".quarantine")
+        .join(started.format("%Y%m%d%H%M%S").to_string());
+
+    let libraries = match all_libraries(database).await {
+        Ok(l) => l,
+        Err(e) => {
+            error!("{e}");
+            return Some(report);
+        }
+    };
+
+    let mut referenced: HashSet<PathBuf> = HashSet::new();
+    for library in &libraries {
+        let entries = match database.list_entries(library).await {
+            Ok(e) => e,
+            Err(e) => {
+                error!("Failed to read library {}: {}", library, e);
+                continue;
+            }
+        };
+        for (file_id, entry) in entries {
+            report.entries_checked += 1;
+            let path = entry.file_location.clone();
+            // Lost by a repair of another entry sharing the content
+            if repair && is_lost(database, library, &file_id).await {
+                continue;
+            }
+            let result = check_entry(
+                database,
+                &libraries,
+                library,
+                &file_id,
+                entry,
+                repair,
+                &root,
+                &quarantine_dir,
+            )
+            .await;
+            if let Some((problem, repair)) = result {
+                report.issues.push(FsckIssue {
+                    problem,
+                    library: Some(library.clone()),
+                    file_id: Some(file_id),
+                    path: path.clone(),
+                    repair,
+                });
+            }
+            referenced.insert(PathBuf::from(path));
+        }
+    }
+
+    // Repairs may have moved files into the blob store, re-read the
+    // references before deciding what is orphaned
+    if repair {
+        referenced.clear();
+        for library in all_libraries(database).await.unwrap_or_default() {
+            for (_id, entry) in database.list_entries(&library).await.unwrap_or_default() {
+                referenced.insert(PathBuf::from(entry.file_location));
+            }
+        }
+    }
+
+    let scan_root = root.clone();
+    let files = tokio::task::spawn_blocking(move || walk_files(&scan_root))
+        .await
+        .unwrap_or_default();
+    report.files_scanned = files.len();
+    let scan_started = SystemTime::from(started);
+    for path in files.into_iter().filter(|p| !referenced.contains(p)) {
+        if !settled(&path, scan_started).await {
+            continue;
+        }
+        let repair = match repair {
+            true => match quarantine(&root, &quarantine_dir, &path).await {
+                true => {
+                    // Orphaned blobs can still have a stale record
+                    if let Some(hash) = path.file_name().and_then(|n| n.to_str()) {
+                        if path.parent().and_then(|p| p.parent())
+                            == Some(root.join(".blobs").as_path())
+                        {
+                            if let Err(e) = database.delete_blob(hash).await {
+                                error!("{e}");
+                            }
+                        }
+                    }
+                    Some(FsckRepair::Quarantined)
+                }
+                false => Some(FsckRepair::Failed),
+            },
+            false => None,
+        };
+        report.issues.push(FsckIssue {
+            problem: FsckProblem::Orphaned,
+            library: None,
+            file_id: None,
+            path: path.to_string_lossy().to_string(),
+            repair,
+        });
+    }
+
+    report.finished = Utc::now();
+    info!(
+        "Consistency check ({:?}) : {} entries, {} files, {} issues",
+        mode,
+        report.entries_checked,
+        report.files_scanned,
+        report.issues.len()
+    );
+    Some(report)
+}
+
+/// Runs a report-only check every `BACKGROUND_INTERVAL_HOURS`, findings are
+/// emitted as `fsck-report` to the desktop window
+pub fn spawn_background_check(database: Arc<dyn Repository>, window: Window) {
+    tauri::async_runtime::spawn(async move {
+        let period = std::time::Duration::from_secs(BACKGROUND_INTERVAL_HOURS * 60 * 60);
+        let mut interval = tokio::time::interval(period);
+        // The first tick fires immediately, leave startup alone
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let report = match run_fsck(database.as_ref(), FsckMode::Report).await {
+                Some(r) => r,
+                None => continue,
+            };
+            if report.issues.is_empty() {
+                continue;
+            }
+            warn!("Consistency check found {} issues", report.issues.len());
+            if let Err(e) = window.emit("fsck-report", report) {
+                error!("Failed to emit fsck report: {}", e);
+            }
+        }
+    });
+}
@@ -0,0 +1,259 @@
+use std::{net::IpAddr, sync::OnceLock};
+
+use chrono::{DateTime, Duration, Utc};
+use tauri::Window;
+
+use super::db::{
+    attempt_table::FailedAttempts,
+    repository::{Repository, RepositoryError},
+};
+
+/*
+   Brute force protection for PIN and user password verification
+
+   Attempts are counted per device or user account and per source address
+   before the credentials are checked, a success removes the count again.
+   After
+   `FREE_ATTEMPTS` failures every further one locks the key for
+   `BASE_LOCKOUT_SECS * 2^n`, capped at `MAX_LOCKOUT_SECS`.
+*/
+
+const FREE_ATTEMPTS: u32 = 3;
+const BASE_LOCKOUT_SECS: i64 = 30;
+const MAX_LOCKOUT_SECS: i64 = 60 * 60;
+// Failures older than this no longer count towards a lockout
+const FAILURE_WINDOW_HOURS: i64 = 24;
+
+#[derive(Clone, serde::Serialize)]
+struct LockoutEvent {
+    device_id: Option<String>,
+    user: Option<String>,
+    source: String,
+    failures: u32,
+    locked_until: DateTime<Utc>,
+}
+
+// Whose credentials an attempt checks, each has its own attempt records
+#[derive(Debug, Clone, Copy)]
+pub enum Subject<'a> {
+    Device(&'a str),
+    // User name, for the account password
+    User(&'a str),
+}
+
+fn keys(subject: Subject, source: &IpAddr) -> [String; 2] {
+    let subject = match subject {
+        Subject::Device(device_id) => FailedAttempts::device_key(device_id),
+        Subject::User(name) => FailedAttempts::user_key(name),
+    };
+    [subject, FailedAttempts::ip_key(source)]
+}
+
+// Serializes the read-modify-write of the attempt records, so parallel
+// requests each count as their own attempt
+static ATTEMPT_LOCK: OnceLock<tokio::sync::Mutex<()>> = OnceLock::new();
+
+async fn lock_attempts() -> tokio::sync::MutexGuard<'static, ()> {
+    ATTEMPT_LOCK
+        .get_or_init(|| tokio::sync::Mutex::new(()))
+        .lock()
+        .await
+}
+
+// A lockout started by an attempt, it only takes effect for the client
+// once that attempt failed
+pub struct Lockout {
+    pub until: DateTime<Utc>,
+    failures: u32,
+}
+
+pub enum Attempt {
+    // Refused without looking at the credentials
+    Locked(DateTime<Utc>),
+    // May be verified, `Some` when this attempt began a new lockout
+    Allowed(Option<Lockout>),
+}
+
+/// Counts an attempt before its credentials are verified. While the subject
+/// or the source address is locked the attempt is refused and not counted,
+/// otherwise it is counted as a failure up front and cleared again by
+/// `record_success`
+pub async fn begin_attempt(
+    database: &dyn Repository,
+    subject: Subject<'_>,
+    source: &IpAddr,
+) -> Result<Attempt, RepositoryError> {
+    let _guard = lock_attempts().await;
+    let now = Utc::now();
+
+    let mut counted = Vec::new();
+    for key in keys(subject, source) {
+        let attempts = match database.get_attempts(&key).await? {
+            Some(a) if now - a.last_failure < Duration::hours(FAILURE_WINDOW_HOURS) => a,
+            _ => FailedAttempts::new(),
+        };
+        if let Some(until) = attempts.locked_until.filter(|until| *until > now) {
+            return Ok(Attempt::Locked(until));
+        }
+        counted.push((key, attempts));
+    }
+
+    let mut lockout: Option<Lockout> = None;
+    for (key, mut attempts) in counted {
+        attempts.failures += 1;
+        attempts.last_failure = now;
+
+        if attempts.failures > FREE_ATTEMPTS {
+            let exponent = (attempts.failures - FREE_ATTEMPTS - 1).min(16);
+            let secs = (BASE_LOCKOUT_SECS << exponent).min(MAX_LOCKOUT_SECS);
+            let until = now + Duration::seconds(secs);
+            attempts.locked_until = Some(until);
+            if lockout.as_ref().map_or(true, |l| l.until < until) {
+                lockout = Some(Lockout {
+                    until,
+                    failures: attempts.failures,
+                });
+            }
+        }
+
+        database.save_attempts(&key, &attempts).await?;
+    }
+    Ok(Attempt::Allowed(lockout))
+}
+
+/// Reports a failed attempt, returns whether it left the client locked out.
+/// Lockouts are also emitted as `pin-lockout` to the desktop window
+pub fn attempt_failed(
+    window: &Window,
+    subject: Subject<'_>,
+    source: &IpAddr,
+    lockout: Option<Lockout>,
+) -> bool {
+    let lockout = match lockout {
+        Some(l) => l,
+        None => return false,
+    };
+
+    warn!(
+        "Verification locked for {:?} from {} until {}",
+        subject, source, lockout.until
+    );
+    let (device_id, user) = match subject {
+        Subject::Device(device_id) => (Some(device_id.to_string()), None),
+        Subject::User(name) => (None, Some(name.to_string())),
+    };
+    let event = LockoutEvent {
+        device_id,
+        user,
+        source: source.to_string(),
+        failures: lockout.failures,
+        locked_until: lockout.until,
+    };
+    if let Err(e) = window.emit("pin-lockout", event) {
+        error!("Failed to emit lockout event: {}", e);
+    }
+    true
+}
+
+// Forgets the failures of the subject and the source address
+pub async fn record_success(
+    database: &dyn Repository,
+    subject: Subject<'_>,
+    source: &IpAddr,
+) -> Result<(), RepositoryError> {
+    let _guard = lock_attempts().await;
+    for key in keys(subject, source) {
+        database.delete_attempts(&key).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::server::db::memory_repository::MemoryRepository;
+
+    const PHONE: Subject<'static> = Subject::Device("phone");
+
+    fn source() -> IpAddr {
+        "192.168.1.20".parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn parallel_attempts_are_all_counted() {
+        let database = Arc::new(MemoryRepository::default());
+        let tasks: Vec<_> = (0..12)
+            .map(|_i| {
+                let database = database.clone();
+                tokio::spawn(async move {
+                    let attempt = begin_attempt(database.as_ref(), PHONE, &source()).await;
+                    matches!(attempt, Ok(Attempt::Allowed(_)))
+                })
+            })
+            .collect();
+
+        let mut allowed = 0;
+        for task in tasks {
+            if task.await.unwrap() {
+                allowed += 1;
+            }
+        }
+        // The free attempts plus the one which starts the lockout
+        assert_eq!(allowed, FREE_ATTEMPTS + 1);
+    }
+
+    #[tokio::test]
+    async fn attempt_after_the_free_ones_starts_a_lockout() {
+        let database = MemoryRepository::default();
+        for _i in 0..FREE_ATTEMPTS {
+            let attempt = begin_attempt(&database, PHONE, &source()).await.unwrap();
+            assert!(matches!(attempt, Attempt::Allowed(None)));
+        }
+
+        let attempt = begin_attempt(&database, PHONE, &source()).await.unwrap();
+        assert!(matches!(attempt, Attempt::Allowed(Some(_))));
+        let attempt = begin_attempt(&database, PHONE, &source()).await.unwrap();
+        assert!(matches!(attempt, Attempt::Locked(_)));
+    }
+
+    #[tokio::test]
+    async fn success_clears_the_count() {
+        let database = MemoryRepository::default();
+        for _i in 0..FREE_ATTEMPTS {
+            begin_attempt(&database, PHONE, &source()).await.unwrap();
+        }
+        record_success(&database, PHONE, &source()).await.unwrap();
+
+        let attempt = begin_attempt(&database, PHONE, &source()).await.unwrap();
+        assert!(matches!(attempt, Attempt::Allowed(None)));
+        let attempts = database
+            .get_attempts(&FailedAttempts::device_key("phone"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(attempts.failures, 1);
+    }
+
+    #[tokio::test]
+    async fn users_and_devices_are_counted_apart() {
+        let database = MemoryRepository::default();
+        for _i in 0..=FREE_ATTEMPTS {
+            begin_attempt(&database, Subject::User("alice"), &source())
+                .await
+                .unwrap();
+        }
+        let attempt = begin_attempt(&database, Subject::User("alice"), &source())
+            .await
+            .unwrap();
+        assert!(matches!(attempt, Attempt::Locked(_)));
+
+        // A device whose id looks like the user's key, from another address
+        let other: IpAddr = "192.168.1.21".parse().unwrap();
+        let attempt = begin_attempt(&database, Subject::Device("user_alice"), &other)
+            .await
+            .unwrap();
+        assert!(matches!(attempt, Attempt::Allowed(None)));
+    }
+}
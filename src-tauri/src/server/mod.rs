@@ -1,8 +1,18 @@
 mod api;
+mod auth;
+pub mod backup;
+mod blob_store;
 pub mod db;
+pub mod fsck;
+mod lockout;
+pub mod pairing;
+pub mod pin;
+pub mod revocation;
+pub mod tls;
 mod utility;
 
-use db::middleware::DbMiddleware;
+use auth::{AuthError, AuthMiddleware};
+use db::{db_instance::DbInstance, db_instance::SharedDb, middleware::DbMiddleware};
 use rocket::{
     figment::{
         providers::{Format, Toml},
@@ -12,7 +22,11 @@ use rocket::{
     response::content::RawHtml,
     Build, Config,
 };
+use std::sync::Arc;
+
+use pairing::PairingState;
 use tauri::Window;
+use tls::TlsPaths;
 
 static_response_handler! {
     "/favicon.ico" => favicon => "favicon",
@@ -29,24 +43,48 @@ fn not_found(req: &Request) -> String {
     format!("Oh no! We couldn't find the requested path '{}'", req.uri())
 }
 
+#[catch(403)]
+fn forbidden(req: &Request) -> String {
+    match req.local_cache(|| None::<AuthError>) {
+        Some(AuthError::ReadOnly) => {
+            "This device is read-only, pushing, modifying and deleting files is not allowed"
+                .to_string()
+        }
+        _ => format!("Access to '{}' is forbidden", req.uri()),
+    }
+}
+
 #[get("/")]
 fn index() -> RawHtml<&'static str> {
     return RawHtml("<html><head><title> hello </title></head><body>Jello</body></html>");
 }
 
-fn rocket(window: Window) -> rocket::Rocket<Build> {
-    let figment = Figment::from(Config::default())
+fn rocket(
+    window: Window,
+    pairing: Arc<PairingState>,
+    tls: Option<TlsPaths>,
+) -> rocket::Rocket<Build> {
+    let mut figment = Figment::from(Config::default());
+    // Serve HTTPS with the generated certificate unless configured otherwise
+    if let Some(tls) = tls {
+        figment = figment
+            .merge(("tls.certs", tls.cert))
+            .merge(("tls.key", tls.key));
+    }
+    let figment = figment
         .merge(Toml::file("Rocket.toml").nested())
         .merge(Toml::file("App.toml").nested());
 
     let build = rocket::custom(figment)
         .attach(DbMiddleware)
+        .attach(AuthMiddleware)
         .attach(static_resources_initializer!(
             "favicon" => "assets/favicon.ico",
             "favicon-png" => "assets/favicon-32x32.png",
         ))
-        .register("/", catchers![not_found])
+        .register("/", catchers![not_found, forbidden])
         .manage(window)
+        .manage(pairing)
         .mount("/", routes![favicon, favicon_png])
         .mount("/", routes![index])
         .mount(
@@ -54,18 +92,79 @@ fn rocket(window: Window) -> rocket::Rocket<Build> {
             routes![
                 api::sync::connect,
                 api::sync::server_sync,
-                api::sync::sync_database
+                api::sync::sync_database,
+                api::sync::sync_manifest,
+                api::sync::sync_changes,
+                api::sync::sync_ack
+            ],
+        )
+        .mount(
+            "/auth",
+            routes![
+                api::auth::login,
+                api::auth::change_pin,
+                api::auth::refresh,
+                api::auth::logout
+            ],
+        )
+        .mount(
+            "/grant",
+            routes![
+                api::grant::create_grant,
+                api::grant::remove_grant,
+                api::grant::list_grants
             ],
         )
-        .mount("/pull", routes![api::pull::pull_file])
-        .mount("/push", routes![api::push::push_file])
-        .mount("/modify", routes![api::modify::modfiy_device]);
+        .mount(
+            "/user",
+            routes![
+                api::user::register_user,
+                api::user::join_user,
+                api::user::leave_user,
+                api::user::user_info
+            ],
+        )
+        .mount("/pull", routes![api::pull::pull_file, api::pull::pull_folder])
+        .mount(
+            "/push",
+            routes![
+                api::push::push_file,
+                api::push::push_hash,
+                api::push::push_folder,
+                api::push::open_upload_session,
+                api::push::push_chunk,
+                api::push::upload_session_status,
+                api::push::commit_upload_session
+            ],
+        )
+        .mount(
+            "/modify",
+            routes![
+                api::modify::modfiy_device,
+                api::modify::modify_file,
+                api::modify::delete_file,
+                api::modify::revoke_device
+            ],
+        );
     return build;
 }
 
-pub fn run(window: Window) {
+pub fn run(window: Window, pairing: Arc<PairingState>, shared_db: Arc<SharedDb>) {
     tauri::async_runtime::spawn(async move {
-        let _rocket = rocket(window);
+        let tls = tls::ensure_certificate().await;
+        if tls.is_none() {
+            warn!("No TLS certificate available, serving plain HTTP");
+        }
+        let fsck_window = window.clone();
+        let _rocket = rocket(window, pairing.clone(), tls);
+        pairing.set_port(_rocket.figment().extract_inner("port").unwrap_or(8000));
+        let _rocket = _rocket.ignite().await?;
+        // Hand the database to the Tauri commands
+        if let Some(db) = _rocket.state::<DbInstance>() {
+            shared_db.set(db.database.clone()).await;
+            fsck::spawn_background_check(db.database.clone(), fsck_window);
+            api::push::spawn_upload_cleanup(db.database.clone());
+        }
         _rocket.launch().await
     });
 }
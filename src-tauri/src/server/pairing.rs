@@ -0,0 +1,171 @@
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU16, Ordering},
+};
+
+use chrono::{DateTime, Duration, Utc};
+use rand::Rng;
+use tauri::Window;
+use tokio::sync::{oneshot, Mutex};
+use uuid::Uuid;
+
+use super::tls::get_fingerprint;
+
+/*
+   Pairing mode started from the desktop app
+
+   While pairing is active `/sync/connect` only registers devices which
+   present the one-time code. With approval enabled every registration is
+   also sent to the window as `pairing-request` and waits for
+   `respond_pairing` from the desktop user.
+*/
+
+const CODE_MINUTES: i64 = 5;
+const APPROVAL_TIMEOUT_SECS: u64 = 60;
+// Wrong codes allowed before the pairing code is thrown away
+const MAX_CODE_FAILURES: u32 = 5;
+
+struct PairingCode {
+    code: String,
+    expires: DateTime<Utc>,
+    require_approval: bool,
+    failures: u32,
+}
+
+// Shared between the Tauri commands and the Rocket handlers
+#[derive(Default)]
+pub struct PairingState {
+    port: AtomicU16,
+    active: Mutex<Option<PairingCode>>,
+    pending: Mutex<HashMap<String, oneshot::Sender<bool>>>,
+}
+
+// What the desktop shows as text and encodes in the QR code
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct PairingPayload {
+    pub host: String,
+    pub port: u16,
+    pub code: String,
+    pub fingerprint: Option<String>,
+    pub expires: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct PairingRequest {
+    pub request_id: String,
+    pub device_id: String,
+    pub device_name: String,
+    pub os: String,
+    pub remote_address: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PairingError {
+    NotActive,
+    InvalidCode,
+    Rejected,
+}
+
+impl PairingState {
+    pub fn set_port(&self, port: u16) {
+        self.port.store(port, Ordering::Relaxed);
+    }
+
+    /// Starts pairing mode with a fresh code, replacing any previous one
+    pub async fn start(&self, host: String, require_approval: bool) -> PairingPayload {
+        let code = format!("{:06}", rand::thread_rng().gen_range(0..1_000_000));
+        let expires = Utc::now() + Duration::minutes(CODE_MINUTES);
+        *self.active.lock().await = Some(PairingCode {
+            code: code.clone(),
+            expires,
+            require_approval,
+            failures: 0,
+        });
+
+        PairingPayload {
+            host,
+            port: self.port.load(Ordering::Relaxed),
+            code,
+            fingerprint: get_fingerprint().await,
+            expires,
+        }
+    }
+
+    pub async fn stop(&self) {
+        *self.active.lock().await = None;
+    }
+
+    /// Checks and consumes the one-time code. Returns whether the desktop
+    /// has to approve the registration
+    pub async fn redeem(&self, code: &str) -> Result<bool, PairingError> {
+        let mut active = self.active.lock().await;
+        let pairing = match active.as_mut() {
+            Some(p) if p.expires > Utc::now() => p,
+            _ => {
+                *active = None;
+                return Err(PairingError::NotActive);
+            }
+        };
+
+        if pairing.code != code {
+            pairing.failures += 1;
+            if pairing.failures >= MAX_CODE_FAILURES {
+                warn!("Too many wrong pairing codes, pairing stopped");
+                *active = None;
+            }
+            return Err(PairingError::InvalidCode);
+        }
+
+        let require_approval = pairing.require_approval;
+        *active = None;
+        Ok(require_approval)
+    }
+
+    /// Asks the desktop user to approve the registration, rejected when
+    /// nobody answers in time
+    pub async fn request_approval(
+        &self,
+        window: &Window,
+        device_id: String,
+        device_name: String,
+        os: String,
+        remote_address: String,
+    ) -> Result<(), PairingError> {
+        let request = PairingRequest {
+            request_id: Uuid::new_v4().to_string(),
+            device_id,
+            device_name,
+            os,
+            remote_address,
+        };
+        let (sender, receiver) = oneshot::channel();
+        self.pending
+            .lock()
+            .await
+            .insert(request.request_id.clone(), sender);
+
+        if let Err(e) = window.emit("pairing-request", request.clone()) {
+            error!("Failed to emit pairing request: {}", e);
+        }
+
+        let answer = tokio::time::timeout(
+            std::time::Duration::from_secs(APPROVAL_TIMEOUT_SECS),
+            receiver,
+        )
+        .await;
+        self.pending.lock().await.remove(&request.request_id);
+
+        match answer {
+            Ok(Ok(true)) => Ok(()),
+            _ => Err(PairingError::Rejected),
+        }
+    }
+
+    // Delivers the desktop user's answer, false if the request is gone
+    pub async fn respond(&self, request_id: &str, approved: bool) -> bool {
+        match self.pending.lock().await.remove(request_id) {
+            Some(sender) => sender.send(approved).is_ok(),
+            None => false,
+        }
+    }
+}
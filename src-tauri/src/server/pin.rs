@@ -0,0 +1,79 @@
+use rand::Rng;
+use super::db::{attempt_table::FailedAttempts, repository::Repository};
+
+/*
+   PIN changes
+
+   Changing the PIN rewrites the `hash` record and ends every session of
+   the device. A reset from the desktop sets a temporary PIN which has to
+   be changed through `/auth/pin` before the device can log in again.
+*/
+
+pub const MIN_PIN_LENGTH: usize = 4;
+const TEMPORARY_PIN_DIGITS: u32 = 6;
+
+pub fn is_valid_pin(pin: &str) -> bool {
+    pin.chars().count() >= MIN_PIN_LENGTH
+}
+
+/// Stores a new Argon2id hash for the device's PIN and logs out its sessions
+pub async fn set_device_pin(
+    database: &dyn Repository,
+    device_id: &str,
+    pin: String,
+    must_change: bool,
+) -> Result<(), &'static str> {
+    let device_hash = match database.get_hash(device_id).await {
+        Ok(Some(h)) => h,
+        Ok(None) => return Err("Device not found"),
+        Err(e) => {
+            error!("{e}");
+            return Err("Error: reading device hash");
+        }
+    };
+
+    let device_hash = tokio::task::spawn_blocking(move || {
+        let mut device_hash = device_hash;
+        device_hash.set_pin(&pin, must_change);
+        device_hash
+    })
+    .await;
+    let device_hash = match device_hash {
+        Ok(h) => h,
+        Err(e) => {
+            error!("{e}");
+            return Err("Error: hashing PIN");
+        }
+    };
+
+    if let Err(e) = database.save_hash(device_id, &device_hash).await {
+        error!("{e}");
+        return Err("Error: storing device hash");
+    }
+
+    if let Err(e) = database.delete_sessions(device_id).await {
+        error!("Failed to remove sessions : {e}");
+    }
+    let r = database
+        .delete_attempts(&FailedAttempts::device_key(device_id))
+        .await;
+    if let Err(e) = r {
+        error!("Failed to clear failed attempts : {e}");
+    }
+    Ok(())
+}
+
+/// Sets a random temporary PIN which must be changed on the next connect
+pub async fn reset_device_pin(
+    database: &dyn Repository,
+    device_id: &str,
+) -> Result<String, &'static str> {
+    let pin = format!(
+        "{:0width$}",
+        rand::thread_rng().gen_range(0..10u32.pow(TEMPORARY_PIN_DIGITS)),
+        width = TEMPORARY_PIN_DIGITS as usize
+    );
+    set_device_pin(database, device_id, pin.clone(), true).await?;
+    warn!("PIN reset for {}", device_id);
+    Ok(pin)
+}
@@ -0,0 +1,378 @@
+use std::{
+    fmt,
+    path::{Component, Path, PathBuf},
+};
+
+use chrono::Utc;
+
+use super::{
+    api::utility::{get_documents_dir, get_staging_dir},
+    blob_store::release_blob,
+    db::{
+        attempt_table::FailedAttempts, local_table::LocalEntry, repository::Repository,
+        REVOKED_PREFIX,
+    },
+};
+
+/*
+   Device revocation
+
+   Removes the device, its PIN hash, sessions, pending uploads and failed
+   attempts. Sessions are checked on every request so revoked devices are
+   locked out immediately. The stored files are handled as chosen by the
+   desktop user or admin. Kept files move to a library table of their own,
+   a device pairing again with the same id starts empty.
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum RevokedFiles {
+    // Entries move to a `revoked_<device id>_<timestamp>` table, files stay
+    Keep,
+    Delete,
+    // Copied to `Documents/Aperture/Archive/<device name>_<device id>` then deleted
+    Archive,
+}
+
+impl std::str::FromStr for RevokedFiles {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Keep" => Ok(RevokedFiles::Keep),
+            "Delete" => Ok(RevokedFiles::Delete),
+            "Archive" => Ok(RevokedFiles::Archive),
+            _ => Err("Unknown file handling, expected Keep, Delete or Archive"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RevocationError {
+    NotFound,
+    Failed(&'static str),
+}
+
+impl fmt::Display for RevocationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RevocationError::NotFound => write!(f, "Device not found"),
+            RevocationError::Failed(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for RevocationError {}
+
+impl From<&'static str> for RevocationError {
+    fn from(e: &'static str) -> Self {
+        RevocationError::Failed(e)
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct Revocation {
+    #[serde(rename = "DeviceID")]
+    pub device_id: String,
+    #[serde(rename = "Files")]
+    pub files: RevokedFiles,
+    #[serde(rename = "Removed")]
+    pub removed: usize,
+    #[serde(rename = "Archived")]
+    pub archived: usize,
+    #[serde(rename = "ArchivePath")]
+    pub archive_path: Option<String>,
+    #[serde(rename = "KeptTable")]
+    pub kept_table: Option<String>,
+}
+
+// Only plain components of client supplied paths end up in the archive
+fn sanitize(path: &str) -> PathBuf {
+    Path::new(path)
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(p) => Some(p),
+            _ => None,
+        })
+        .collect()
+}
+
+async fn archive_entries(
+    device_id: &str,
+    device_name: &str,
+    entries: &[LocalEntry],
+) -> Result<(PathBuf, usize), &'static str> {
+    let archive_dir = match get_documents_dir().await {
+        Ok(dir) => dir
+            .join("Aperture")
+            .join("Archive")
+            .join(sanitize(&format!("{device_name}_{device_id}"))),
+        Err(e) => {
+            error!("Failed to get documents directory: {}", e);
+            return Err("Error: archive directory not available");
+        }
+    };
+
+    let mut archived = 0;
+    for entry in entries.iter().filter(|e| !e.lost) {
+        let target_dir = archive_dir.join(sanitize(&entry.relative_path));
+        if let Err(e) = tokio::fs::create_dir_all(&target_dir).await {
+            error!("Failed to create {:?}: {}", target_dir, e);
+            return Err("Error: creating archive directory");
+        }
+        let target = target_dir.join(sanitize(&entry.file_name));
+        if let Err(e) = tokio::fs::copy(&entry.file_location, &target).await {
+            error!("Failed to archive {}: {}", entry.file_location, e);
+            return Err("Error: archiving file");
+        }
+        archived += 1;
+    }
+    Ok((archive_dir, archived))
+}
+
+async fn remove_entries(database: &dyn Repository, device_id: &str) -> Result<usize, &'static str> {
+    let entries = match database.delete_library(device_id).await {
+        Ok(e) => e,
+        Err(e) => {
+            error!("{e}");
+            return Err("Error: removing the entries from database");
+        }
+    };
+
+    for entry in &entries {
+        match &entry.content_hash {
+            Some(hash) => {
+                if let Err(e) = release_blob(database, hash).await {
+                    error!("{e}");
+                }
+            }
+            None if entry.lost => {}
+            None => {
+                if let Err(e) = tokio::fs::remove_file(&entry.file_location).await {
+                    error!("Failed to remove file {}: {}", entry.file_location, e);
+                }
+            }
+        }
+    }
+    Ok(entries.len())
+}
+
+// Moves the entries to a new table, the blob references move with them
+async fn keep_entries(database: &dyn Repository, device_id: &str) -> Result<String, &'static str> {
+    let kept_table = format!("{REVOKED_PREFIX}{device_id}_{}", Utc::now().timestamp());
+    let entries = match database.list_entries(device_id).await {
+        Ok(e) => e,
+        Err(e) => {
+            error!("{e}");
+            return Err("Error: reading entries");
+        }
+    };
+
+    if let Err(e) = database.prepare_library(&kept_table).await {
+        error!("{e}");
+        return Err("Error: creating table for kept entries");
+    }
+    for (file_id, entry) in &entries {
+        if let Err(e) = database.save_entry(&kept_table, file_id, entry).await {
+            error!("{e}");
+            let _r = database.delete_library(&kept_table).await;
+            return Err("Error: moving kept entries");
+        }
+    }
+    if let Err(e) = database.delete_library(device_id).await {
+        error!("{e}");
+        return Err("Error: removing the entries from database");
+    }
+    Ok(kept_table)
+}
+
+/// Revokes the device and handles its files according to `files`
+pub async fn revoke_device(
+    database: &dyn Repository,
+    device_id: &str,
+    files: RevokedFiles,
+) -> Result<Revocation, RevocationError> {
+    let device = match database.get_device(device_id).await {
+        Ok(Some(d)) => d,
+        Ok(None) => return Err(RevocationError::NotFound),
+        Err(e) => {
+            error!("{e}");
+            return Err(RevocationError::Failed("Error: reading device"));
+        }
+    };
+
+    // Files first, a failed archive leaves the device untouched
+    let mut revocation = Revocation {
+        device_id: device_id.to_string(),
+        files,
+        removed: 0,
+        archived: 0,
+        archive_path: None,
+        kept_table: None,
+    };
+    if files == RevokedFiles::Archive {
+        let entries: Vec<LocalEntry> = match database.list_entries(device_id).await {
+            Ok(e) => e.into_iter().map(|(_id, entry)| entry).collect(),
+            Err(e) => {
+                error!("{e}");
+                return Err(RevocationError::Failed("Error: reading entries"));
+            }
+        };
+        let (archive_path, archived) = archive_entries(device_id, &device.name, &entries).await?;
+        revocation.archived = archived;
+        revocation.archive_path = Some(archive_path.to_string_lossy().to_string());
+    }
+    if files == RevokedFiles::Keep {
+        revocation.kept_table = Some(keep_entries(database, device_id).await?);
+    } else {
+        revocation.removed = remove_entries(database, device_id).await?;
+    }
+
+    match database.delete_uploads(device_id).await {
+        Ok(uploads) => {
+            for upload in uploads {
+                if let Some(staging_dir) = get_staging_dir(&upload.session_id).await {
+                    let _r = tokio::fs::remove_dir_all(staging_dir).await;
+                }
+            }
+        }
+        Err(e) => error!("Failed to remove upload sessions : {e}"),
+    }
+
+    if let Err(e) = database.delete_sessions(device_id).await {
+        error!("{e}");
+        return Err(RevocationError::Failed("Error: removing sessions"));
+    }
+    if let Err(e) = database.delete_tombstones(device_id).await {
+        error!("{e}");
+        return Err(RevocationError::Failed("Error: removing tombstones"));
+    }
+    if let Err(e) = database.delete_changes(device_id).await {
+        error!("{e}");
+        return Err(RevocationError::Failed("Error: removing change log"));
+    }
+    if let Err(e) = database.delete_grants(device_id).await {
+        error!("{e}");
+        return Err(RevocationError::Failed("Error: removing access grants"));
+    }
+
+    if let Err(e) = database.delete_hash(device_id).await {
+        error!("{e}");
+        return Err(RevocationError::Failed("Error: removing device hash"));
+    }
+    let attempts = database
+        .delete_attempts(&FailedAttempts::device_key(device_id))
+        .await;
+    if let Err(e) = attempts {
+        error!("{e}");
+    }
+    if let Err(e) = database.delete_device(device_id).await {
+        error!("{e}");
+        return Err(RevocationError::Failed("Error: removing device"));
+    }
+
+    warn!("Device revoked : {} ({:?})", device_id, files);
+    Ok(revocation)
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::server::db::{
+        device_table::Device,
+        is_reserved_table,
+        memory_repository::MemoryRepository,
+        repository::{all_libraries, RepositoryError},
+        OS,
+    };
+
+    async fn paired_device(database: &MemoryRepository, device_id: &str) -> PathBuf {
+        let device = Device::new(
+            "Phone".to_string(),
+            false,
+            false,
+            OS::Windows("11".to_string()),
+            "127.0.0.1".to_string(),
+        );
+        database.save_device(device_id, &device).await.unwrap();
+
+        let location = std::env::temp_dir().join(format!("aperture-entry-{}", Uuid::new_v4()));
+        std::fs::write(&location, b"photo").unwrap();
+        let entry = LocalEntry::new(
+            device.uuid,
+            "photo.jpg".to_string(),
+            5,
+            location.to_string_lossy().to_string(),
+            None,
+            None,
+            None,
+            "DCIM".to_string(),
+            "/sdcard/DCIM".to_string(),
+            "DCIM".to_string(),
+        )
+        .unwrap();
+        database
+            .save_entry(device_id, "photo", &entry)
+            .await
+            .unwrap();
+        location
+    }
+
+    #[tokio::test]
+    async fn keep_moves_the_entries_out_of_the_device_table() {
+        let database = MemoryRepository::default();
+        let location = paired_device(&database, "phone").await;
+
+        let revocation = revoke_device(&database, "phone", RevokedFiles::Keep)
+            .await
+            .unwrap();
+        let kept_table = revocation.kept_table.unwrap();
+        assert!(kept_table.starts_with("revoked_phone_"));
+        assert_eq!(database.list_entries(&kept_table).await.unwrap().len(), 1);
+        assert!(database.list_entries("phone").await.unwrap().is_empty());
+        assert!(database.get_device("phone").await.unwrap().is_none());
+        assert!(location.exists());
+
+        // Still visible to the consistency check and the backup
+        let libraries = all_libraries(&database).await.unwrap();
+        assert!(libraries.contains(&kept_table));
+        std::fs::remove_file(location).unwrap();
+    }
+
+    #[tokio::test]
+    async fn delete_removes_entries_and_files() {
+        let database = MemoryRepository::default();
+        let location = paired_device(&database, "phone").await;
+
+        let revocation = revoke_device(&database, "phone", RevokedFiles::Delete)
+            .await
+            .unwrap();
+        assert_eq!(revocation.removed, 1);
+        assert!(database.list_entries("phone").await.unwrap().is_empty());
+        assert!(!location.exists());
+    }
+
+    #[tokio::test]
+    async fn unknown_device_is_not_found() {
+        let database = MemoryRepository::default();
+        let result = revoke_device(&database, "missing", RevokedFiles::Delete).await;
+        assert!(matches!(result, Err(RevocationError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn server_tables_are_never_dropped_as_a_library() {
+        let database = MemoryRepository::default();
+        let location = paired_device(&database, "phone").await;
+
+        for table in ["device", "Hash", "global", "global_0a1b", "revoked_phone_1"] {
+            assert!(is_reserved_table(table), "{table} is not reserved");
+        }
+        assert!(!is_reserved_table("phone"));
+
+        let result = database.delete_library("device").await;
+        assert!(matches!(result, Err(RepositoryError::Reserved(_))));
+        assert!(database.get_device("phone").await.unwrap().is_some());
+        std::fs::remove_file(location).unwrap();
+    }
+}
@@ -0,0 +1,125 @@
+use std::path::{Path, PathBuf};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+
+use super::api::utility::get_documents_dir;
+
+/*  Self-signed certificate for serving HTTPS
+
+   Generated on first start and kept in `Documents/Aperture/.tls`. Clients
+   can't verify it against a CA, they pin the SHA-256 fingerprint of the
+   certificate shown by the desktop during pairing instead.
+*/
+
+#[derive(Debug, Clone)]
+pub struct TlsPaths {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+pub async fn get_tls_paths() -> Option<TlsPaths> {
+    match get_documents_dir().await {
+        Ok(dir) => {
+            let tls_dir = dir.join("Aperture").join(".tls");
+            Some(TlsPaths {
+                cert: tls_dir.join("cert.pem"),
+                key: tls_dir.join("key.pem"),
+            })
+        }
+        Err(e) => {
+            error!("Failed to get documents directory: {}", e);
+            None
+        }
+    }
+}
+
+/// Returns the certificate and key, generating them if they don't exist yet
+pub async fn ensure_certificate() -> Option<TlsPaths> {
+    let paths = get_tls_paths().await?;
+    if paths.cert.exists() && paths.key.exists() {
+        return Some(paths);
+    }
+
+    let cert = match rcgen::generate_simple_self_signed(vec![
+        "localhost".to_string(),
+        "aperture.local".to_string(),
+    ]) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Failed to generate certificate: {}", e);
+            return None;
+        }
+    };
+    let cert_pem = match cert.serialize_pem() {
+        Ok(p) => p,
+        Err(e) => {
+            error!("Failed to serialize certificate: {}", e);
+            return None;
+        }
+    };
+    let key_pem = cert.serialize_private_key_pem();
+
+    if let Some(parent) = paths.cert.parent() {
+        if let Err(e) = tokio::fs::create_dir_all(parent).await {
+            error!("Failed to create tls directory: {}", e);
+            return None;
+        }
+    }
+    if let Err(e) = write_private_key(&paths.key, &key_pem).await {
+        error!("Failed to write private key: {}", e);
+        return None;
+    }
+    if let Err(e) = tokio::fs::write(&paths.cert, cert_pem).await {
+        error!("Failed to write certificate: {}", e);
+        return None;
+    }
+    info!("Generated self-signed certificate at {:?}", paths.cert);
+    Some(paths)
+}
+
+// The key is only readable by the owner, on unix it is created with 0600
+// and an existing file is tightened before it is overwritten
+async fn write_private_key(path: &Path, key_pem: &str) -> std::io::Result<()> {
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        options.mode(0o600);
+        if path.exists() {
+            tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)).await?;
+        }
+    }
+
+    let mut file = options.open(path).await?;
+    file.write_all(key_pem.as_bytes()).await?;
+    file.flush().await
+}
+
+/// SHA-256 of the DER encoded certificate as colon separated hex,
+/// e.g. `AB:CD:...`
+pub async fn get_fingerprint() -> Option<String> {
+    let paths = get_tls_paths().await?;
+    let pem = tokio::fs::read_to_string(&paths.cert).await.ok()?;
+
+    // Only the first certificate of the chain is pinned
+    let body: String = pem
+        .lines()
+        .skip_while(|l| !l.starts_with("-----BEGIN CERTIFICATE-----"))
+        .skip(1)
+        .take_while(|l| !l.starts_with("-----END CERTIFICATE-----"))
+        .collect();
+    let der = match STANDARD.decode(body) {
+        Ok(d) => d,
+        Err(e) => {
+            error!("Failed to decode certificate: {}", e);
+            return None;
+        }
+    };
+
+    let digest = Sha256::digest(der);
+    let hex: Vec<String> = digest.iter().map(|b| format!("{:02X}", b)).collect();
+    Some(hex.join(":"))
+}
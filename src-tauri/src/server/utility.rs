@@ -1,3 +1,7 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
 use rocket_multipart_form_data::TextField;
 use sha2::{Digest, Sha256};
 
@@ -15,11 +19,23 @@ pub fn verify_required_data<T>(vector: &[Option<T>]) -> bool {
 
 pub trait TextFieldExt {
     fn first_text(&self) -> Option<String>;
+    // Optional numeric field, present but not a number is an error
+    fn first_number(&self) -> Result<Option<u64>, &'static str>;
 }
 
 impl TextFieldExt for Option<&Vec<TextField>> {
     fn first_text(&self) -> Option<String> {
-        self.unwrap().first().map(|t| t.text.clone())
+        self.and_then(|v| v.first()).map(|t| t.text.clone())
+    }
+
+    fn first_number(&self) -> Result<Option<u64>, &'static str> {
+        match self.and_then(|v| v.first()) {
+            Some(t) => match t.text.parse() {
+                Ok(n) => Ok(Some(n)),
+                Err(_e) => Err("Error: expected a number"),
+            },
+            None => Ok(None),
+        }
     }
 }
 
@@ -31,3 +47,35 @@ pub fn gen_sha_256_hash(string: &String) -> String {
     let result = hasher.finalize();
     format!("{:x}", result)
 }
+
+// Key of a file inside its library. File names never contain a `/`, so
+// the separator keeps different path and name splits apart, migration 4
+// computes the same key in SurrealQL
+pub fn gen_file_id(relative_path: &str, file_name: &str) -> String {
+    gen_sha_256_hash(&format!("{relative_path}/{file_name}"))
+}
+
+// A single path component, anything else could address another directory
+pub fn valid_file_name(file_name: &str) -> bool {
+    !file_name.is_empty() && !file_name.contains(['/', '\\', '\0']) && !file_name.contains("..")
+}
+
+// Salted Argon2id hash of a PIN in PHC string format. Slow on purpose,
+// callers run it and `verify_pin_hash` through `spawn_blocking`
+pub fn gen_pin_hash(pin: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(pin.as_bytes(), &salt)
+        .expect("Argon2 with default params can not fail")
+        .to_string()
+}
+
+// Checks a PIN against a PHC string produced by `gen_pin_hash`
+pub fn verify_pin_hash(pin: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(parsed) => Argon2::default()
+            .verify_password(pin.as_bytes(), &parsed)
+            .is_ok(),
+        Err(_e) => false,
+    }
+}